@@ -0,0 +1,502 @@
+//! Stand-in for the robot controller, so the TCP interface can be run end to end without the robot.
+//!
+//! The controller is described by a JSON config: the entities, variables and commands it sets up,
+//! and either scripted frames or simple effects that commands have on the variables.
+//! Goals can be sent at given steps. Every command and goal status received from AERA is recorded as one JSON object per line.
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context};
+use prost::Message;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use aera_exp::interfaces::CommIds;
+use aera_exp::interfaces::tcp_interface::{decode_runtime_value, values_to_le_bytes};
+use aera_exp::protobuf::variable_description::DataType;
+use aera_exp::protobuf::{goal_message, goal_status_message, tcp_message, CommandDescription, DataMessage, GoalMessage, ProtoVariable, SetupMessage, StopMessage, TcpMessage, VariableDescription};
+use aera_exp::types::value::Value;
+
+#[derive(Debug, Deserialize)]
+struct MockConfig {
+    #[serde(default = "default_address")]
+    address: String,
+    /// Number of data messages to send before stopping
+    #[serde(default = "default_steps")]
+    steps: usize,
+    /// How long to wait for a command after sending data, planning can take a while
+    #[serde(default = "default_command_timeout")]
+    command_timeout_secs: u64,
+    /// File the received commands are written to
+    record: Option<PathBuf>,
+    entities: Vec<String>,
+    commands: Vec<CommandConfig>,
+    variables: Vec<VariableConfig>,
+    /// Values to overwrite at each step, keyed by `entity.variable`
+    #[serde(default)]
+    frames: Vec<HashMap<String, JsonValue>>,
+    #[serde(default)]
+    effects: Vec<EffectConfig>,
+    #[serde(default)]
+    goals: Vec<GoalConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandConfig {
+    name: String,
+    entity: String,
+    #[serde(default = "default_data_type")]
+    data_type: String,
+    #[serde(default = "default_dimensions")]
+    dimensions: Vec<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VariableConfig {
+    entity: String,
+    name: String,
+    #[serde(default = "default_data_type")]
+    data_type: String,
+    #[serde(default = "default_dimensions")]
+    dimensions: Vec<u64>,
+    value: JsonValue,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum EffectOp {
+    /// Add the command params to the variable of the command entity
+    Add,
+    /// Set the variable of the command entity to the command params
+    Set,
+    /// Set the variable to the first other entity whose `match_variable` equals the one of the command entity
+    Grab,
+    /// Set the variable to nothing
+    Release,
+}
+
+#[derive(Debug, Deserialize)]
+struct EffectConfig {
+    command: String,
+    op: EffectOp,
+    variable: String,
+    /// For `add`, entities listed in this variable of the command entity are moved along
+    carry: Option<String>,
+    match_variable: Option<String>,
+}
+
+/// Goal sent right before the data of `step`
+#[derive(Debug, Deserialize)]
+struct GoalConfig {
+    step: usize,
+    /// SET, REPLACE_ALL or CANCEL
+    #[serde(default = "default_goal_operation")]
+    operation: String,
+    name: String,
+    /// Values the goal wants the variables to have, keyed by `entity.variable`
+    #[serde(default)]
+    facts: HashMap<String, JsonValue>,
+    #[serde(default)]
+    priority: i32,
+    #[serde(default)]
+    timeout_ms: u64,
+    /// RETRY, DRIVE or ABANDON
+    #[serde(default = "default_goal_policy")]
+    policy: String,
+    #[serde(default)]
+    max_attempts: u32,
+}
+
+fn default_address() -> String {
+    "127.0.0.1:8080".to_string()
+}
+
+fn default_steps() -> usize {
+    20
+}
+
+fn default_command_timeout() -> u64 {
+    600
+}
+
+fn default_data_type() -> String {
+    "DOUBLE".to_string()
+}
+
+fn default_dimensions() -> Vec<u64> {
+    vec![1]
+}
+
+fn default_goal_operation() -> String {
+    "SET".to_string()
+}
+
+fn default_goal_policy() -> String {
+    "RETRY".to_string()
+}
+
+struct MockController {
+    config: MockConfig,
+    stream: TcpStream,
+    ids: HashMap<String, i32>,
+    comm_ids: CommIds,
+    descriptions: HashMap<(String, String), VariableDescription>,
+    command_descriptions: HashMap<i32, (String, VariableDescription)>,
+    world: HashMap<(String, String), JsonValue>,
+    record: Option<BufWriter<File>>,
+}
+
+fn main() {
+    simple_log::quick!("info");
+
+    let Some(config_path) = std::env::args().nth(1) else {
+        eprintln!("Usage: mock_controller <CONFIG.json>");
+        exit(2);
+    };
+    if let Err(e) = run(&config_path) {
+        log::error!("{e:#}");
+        exit(1);
+    }
+}
+
+fn run(config_path: &str) -> anyhow::Result<()> {
+    let config: MockConfig = serde_json::from_str(&fs::read_to_string(config_path)?)
+        .with_context(|| format!("Invalid config {config_path}"))?;
+    let stream = connect(&config.address, Duration::from_secs(30))?;
+    let mut controller = MockController::new(config, stream)?;
+
+    controller.send_setup()?;
+    controller.wait_for_start()?;
+    for step in 0..controller.config.steps {
+        controller.apply_frame(step);
+        controller.send_goals(step)?;
+        controller.send_data(step)?;
+        controller.receive_command(step)?;
+    }
+    log::info!("Sent all {} steps, stopping", controller.config.steps);
+    controller.send(tcp_message::Type::Stop, Some(tcp_message::Message::StopMessage(StopMessage {})))?;
+    if let Some(record) = &mut controller.record {
+        record.flush()?;
+    }
+
+    Ok(())
+}
+
+/// AERA listens for the controller, so keep trying until it has started
+fn connect(address: &str, timeout: Duration) -> anyhow::Result<TcpStream> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match TcpStream::connect(address) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if Instant::now() < deadline => {
+                log::debug!("Could not connect to {address} yet: {e}");
+                std::thread::sleep(Duration::from_millis(200));
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to connect to {address}")),
+        }
+    }
+}
+
+impl MockController {
+    fn new(config: MockConfig, stream: TcpStream) -> anyhow::Result<Self> {
+        // Entities, variables and commands share one id space
+        let names = config.entities.iter()
+            .chain(config.variables.iter().map(|v| &v.name))
+            .chain(config.commands.iter().map(|c| &c.name));
+        let mut ids = HashMap::new();
+        for name in names {
+            let next_id = ids.len() as i32 + 1;
+            ids.entry(name.clone()).or_insert(next_id);
+        }
+        let id_of = |name: &str| ids.get(name).copied().with_context(|| format!("{name} is not declared"));
+
+        let mut descriptions = HashMap::new();
+        let mut world = HashMap::new();
+        for variable in &config.variables {
+            let description = VariableDescription {
+                entity_id: id_of(&variable.entity)?,
+                id: id_of(&variable.name)?,
+                data_type: parse_data_type(&variable.data_type)? as i32,
+                dimensions: variable.dimensions.clone(),
+                opcode_string_handle: String::new(),
+            };
+            let key = (variable.entity.clone(), variable.name.clone());
+            descriptions.insert(key.clone(), description);
+            world.insert(key, variable.value.clone());
+        }
+        let mut command_descriptions = HashMap::new();
+        for command in &config.commands {
+            let description = VariableDescription {
+                entity_id: id_of(&command.entity)?,
+                id: id_of(&command.name)?,
+                data_type: parse_data_type(&command.data_type)? as i32,
+                dimensions: command.dimensions.clone(),
+                opcode_string_handle: String::new(),
+            };
+            command_descriptions.insert(description.id, (command.name.clone(), description));
+        }
+        let record = match &config.record {
+            Some(path) => Some(BufWriter::new(File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?)),
+            None => None,
+        };
+
+        let mut comm_ids = CommIds::new();
+        comm_ids.insert_map(&ids);
+
+        Ok(Self { config, stream, ids, comm_ids, descriptions, command_descriptions, world, record })
+    }
+
+    fn send_setup(&mut self) -> anyhow::Result<()> {
+        let ids_of = |names: Vec<&String>| names.into_iter().map(|n| (n.clone(), self.ids[n])).collect();
+        let setup = SetupMessage {
+            entities: ids_of(self.config.entities.iter().collect()),
+            objects: ids_of(self.config.variables.iter().map(|v| &v.name).collect()),
+            commands: ids_of(self.config.commands.iter().map(|c| &c.name).collect()),
+            command_descriptions: self.command_descriptions.values()
+                .map(|(name, description)| CommandDescription { description: Some(description.clone()), name: name.clone() })
+                .collect(),
+        };
+        log::info!("Sending setup message");
+        self.send(tcp_message::Type::Setup, Some(tcp_message::Message::SetupMessage(setup)))
+    }
+
+    fn wait_for_start(&mut self) -> anyhow::Result<()> {
+        let message = self.receive(None)?;
+        let Some(tcp_message::Message::StartMessage(start)) = message.message else {
+            bail!("Expected start message, got message type {}", message.message_type);
+        };
+        log::info!("Started with reconnection type {}", start.reconnection_type);
+
+        Ok(())
+    }
+
+    fn apply_frame(&mut self, step: usize) {
+        let Some(frame) = self.config.frames.get(step) else {
+            return;
+        };
+        for (key, value) in frame {
+            match key.split_once('.') {
+                Some((entity, variable)) => {
+                    self.world.insert((entity.to_string(), variable.to_string()), value.clone());
+                }
+                None => log::warn!("Frame key {key} should have the form entity.variable"),
+            }
+        }
+    }
+
+    fn send_data(&mut self, step: usize) -> anyhow::Result<()> {
+        let mut variables = Vec::new();
+        for variable in &self.config.variables {
+            let key = (variable.entity.clone(), variable.name.clone());
+            let description = self.descriptions[&key].clone();
+            let data = encode(&self.world[&key], &description, &self.comm_ids)
+                .with_context(|| format!("Failed to encode {}.{}", variable.entity, variable.name))?;
+            variables.push(ProtoVariable { meta_data: Some(description), data });
+        }
+        log::debug!("Sending data for step {step}");
+        self.send(tcp_message::Type::Data, Some(tcp_message::Message::DataMessage(DataMessage { variables, time_span: 100 })))
+    }
+
+    fn send_goals(&mut self, step: usize) -> anyhow::Result<()> {
+        let mut messages = Vec::new();
+        for goal in self.config.goals.iter().filter(|g| g.step == step) {
+            let mut facts = Vec::new();
+            for (key, value) in &goal.facts {
+                let (entity, variable) = key.split_once('.')
+                    .with_context(|| format!("Goal fact {key} should have the form entity.variable"))?;
+                let description = self.descriptions.get(&(entity.to_string(), variable.to_string()))
+                    .with_context(|| format!("Goal fact {key} is not a declared variable"))?
+                    .clone();
+                let data = encode(value, &description, &self.comm_ids).with_context(|| format!("Failed to encode goal fact {key}"))?;
+                facts.push(ProtoVariable { meta_data: Some(description), data });
+            }
+            let operation = goal_message::Operation::from_str_name(&goal.operation)
+                .with_context(|| format!("Unknown goal operation {}", goal.operation))?;
+            let policy = goal_message::Policy::from_str_name(&goal.policy)
+                .with_context(|| format!("Unknown goal policy {}", goal.policy))?;
+            messages.push(GoalMessage {
+                operation: operation as i32,
+                name: goal.name.clone(),
+                facts,
+                priority: goal.priority,
+                timeout: goal.timeout_ms,
+                policy: policy as i32,
+                max_attempts: goal.max_attempts,
+            });
+        }
+        for message in messages {
+            log::info!("Step {step}: sending goal {} {}", message.operation().as_str_name(), message.name);
+            self.send(tcp_message::Type::Goal, Some(tcp_message::Message::GoalMessage(message)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Goal statuses can arrive before the command, they are logged and recorded until the command arrives
+    fn receive_command(&mut self, step: usize) -> anyhow::Result<()> {
+        let data = loop {
+            let message = self.receive(Some(Duration::from_secs(self.config.command_timeout_secs)))?;
+            match message.message {
+                Some(tcp_message::Message::DataMessage(data)) => break data,
+                Some(tcp_message::Message::GoalStatusMessage(status)) => {
+                    let status_name = goal_status_message::Status::try_from(status.status)
+                        .map_or("UNKNOWN", |s| s.as_str_name());
+                    log::info!("Step {step}: goal {} is {status_name}", status.name);
+                    if let Some(record) = &mut self.record {
+                        writeln!(record, "{}", json!({ "step": step, "goal": status.name, "status": status_name }))?;
+                    }
+                }
+                _ => bail!("Expected command, got message type {}", message.message_type),
+            }
+        };
+        for variable in data.variables {
+            let description = variable.meta_data.clone().context("Command without meta data")?;
+            let (name, _) = self.command_descriptions.get(&description.id)
+                .with_context(|| format!("Unknown command id {}", description.id))?
+                .clone();
+            let entity = self.comm_ids.get_name(description.entity_id)?.to_string();
+            let params = to_json(&decode_runtime_value(&variable, &self.comm_ids)?);
+            log::info!("Step {step}: received command {name} {entity} {params}");
+
+            if let Some(record) = &mut self.record {
+                writeln!(record, "{}", json!({ "step": step, "command": name, "entity": entity, "params": params }))?;
+            }
+            self.apply_effects(&name, &entity, &params);
+        }
+
+        Ok(())
+    }
+
+    fn apply_effects(&mut self, command: &str, entity: &str, params: &JsonValue) {
+        for effect in self.config.effects.iter().filter(|e| e.command == command) {
+            let key = (entity.to_string(), effect.variable.clone());
+            match effect.op {
+                EffectOp::Add => {
+                    let mut moved = vec![entity.to_string()];
+                    if let Some(carry) = &effect.carry {
+                        moved.extend(entity_names(self.world.get(&(entity.to_string(), carry.clone()))));
+                    }
+                    for moved_entity in moved {
+                        if let Some(value) = self.world.get_mut(&(moved_entity, effect.variable.clone())) {
+                            *value = add_json(value, params);
+                        }
+                    }
+                }
+                EffectOp::Set => {
+                    self.world.insert(key, unwrap_single(params));
+                }
+                EffectOp::Grab => {
+                    let match_variable = effect.match_variable.clone().unwrap_or("position".to_string());
+                    let own = self.world.get(&(entity.to_string(), match_variable.clone())).cloned();
+                    let grabbed = self.world.iter()
+                        .filter(|((e, v), value)| e != entity && *v == match_variable && own.as_ref().is_some_and(|own| json_eq(own, value)))
+                        .map(|((e, _), _)| e.clone())
+                        .min();
+                    if let Some(grabbed) = grabbed {
+                        self.world.insert(key, JsonValue::String(grabbed));
+                    }
+                }
+                EffectOp::Release => {
+                    self.world.insert(key, JsonValue::Null);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, message_type: tcp_message::Type, message: Option<tcp_message::Message>) -> anyhow::Result<()> {
+        let encoded = TcpMessage { message_type: message_type as i32, timestamp: 0, message }.encode_to_vec();
+        self.stream.write_all(&(encoded.len() as u64).to_le_bytes())?;
+        self.stream.write_all(&encoded)?;
+
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Option<Duration>) -> anyhow::Result<TcpMessage> {
+        self.stream.set_read_timeout(timeout)?;
+        let mut size = [0; 8];
+        self.stream.read_exact(&mut size).context("Failed to receive message from AERA")?;
+        let mut data = vec![0; u64::from_le_bytes(size) as usize];
+        self.stream.read_exact(&mut data)?;
+
+        Ok(TcpMessage::decode(data.as_slice())?)
+    }
+}
+
+fn parse_data_type(data_type: &str) -> anyhow::Result<DataType> {
+    DataType::from_str_name(data_type).with_context(|| format!("Unknown data type {data_type}"))
+}
+
+/// Flatten nested JSON arrays into the elements that are sent one after another
+fn leaves(value: &JsonValue) -> Vec<&JsonValue> {
+    match value {
+        JsonValue::Array(values) => values.iter().flat_map(leaves).collect(),
+        value => vec![value],
+    }
+}
+
+/// Encode the JSON value with the codec of the runtime, so the controller sends exactly what AERA expects
+fn encode(value: &JsonValue, description: &VariableDescription, comm_ids: &CommIds) -> anyhow::Result<Vec<u8>> {
+    let data_type = DataType::try_from(description.data_type)?;
+    values_to_le_bytes(&[to_value(value, data_type)?], data_type, comm_ids)
+}
+
+/// Names are entity ids unless the variable is a string, and null means that nothing is referenced
+fn to_value(value: &JsonValue, data_type: DataType) -> anyhow::Result<Value> {
+    Ok(match value {
+        JsonValue::Null => Value::Vec(Vec::new()),
+        JsonValue::Bool(b) => Value::Number(f64::from(*b)),
+        JsonValue::Number(n) => Value::Number(n.as_f64().with_context(|| format!("Expected number, got {n}"))?),
+        JsonValue::String(s) if data_type == DataType::String => Value::String(s.clone()),
+        JsonValue::String(s) => Value::EntityId(s.clone()),
+        JsonValue::Array(values) => Value::Vec(values.iter().map(|v| to_value(v, data_type)).collect::<anyhow::Result<_>>()?),
+        JsonValue::Object(_) => bail!("Expected value, got {value}"),
+    })
+}
+
+fn to_json(value: &Value) -> JsonValue {
+    match value {
+        Value::Number(n) | Value::ConstantNumber(n) | Value::UncertainNumber(n, _) => json!(n),
+        Value::String(s) | Value::EntityId(s) => json!(s),
+        Value::Vec(values) => JsonValue::Array(values.iter().map(to_json).collect()),
+    }
+}
+
+/// Command params are always a list, while single variables are plain values
+fn unwrap_single(params: &JsonValue) -> JsonValue {
+    match params {
+        JsonValue::Array(values) if values.len() == 1 => values[0].clone(),
+        params => params.clone(),
+    }
+}
+
+fn add_json(value: &JsonValue, params: &JsonValue) -> JsonValue {
+    match (value, unwrap_single(params)) {
+        (JsonValue::Array(values), JsonValue::Array(deltas)) => {
+            JsonValue::Array(values.iter().zip(deltas.iter()).map(|(v, d)| add_json(v, d)).collect())
+        }
+        (value, delta) => match (value.as_f64(), delta.as_f64()) {
+            (Some(v), Some(d)) => json!(v + d),
+            _ => value.clone(),
+        },
+    }
+}
+
+fn json_eq(a: &JsonValue, b: &JsonValue) -> bool {
+    match (a, b) {
+        (JsonValue::Array(a), JsonValue::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_eq(a, b)),
+        (a, b) => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => (a - b).abs() < 1e-6,
+            _ => a == b,
+        },
+    }
+}
+
+fn entity_names(value: Option<&JsonValue>) -> Vec<String> {
+    value.map(leaves).unwrap_or_default().into_iter().filter_map(|v| v.as_str().map(|s| s.to_string())).collect()
+}
@@ -0,0 +1,221 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use aera_exp::interfaces::tcp_interface::TcpConfig;
+use aera_exp::protobuf::start_message::ReconnectionType;
+use aera_exp::runtime::seeds::{builtin_seed, BUILTIN_SEEDS};
+use aera_exp::runtime::{RunMode, RunOptions};
+
+pub const USAGE: &str = "\
+Usage: aera-exp [OPTIONS]
+
+Options:
+  -s, --seed <NAME|FILE>           Built-in seed or path to a Replicode seed file [default: robot-sift-learn]
+  -i, --interface <KIND>           tcp, sim (hand and sphere world), frames (recorded robot frames),
+                                   replay (recorded TCP session) or none [default: tcp]
+  -a, --address <ADDRESS>          Address to listen on for the TCP controller [default: 127.0.0.1]
+  -p, --port <PORT>                Port to listen on for the TCP controller [default: 8080]
+      --connect-timeout <SECONDS>  Give up if the controller has not connected in time
+      --read-timeout <SECONDS>     Skip a step if the controller sends nothing in time
+      --setup-timeout <SECONDS>    Give up if the controller has not sent its setup in time after connecting
+      --record <FILE>              Record the TCP session to a file
+      --session <FILE>             Recorded TCP session to replay
+      --reconnection <TYPE>        re-init, re-setup or none, what the controller does after a disconnect [default: re-init]
+  -l, --log-level <LEVEL>          error, warn, info, debug or trace [default: debug]
+  -n, --max-steps <N>              Stop after N steps
+  -t, --time-limit <SECONDS>       Stop after running for this long
+      --stop-when-achieved         Stop once all goals have been achieved
+      --tick <MILLISECONDS>        Length of a step when the environment sends no timestamps [default: 100]
+      --fast                       Run steps as fast as possible instead of one per tick
+      --planning-budget <MILLISECONDS>
+                                   Plan for at most this long each step and act on the best plan so far
+      --planning-threads <N>       Number of states to expand in parallel when planning [default: number of cores]
+      --max-models <N>             Forget the least recently used learned causal models when there are more than N models
+      --load-knowledge <FILE>      Replace the seeded models, csts and goals with a saved knowledge file
+      --save-knowledge <FILE>      Save the knowledge when it changes and on exit [default: knowledge.json]
+      --no-save-knowledge          Don't save the knowledge
+  -m, --mode <MODE>                normal, debug-path or plan-once [default: normal]
+      --list-seeds                 Print the names of the built-in seeds
+  -h, --help                       Print this help";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SeedSource {
+    Builtin(String),
+    File(PathBuf),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterfaceKind {
+    Tcp,
+    Sim,
+    Frames,
+    Replay,
+    None,
+}
+
+#[derive(Clone, Debug)]
+pub struct Args {
+    pub seed: SeedSource,
+    pub interface: InterfaceKind,
+    pub tcp: TcpConfig,
+    pub session: Option<PathBuf>,
+    pub log_level: String,
+    pub run_options: RunOptions,
+}
+
+pub enum CliAction {
+    Run(Args),
+    ListSeeds,
+    Help,
+}
+
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<CliAction> {
+    let mut parsed = Args {
+        seed: SeedSource::Builtin("robot-sift-learn".to_string()),
+        interface: InterfaceKind::Tcp,
+        tcp: TcpConfig::default(),
+        session: None,
+        log_level: "debug".to_string(),
+        run_options: RunOptions::default(),
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // Both `--option value` and `--option=value` are accepted
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || inline_value.clone().or_else(|| args.next()).with_context(|| format!("Missing value for {name}"));
+
+        match name.as_str() {
+            "-h" | "--help" => return Ok(CliAction::Help),
+            "--list-seeds" => return Ok(CliAction::ListSeeds),
+            "-s" | "--seed" => parsed.seed = parse_seed(&value()?)?,
+            "-i" | "--interface" => parsed.interface = parse_interface(&value()?)?,
+            "-a" | "--address" => parsed.tcp.bind_address = value()?,
+            "-p" | "--port" => {
+                let port = value()?;
+                parsed.tcp.port = port.parse().with_context(|| format!("Invalid port {port}"))?;
+            }
+            "--connect-timeout" => parsed.tcp.connect_timeout = Some(parse_seconds(&value()?)?),
+            "--read-timeout" => parsed.tcp.read_timeout = Some(parse_seconds(&value()?)?),
+            "--setup-timeout" => parsed.tcp.setup_timeout = Some(parse_seconds(&value()?)?),
+            "--record" => parsed.tcp.record_path = Some(PathBuf::from(value()?)),
+            "--session" => parsed.session = Some(PathBuf::from(value()?)),
+            "--reconnection" => parsed.tcp.reconnection_type = parse_reconnection_type(&value()?)?,
+            "-l" | "--log-level" => {
+                let level = value()?.to_lowercase();
+                if !["error", "warn", "info", "debug", "trace"].contains(&level.as_str()) {
+                    bail!("Unknown log level {level}");
+                }
+                parsed.log_level = level;
+            }
+            "-n" | "--max-steps" => {
+                let max_steps = value()?;
+                parsed.run_options.max_steps = Some(max_steps.parse().with_context(|| format!("Invalid step limit {max_steps}"))?);
+            }
+            "-t" | "--time-limit" => parsed.run_options.max_duration = Some(parse_seconds(&value()?)?),
+            "--stop-when-achieved" => parsed.run_options.stop_when_goals_achieved = true,
+            "--tick" => {
+                let tick = value()?;
+                let tick: u64 = tick.parse().with_context(|| format!("Invalid tick {tick}"))?;
+                if tick == 0 {
+                    bail!("Tick has to be positive");
+                }
+                parsed.run_options.tick = Duration::from_millis(tick);
+            }
+            "--fast" => parsed.run_options.fast = true,
+            "--planning-budget" => {
+                let budget = value()?;
+                let budget: u64 = budget.parse().with_context(|| format!("Invalid planning budget {budget}"))?;
+                if budget == 0 {
+                    bail!("Planning budget has to be positive");
+                }
+                parsed.run_options.planning_budget = Some(Duration::from_millis(budget));
+            }
+            "--planning-threads" => {
+                let threads = value()?;
+                let threads: usize = threads.parse().with_context(|| format!("Invalid number of planning threads {threads}"))?;
+                if threads == 0 {
+                    bail!("Number of planning threads has to be positive");
+                }
+                parsed.run_options.planning_threads = threads;
+            }
+            "--max-models" => {
+                let max_models = value()?;
+                parsed.run_options.forgetting.max_models = Some(max_models.parse().with_context(|| format!("Invalid model limit {max_models}"))?);
+            }
+            "--load-knowledge" => {
+                let path = PathBuf::from(value()?);
+                if !path.is_file() {
+                    bail!("Knowledge file {} does not exist", path.display());
+                }
+                parsed.run_options.load_knowledge = Some(path);
+            }
+            "--save-knowledge" => parsed.run_options.save_knowledge = Some(PathBuf::from(value()?)),
+            "--no-save-knowledge" => parsed.run_options.save_knowledge = None,
+            "-m" | "--mode" => parsed.run_options.mode = parse_mode(&value()?)?,
+            _ => bail!("Unknown argument {arg}"),
+        }
+    }
+
+    if parsed.interface == InterfaceKind::Replay && parsed.session.is_none() {
+        bail!("The replay interface needs a recorded session, given with --session");
+    }
+
+    Ok(CliAction::Run(parsed))
+}
+
+fn parse_seed(seed: &str) -> anyhow::Result<SeedSource> {
+    if builtin_seed(seed).is_some() {
+        return Ok(SeedSource::Builtin(seed.to_string()));
+    }
+    let path = PathBuf::from(seed);
+    if !path.is_file() {
+        bail!(
+            "{seed} is neither a seed file nor a built-in seed ({})",
+            BUILTIN_SEEDS.iter().map(|(name, _)| name).join(", ")
+        );
+    }
+
+    Ok(SeedSource::File(path))
+}
+
+fn parse_seconds(seconds: &str) -> anyhow::Result<Duration> {
+    let seconds: f64 = seconds.parse().with_context(|| format!("Invalid number of seconds {seconds}"))?;
+    if seconds <= 0.0 {
+        bail!("Duration has to be positive, got {seconds}");
+    }
+    Duration::try_from_secs_f64(seconds).with_context(|| format!("Invalid duration {seconds}"))
+}
+
+fn parse_reconnection_type(reconnection_type: &str) -> anyhow::Result<ReconnectionType> {
+    match reconnection_type {
+        "re-init" => Ok(ReconnectionType::ReInit),
+        "re-setup" => Ok(ReconnectionType::ReSetup),
+        "none" => Ok(ReconnectionType::None),
+        _ => bail!("Unknown reconnection type {reconnection_type}"),
+    }
+}
+
+fn parse_interface(interface: &str) -> anyhow::Result<InterfaceKind> {
+    match interface {
+        "tcp" => Ok(InterfaceKind::Tcp),
+        "sim" => Ok(InterfaceKind::Sim),
+        "frames" => Ok(InterfaceKind::Frames),
+        "replay" => Ok(InterfaceKind::Replay),
+        "none" => Ok(InterfaceKind::None),
+        _ => bail!("Unknown interface {interface}"),
+    }
+}
+
+fn parse_mode(mode: &str) -> anyhow::Result<RunMode> {
+    match mode {
+        "normal" => Ok(RunMode::Normal),
+        "debug-path" => Ok(RunMode::DebugExpectedPath),
+        "plan-once" => Ok(RunMode::PlanOnce),
+        _ => bail!("Unknown run mode {mode}"),
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use anyhow::{bail, Context};
+use crate::interfaces::{Environment, InputEvent};
+use crate::types::runtime::{RuntimeCommand, System};
+use crate::types::value::Value;
+use crate::types::EntityVariableKey;
+
+/// Simulated world where the hand `h` moves along a line and can grab and release the cube `c` and the sphere `s`.
+/// The initial world is taken from the variables of the seed
+pub struct HandGrabSphereWorld {
+    variables: HashMap<EntityVariableKey, Value>,
+    initial_variables: HashMap<EntityVariableKey, Value>,
+}
+
+impl HandGrabSphereWorld {
+    pub fn new() -> Self {
+        Self {
+            variables: HashMap::new(),
+            initial_variables: HashMap::new(),
+        }
+    }
+
+    fn position(&self, entity: &str) -> anyhow::Result<&Value> {
+        self.variables
+            .get(&EntityVariableKey::new(entity, "position"))
+            .with_context(|| format!("No position for {entity} in the world"))
+    }
+
+    fn move_by(&mut self, entity: &str, move_by: f64) {
+        if let Some(Value::Number(pos)) = self.variables.get_mut(&EntityVariableKey::new(entity, "position")) {
+            *pos += move_by;
+        }
+    }
+
+    fn set_holding(&mut self, holding: Vec<Value>) {
+        self.variables.insert(EntityVariableKey::new("h", "holding"), Value::Vec(holding));
+    }
+}
+
+impl Default for HandGrabSphereWorld {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Environment for HandGrabSphereWorld {
+    fn setup(&mut self, system: &mut System) -> anyhow::Result<()> {
+        self.initial_variables = system.current_state.variables.clone();
+        self.variables = self.initial_variables.clone();
+        Ok(())
+    }
+
+    fn observe(&mut self, system: &mut System) -> anyhow::Result<InputEvent> {
+        system.current_state.variables = self.variables.clone();
+        Ok(InputEvent::Observed)
+    }
+
+    fn execute(&mut self, command: &RuntimeCommand, _system: &mut System) -> anyhow::Result<()> {
+        match &command.name[..] {
+            "move" => {
+                let Some(Value::Number(move_by)) = command.params.first() else {
+                    bail!("Invalid parameters supplied to move command");
+                };
+                let move_by = *move_by;
+                self.move_by("h", move_by);
+
+                let holding = self.variables.get(&EntityVariableKey::new("h", "holding")).map(|h| h.as_vec().as_slice());
+                if let Some([Value::EntityId(holding), ..]) = holding {
+                    let holding = holding.clone();
+                    self.move_by(&holding, move_by);
+                }
+            }
+            "grab" => {
+                let hand_pos = self.position("h")?;
+                if hand_pos == self.position("c")? {
+                    self.set_holding(vec![Value::EntityId("c".to_string())]);
+                } else if hand_pos == self.position("s")? {
+                    self.set_holding(vec![Value::EntityId("s".to_string())]);
+                }
+            }
+            "release" => self.set_holding(Vec::new()),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self, system: &mut System) -> anyhow::Result<()> {
+        self.variables = self.initial_variables.clone();
+        system.current_state.variables = self.variables.clone();
+        Ok(())
+    }
+}
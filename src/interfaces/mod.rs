@@ -1,7 +1,113 @@
 pub mod tcp_interface;
+pub mod session_log;
+pub mod replay_interface;
+pub mod hand_grab_sphere_world;
 
 use std::collections::HashMap;
+use anyhow::Context;
 use itertools::Itertools;
+use crate::runtime::goals::GoalEvent;
+use crate::types::runtime::{RuntimeCommand, System};
+
+/// What the environment reported when asked for input at the start of a step
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    Observed,
+    /// Nothing arrived in time, the step is skipped and the previous state is kept
+    NoObservation,
+    /// The environment has stopped and the run ends
+    Stopped,
+    /// The environment has started over, so plans and predictions made for its earlier state no longer apply.
+    /// The step is skipped like when nothing was observed
+    Restarted,
+}
+
+/// When the last observation was made, as reported by the environment. Both are in milliseconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObservationTime {
+    /// Time on the clock of the environment, only differences between timestamps are used
+    Timestamp(u64),
+    /// Time that passed since the previous observation
+    Span(u64),
+}
+
+/// The world the runtime observes and acts in, e.g. the controller over TCP or a simulation
+pub trait Environment {
+    /// Called once after the system has been seeded, before the first observation
+    fn setup(&mut self, _system: &mut System) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Update the current state of the system with what the environment observes
+    fn observe(&mut self, system: &mut System) -> anyhow::Result<InputEvent>;
+
+    fn execute(&mut self, command: &RuntimeCommand, system: &mut System) -> anyhow::Result<()>;
+
+    /// Time of the last observation, if the environment keeps its own clock.
+    /// Without it the system time advances by a fixed tick every step
+    fn observation_time(&self) -> Option<ObservationTime> {
+        None
+    }
+
+    /// Put the environment back in the state it had after setup
+    fn reset(&mut self, _system: &mut System) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Checked after every executed command, the run ends when this is true
+    fn is_episode_done(&self, _system: &System) -> bool {
+        false
+    }
+
+    /// Called when a goal is achieved, missed, abandoned or has no plan, before the command of the step is executed
+    fn report_goal(&mut self, _event: &GoalEvent) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl<E: Environment + ?Sized> Environment for &mut E {
+    fn setup(&mut self, system: &mut System) -> anyhow::Result<()> {
+        (**self).setup(system)
+    }
+
+    fn observe(&mut self, system: &mut System) -> anyhow::Result<InputEvent> {
+        (**self).observe(system)
+    }
+
+    fn execute(&mut self, command: &RuntimeCommand, system: &mut System) -> anyhow::Result<()> {
+        (**self).execute(command, system)
+    }
+
+    fn observation_time(&self) -> Option<ObservationTime> {
+        (**self).observation_time()
+    }
+
+    fn reset(&mut self, system: &mut System) -> anyhow::Result<()> {
+        (**self).reset(system)
+    }
+
+    fn is_episode_done(&self, system: &System) -> bool {
+        (**self).is_episode_done(system)
+    }
+
+    fn report_goal(&mut self, event: &GoalEvent) -> anyhow::Result<()> {
+        (**self).report_goal(event)
+    }
+}
+
+/// No environment at all, the state only comes from the seed and commands are only logged
+pub struct NoEnvironment;
+
+impl Environment for NoEnvironment {
+    fn observe(&mut self, _system: &mut System) -> anyhow::Result<InputEvent> {
+        Ok(InputEvent::Observed)
+    }
+
+    fn execute(&mut self, command: &RuntimeCommand, _system: &mut System) -> anyhow::Result<()> {
+        log::debug!("Command to execute next {command}");
+        Ok(())
+    }
+}
 
 pub struct CommIds {
     id_map: HashMap<String, i32>,
@@ -35,7 +141,11 @@ impl CommIds {
         *self.id_map.get(name).expect("Name for unregistered communication id used")
     }
 
-    pub fn get_name(&self, id: i32) -> &str {
-        self.name_map.get(&id).expect("Unregistered communication id used")
+    pub fn try_get_id(&self, name: &str) -> anyhow::Result<i32> {
+        self.id_map.get(name).copied().with_context(|| format!("Name {name} has no registered communication id"))
+    }
+
+    pub fn get_name(&self, id: i32) -> anyhow::Result<&str> {
+        self.name_map.get(&id).map(String::as_str).with_context(|| format!("Unregistered communication id {id} used"))
     }
 }
\ No newline at end of file
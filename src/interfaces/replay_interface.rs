@@ -0,0 +1,135 @@
+use std::path::Path;
+use anyhow::Context;
+use crate::interfaces::session_log::{read_session, Direction, RecordedMessage};
+use crate::interfaces::tcp_interface::{apply_update, decode_goal_message, decode_runtime_value, decode_variables, observation_time, setup_comm_ids, TcpUpdate};
+use crate::interfaces::{CommIds, Environment, InputEvent, ObservationTime};
+use crate::protobuf::start_message::ReconnectionType;
+use crate::protobuf::{tcp_message, TcpMessage};
+use crate::types::runtime::{RuntimeCommand, System};
+
+/// Feeds the observations of a recorded controller session back to the runtime in place of the controller.
+/// Commands are not sent anywhere, they are only compared with the ones that were executed in the recording
+pub struct ReplayInterface {
+    messages: Vec<RecordedMessage>,
+    next_message: usize,
+    comm_ids: CommIds,
+    /// False until the first setup message has been replayed
+    set_up: bool,
+    reconnection_type: ReconnectionType,
+    observation_time: Option<ObservationTime>,
+}
+
+impl ReplayInterface {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let messages = read_session(path)?;
+        log::info!("Replaying {} recorded messages", messages.len());
+
+        Ok(Self {
+            messages,
+            next_message: 0,
+            comm_ids: CommIds::new(),
+            set_up: false,
+            reconnection_type: ReconnectionType::ReInit,
+            observation_time: None,
+        })
+    }
+
+    pub fn update_variables(&mut self) -> anyhow::Result<TcpUpdate> {
+        while let Some((direction, message)) = self.next()? {
+            match (direction, tcp_message::Type::try_from(message.message_type), message.message) {
+                (Direction::Received, Ok(tcp_message::Type::Setup), Some(tcp_message::Message::SetupMessage(setup_message))) => {
+                    self.comm_ids = setup_comm_ids(&setup_message);
+                    // Like over TCP, a setup after the first one is a restart of the controller with RE_INIT
+                    if std::mem::replace(&mut self.set_up, true) && self.reconnection_type == ReconnectionType::ReInit {
+                        return Ok(TcpUpdate::Restarted);
+                    }
+                }
+                (Direction::Received, Ok(tcp_message::Type::Data), Some(tcp_message::Message::DataMessage(dm))) => {
+                    self.observation_time = observation_time(message.timestamp, &dm);
+                    return Ok(TcpUpdate::Variables(decode_variables(dm.variables, &self.comm_ids)?));
+                }
+                (Direction::Received, Ok(tcp_message::Type::Goal), Some(tcp_message::Message::GoalMessage(goal_message))) => {
+                    return Ok(TcpUpdate::Goal(decode_goal_message(goal_message, &self.comm_ids)?));
+                }
+                (Direction::Received, Ok(tcp_message::Type::Stop), _) => return Ok(TcpUpdate::Stopped),
+                // The reconnection type of the recorded run decides how later setups are applied
+                (Direction::Sent, Ok(tcp_message::Type::Start), Some(tcp_message::Message::StartMessage(start))) => {
+                    self.reconnection_type = ReconnectionType::try_from(start.reconnection_type).unwrap_or(ReconnectionType::ReInit);
+                }
+                _ => {}
+            }
+        }
+
+        log::info!("End of recorded session");
+        Ok(TcpUpdate::Stopped)
+    }
+
+    /// Log when the runtime chooses a different command than in the recording
+    pub fn execute_command(&mut self, command: &RuntimeCommand) -> anyhow::Result<()> {
+        let recorded = self.messages[self.next_message..]
+            .iter()
+            .take_while(|m| m.direction == Direction::Sent || m.message_type != tcp_message::Type::Data.as_str_name())
+            .find(|m| m.direction == Direction::Sent && m.message_type == tcp_message::Type::Data.as_str_name());
+        let Some(recorded) = recorded else {
+            log::debug!("No command recorded for {command}");
+            return Ok(());
+        };
+
+        let Some(tcp_message::Message::DataMessage(dm)) = recorded.decode()?.message else {
+            return Ok(());
+        };
+        for variable in dm.variables {
+            let desc = variable.meta_data.as_ref().context("Recorded command without meta data")?;
+            let name = self.comm_ids.get_name(desc.id)?;
+            if name != command.name {
+                let params = decode_runtime_value(&variable, &self.comm_ids)?;
+                log::info!("Executed {command}, but the recording executed {name} with {params}");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn next(&mut self) -> anyhow::Result<Option<(Direction, TcpMessage)>> {
+        let Some(recorded) = self.messages.get(self.next_message) else {
+            return Ok(None);
+        };
+        self.next_message += 1;
+        let message = recorded.decode()
+            .with_context(|| format!("Invalid recorded message {}", self.next_message))?;
+
+        Ok(Some((recorded.direction, message)))
+    }
+}
+
+impl Environment for ReplayInterface {
+    fn observe(&mut self, system: &mut System) -> anyhow::Result<InputEvent> {
+        loop {
+            let update = self.update_variables()?;
+            if let Some(event) = apply_update(update, system) {
+                return Ok(event);
+            }
+        }
+    }
+
+    fn observation_time(&self) -> Option<ObservationTime> {
+        self.observation_time
+    }
+
+    fn execute(&mut self, command: &RuntimeCommand, _system: &mut System) -> anyhow::Result<()> {
+        if let Err(e) = self.execute_command(command) {
+            log::warn!("Failed to compare command with recording: {e:#}");
+        }
+        Ok(())
+    }
+
+    /// Start over from the beginning of the recording
+    fn reset(&mut self, _system: &mut System) -> anyhow::Result<()> {
+        self.next_message = 0;
+        self.comm_ids = CommIds::new();
+        self.set_up = false;
+        self.reconnection_type = ReconnectionType::ReInit;
+        self.observation_time = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use crate::protobuf::{tcp_message, TcpMessage};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// From the controller to AERA
+    Received,
+    /// From AERA to the controller
+    Sent,
+}
+
+/// One line of a session log. The message is stored as hex encoded protobuf,
+/// so it is replayed exactly as it was received
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    pub timestamp_ms: u64,
+    pub direction: Direction,
+    pub message_type: String,
+    pub data: String,
+}
+
+impl RecordedMessage {
+    pub fn new(direction: Direction, message: &TcpMessage) -> RecordedMessage {
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let message_type = tcp_message::Type::try_from(message.message_type)
+            .map(|t| t.as_str_name().to_string())
+            .unwrap_or(message.message_type.to_string());
+
+        RecordedMessage {
+            timestamp_ms,
+            direction,
+            message_type,
+            data: message.encode_to_vec().iter().map(|b| format!("{b:02x}")).join(""),
+        }
+    }
+
+    pub fn decode(&self) -> anyhow::Result<TcpMessage> {
+        if !self.data.is_ascii() || !self.data.len().is_multiple_of(2) {
+            bail!("Recorded message is not valid hex");
+        }
+        let bytes = (0..self.data.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&self.data[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Recorded message is not valid hex")?;
+
+        Ok(TcpMessage::decode(bytes.as_slice())?)
+    }
+}
+
+/// Writes every message exchanged with the controller to a file, one JSON object per line
+pub struct SessionRecorder {
+    writer: BufWriter<File>,
+}
+
+impl SessionRecorder {
+    pub fn create(path: impl AsRef<Path>) -> anyhow::Result<SessionRecorder> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create session log {}", path.display()))?;
+
+        Ok(SessionRecorder { writer: BufWriter::new(file) })
+    }
+
+    /// Each line is flushed right away so the log is usable even if the run crashes
+    pub fn record(&mut self, direction: Direction, message: &TcpMessage) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.writer, &RecordedMessage::new(direction, message))?;
+        writeln!(self.writer)?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+pub fn read_session(path: impl AsRef<Path>) -> anyhow::Result<Vec<RecordedMessage>> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open session log {}", path.display()))?;
+
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|l| l.trim().is_empty()))
+        .map(|(i, line)| {
+            serde_json::from_str(&line?)
+                .with_context(|| format!("Invalid message on line {} of {}", i + 1, path.display()))
+        })
+        .collect()
+}
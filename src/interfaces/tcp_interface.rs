@@ -1,34 +1,104 @@
 use crate::protobuf;
-use crate::protobuf::{tcp_message, DataMessage, ProtoVariable, StartMessage, TcpMessage, VariableDescription};
-use crate::types::runtime::RuntimeCommand;
-use crate::types::EntityVariableKey;
-use anyhow::{bail, Context};
+use crate::protobuf::{goal_message, goal_status_message, tcp_message, DataMessage, GoalMessage, GoalStatusMessage, ProtoVariable, SetupMessage, StartMessage, TcpMessage, VariableDescription};
+use crate::protobuf::start_message::ReconnectionType;
+use crate::runtime::goals::{GoalEvent, GoalEventKind};
+use crate::types::pattern::PatternItem;
+use crate::types::runtime::{RuntimeCommand, System};
+use crate::types::{EntityPatternValue, EntityVariableKey, Fact, Goal, GoalPolicy, MkVal, TimePatternRange};
+use anyhow::{anyhow, bail, Context};
+use itertools::Itertools;
 use prost::Message;
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::net::{TcpListener, TcpStream};
-use crate::interfaces::CommIds;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use crate::interfaces::{CommIds, Environment, InputEvent, ObservationTime};
+use crate::interfaces::session_log::{Direction, SessionRecorder};
 use crate::protobuf::variable_description::DataType;
 use crate::types::value::Value;
 
+#[derive(Clone, Debug)]
+pub struct TcpConfig {
+    pub bind_address: String,
+    pub port: u16,
+    /// How long to wait for the controller to connect, forever if not set
+    pub connect_timeout: Option<Duration>,
+    /// How long to wait for each message from the controller, forever if not set
+    pub read_timeout: Option<Duration>,
+    /// How long to wait for the setup message after connecting or reconnecting, forever if not set.
+    /// A restarting controller can take much longer to set up than to send the next observation
+    pub setup_timeout: Option<Duration>,
+    /// Sent to the controller in the start message to tell it what to do after a disconnect
+    pub reconnection_type: ReconnectionType,
+    /// Write every message exchanged with the controller to this file
+    pub record_path: Option<PathBuf>,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "127.0.0.1".to_string(),
+            port: 8080,
+            connect_timeout: None,
+            read_timeout: None,
+            setup_timeout: None,
+            reconnection_type: ReconnectionType::ReInit,
+            record_path: None,
+        }
+    }
+}
+
+/// Result of waiting for the next message from the controller
+#[derive(Clone, Debug)]
+pub enum TcpUpdate {
+    Variables(HashMap<EntityVariableKey, Value>),
+    Goal(GoalUpdate),
+    /// Nothing was received before the read timeout, or the controller was set up again and kept its state (RE_SETUP)
+    NoData,
+    /// The controller restarted and was set up again (RE_INIT), its state has nothing to do with the earlier one
+    Restarted,
+    /// The controller sent STOP or disconnected without planning to reconnect
+    Stopped,
+}
+
+/// Change to the goals of the system sent by the controller
+#[derive(Clone, Debug)]
+pub enum GoalUpdate {
+    /// Add the goal or replace the one with the same name. The deadline is relative to when the update is applied
+    Set(Goal),
+    /// Replace all goals with this one, its deadline is relative like for `Set`
+    ReplaceAll(Goal),
+    /// Remove the goal with this name, or all goals if the name is empty
+    Cancel(String),
+}
+
 pub struct TcpInterface {
-    #[allow(unused)]
+    config: TcpConfig,
     listener: TcpListener,
     stream: TcpStream,
     comm_ids: CommIds,
     command_descriptions: HashMap<String, VariableDescription>,
+    recorder: Option<SessionRecorder>,
+    observation_time: Option<ObservationTime>,
 }
 
 impl TcpInterface {
-    pub fn connect() -> anyhow::Result<Self> {
-        let listener = TcpListener::bind("127.0.0.1:8080")?;
+    pub fn connect(config: &TcpConfig) -> anyhow::Result<Self> {
+        let recorder = config.record_path.as_ref().map(SessionRecorder::create).transpose()?;
+        let listener = TcpListener::bind((config.bind_address.as_str(), config.port))
+            .with_context(|| format!("Failed to listen on {}:{}", config.bind_address, config.port))?;
         log::info!("Listening on {}", listener.local_addr()?);
-        let stream = listener.incoming().next().unwrap()?;
+        let stream = accept_connection(&listener, config.connect_timeout)?;
+        stream.set_read_timeout(config.read_timeout)?;
         let mut tcp_interface = Self {
+            config: config.clone(),
             listener,
             stream,
             comm_ids: CommIds::new(),
             command_descriptions: HashMap::new(),
+            recorder,
+            observation_time: None,
         };
         log::info!("Connected, waiting for setup message");
         tcp_interface.handle_setup_message()?;
@@ -38,56 +108,147 @@ impl TcpInterface {
         Ok(tcp_interface)
     }
 
-    pub fn update_variables(&mut self) -> HashMap<EntityVariableKey, Value> {
-        let message = self.listen_for_message()
-            .expect("Failed to receive variables from controller")
-            .expect("Receiving variables timed out");
-        let tcp_message::Message::DataMessage(dm) = message.message.unwrap() else {
-          panic!("Received invalid message from controller");
+    pub fn update_variables(&mut self) -> anyhow::Result<TcpUpdate> {
+        let message = match self.listen_for_message() {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(TcpUpdate::NoData),
+            Err(e) if is_disconnect(&e) => return self.handle_disconnect(),
+            Err(e) => return Err(e.context("Failed to receive variables from controller")),
         };
-        let variables = dm.variables.into_iter().map(|v| {
-            let desc = v.meta_data.as_ref().unwrap();
 
-            (EntityVariableKey::new(self.comm_ids.get_name(desc.entity_id), self.comm_ids.get_name(desc.id)), decode_runtime_value(&v, &self.comm_ids))
-        }).collect();
+        match (tcp_message::Type::try_from(message.message_type), message.message) {
+            (Ok(tcp_message::Type::Data), Some(tcp_message::Message::DataMessage(dm))) => {
+                self.observation_time = observation_time(message.timestamp, &dm);
+                Ok(TcpUpdate::Variables(decode_variables(dm.variables, &self.comm_ids)?))
+            }
+            (Ok(tcp_message::Type::Goal), Some(tcp_message::Message::GoalMessage(goal_message))) => {
+                Ok(TcpUpdate::Goal(decode_goal_message(goal_message, &self.comm_ids)?))
+            }
+            (Ok(tcp_message::Type::Stop), _) => {
+                log::info!("Controller sent stop message");
+                Ok(TcpUpdate::Stopped)
+            }
+            // The controller can also send a new setup without announcing it first
+            (Ok(tcp_message::Type::Setup), Some(tcp_message::Message::SetupMessage(setup_message))) => {
+                log::info!("Controller sent new setup message");
+                self.apply_setup_message(setup_message);
+                self.send_start_message()?;
+                Ok(self.after_setup())
+            }
+            (Ok(tcp_message::Type::Reconnect), _) => {
+                if self.config.reconnection_type == ReconnectionType::None {
+                    log::info!("Controller is reconnecting, but no reconnection was requested");
+                    return Ok(TcpUpdate::Stopped);
+                }
+                log::info!("Controller is reconnecting, waiting for setup message");
+                self.handle_setup_message()?;
+                self.send_start_message()?;
+                Ok(self.after_setup())
+            }
+            _ => bail!("Received invalid message from controller"),
+        }
+    }
+
+    /// Wait for the controller to connect again and set it up with the negotiated reconnection type
+    fn handle_disconnect(&mut self) -> anyhow::Result<TcpUpdate> {
+        if self.config.reconnection_type == ReconnectionType::None {
+            log::info!("Controller disconnected");
+            return Ok(TcpUpdate::Stopped);
+        }
 
-        variables
+        log::warn!("Controller disconnected, waiting for it to reconnect");
+        self.stream = accept_connection(&self.listener, self.config.connect_timeout)
+            .context("Controller did not reconnect")?;
+        self.stream.set_read_timeout(self.config.read_timeout)?;
+        log::info!("Reconnected, waiting for setup message");
+        self.handle_setup_message()?;
+        self.send_start_message()?;
+
+        Ok(self.after_setup())
+    }
+
+    /// A controller set up again with RE_INIT has restarted, with RE_SETUP it continues where it was
+    fn after_setup(&self) -> TcpUpdate {
+        match self.config.reconnection_type {
+            ReconnectionType::ReInit => TcpUpdate::Restarted,
+            _ => TcpUpdate::NoData,
+        }
     }
 
     pub fn execute_command(&mut self, command: &RuntimeCommand) -> anyhow::Result<()> {
         let command_desc = self.command_descriptions.get(&command.name).context("Command has not been registered by controller")?;
+        let data_type = DataType::try_from(command_desc.data_type)
+            .map_err(|_| anyhow!("Command {} has unsupported data type {}", command.name, command_desc.data_type))?;
 
-        self.send_tcp_message(&TcpMessage {
+        let result = self.send_tcp_message(&TcpMessage {
             message_type: tcp_message::Type::Data as i32,
             timestamp: 0,
             message: Some(tcp_message::Message::DataMessage(DataMessage {
                 variables: vec![
                     ProtoVariable {
                         meta_data: Some(command_desc.clone()),
-                        data: values_to_le_bytes(&command.params, &self.comm_ids),
+                        data: values_to_le_bytes(&command.params, data_type, &self.comm_ids)?,
                     }
                 ],
                 time_span: 0,
             })),
-        })?;
+        });
+        match result {
+            // The disconnect is handled when the next message is read
+            Err(e) if is_disconnect(&e) => {
+                log::warn!("Controller disconnected before command {command} could be sent");
+                Ok(())
+            }
+            result => result,
+        }
+    }
 
-        Ok(())
+    pub fn send_goal_status(&mut self, event: &GoalEvent) -> anyhow::Result<()> {
+        let status = match event.kind {
+            GoalEventKind::Achieved => goal_status_message::Status::Achieved,
+            GoalEventKind::Abandoned => goal_status_message::Status::Abandoned,
+            GoalEventKind::NoPlan => goal_status_message::Status::NoPlan,
+            GoalEventKind::Missed => goal_status_message::Status::Missed,
+        };
+        let result = self.send_tcp_message(&TcpMessage {
+            message_type: tcp_message::Type::GoalStatus as i32,
+            timestamp: 0,
+            message: Some(tcp_message::Message::GoalStatusMessage(GoalStatusMessage {
+                name: event.goal.clone(),
+                status: status as i32,
+            })),
+        });
+        match result {
+            Err(e) if is_disconnect(&e) => {
+                log::warn!("Controller disconnected before the status of goal {} could be sent", event.goal);
+                Ok(())
+            }
+            result => result,
+        }
     }
 
+    /// Wait for the setup message with the setup timeout instead of the read timeout
     fn handle_setup_message(&mut self) -> anyhow::Result<()> {
-        let message = self.listen_for_message()?.unwrap();
+        self.stream.set_read_timeout(self.config.setup_timeout)?;
+        let message = self.listen_for_message();
+        self.stream.set_read_timeout(self.config.read_timeout)?;
+        let message = message?.context("Timed out waiting for setup message")?;
         let Some(tcp_message::Message::SetupMessage(setup_message)) = message.message else {
             bail!("Invalid setup message");
         };
-        self.comm_ids.insert_map(&setup_message.entities);
-        self.comm_ids.insert_map(&setup_message.objects);
-        self.comm_ids.insert_map(&setup_message.commands);
+        self.apply_setup_message(setup_message);
+
+        Ok(())
+    }
+
+    /// The setup message lists everything the controller has, so whatever it set up before is forgotten
+    fn apply_setup_message(&mut self, setup_message: SetupMessage) {
+        self.comm_ids = setup_comm_ids(&setup_message);
         self.command_descriptions = setup_message.command_descriptions.into_iter()
             .map(|c| {
                 (c.name, c.description.unwrap())
-            }).collect();
-
-        Ok(())
+            })
+            .collect();
     }
 
     fn send_start_message(&mut self) -> anyhow::Result<()> {
@@ -96,7 +257,7 @@ impl TcpInterface {
             timestamp: 0,
             message: Some(tcp_message::Message::StartMessage(StartMessage {
                 diagnostic_mode: true,
-                reconnection_type: 0,
+                reconnection_type: self.config.reconnection_type as i32,
             })),
         })?;
 
@@ -106,98 +267,376 @@ impl TcpInterface {
     fn send_tcp_message(&mut self, message: &TcpMessage) -> anyhow::Result<()> {
         let encoded = message.encode_to_vec();
         let size_bytes = (encoded.len() as u64).to_le_bytes();
-        self.stream.write(&size_bytes)?;
-        self.stream.write(&encoded)?;
+        self.stream.write_all(&size_bytes)?;
+        self.stream.write_all(&encoded)?;
+        self.record(Direction::Sent, message);
 
         Ok(())
     }
 
     fn listen_for_message(&mut self) -> anyhow::Result<Option<TcpMessage>> {
+        // Only the first byte is waited for with the timeout, a timeout later in the header would lose the bytes read so far
         let mut size_buf = vec![0; 8];
-        match self.stream.read_exact(&mut size_buf[..]) {
-            Ok(()) => {}
+        match self.stream.read(&mut size_buf[..1]) {
+            Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(_) => {}
             Err(e) => {
-                return if e.kind() == std::io::ErrorKind::TimedOut {
+                // Depending on the platform a read timeout is reported as either of these
+                return if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) {
                     Ok(None)
                 } else {
                     Err(e.into())
                 };
             }
         }
-        let size = le_bytes_to_u64(&size_buf[..]);
 
+        // The message has started arriving, so the rest of it has to arrive within the timeout as a whole.
+        // A controller that stalls in the middle of a message can't be kept track of, that is an error
+        let read_timeout = self.stream.read_timeout()?;
+        let deadline = read_timeout.map(|timeout| Instant::now() + timeout);
+        let result = self.read_message_after_first_byte(&mut size_buf, deadline);
+        self.stream.set_read_timeout(read_timeout)?;
+        let data_buf = result.context("Controller stopped sending in the middle of a message")?;
+
+        let message = protobuf::TcpMessage::decode(data_buf.as_slice())?;
+        self.record(Direction::Received, &message);
+
+        Ok(Some(message))
+    }
+
+    /// Read the rest of the size header, whose first byte has been read already, and then the message data
+    fn read_message_after_first_byte(&mut self, size_buf: &mut [u8], deadline: Option<Instant>) -> std::io::Result<Vec<u8>> {
+        read_exact_before(&mut self.stream, &mut size_buf[1..], deadline)?;
+        let size = le_bytes_to_u64(size_buf);
         let mut data_buf = vec![0; size as usize];
-        self.stream.read_exact(&mut data_buf[..])?;
+        read_exact_before(&mut self.stream, &mut data_buf[..], deadline)?;
 
-        Ok(Some(protobuf::TcpMessage::decode(data_buf.as_slice())?))
+        Ok(data_buf)
+    }
+
+    /// A failing recorder should not end the session, so errors are only logged
+    fn record(&mut self, direction: Direction, message: &TcpMessage) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(direction, message) {
+                log::warn!("Failed to record message: {e:#}");
+            }
+        }
     }
 }
 
-fn values_to_le_bytes(values: &[Value], comm_ids: &CommIds) -> Vec<u8> {
-    values.into_iter().flat_map(|v| match v {
-        Value::Number(v) => v.to_le_bytes().to_vec(),
-        Value::ConstantNumber(v) => v.to_le_bytes().to_vec(),
-        // Std should probably never be sent to the controller
-        Value::UncertainNumber(m, _) => m.to_le_bytes().to_vec(),
-        Value::String(v) => v.as_bytes().to_vec(),
-        Value::EntityId(e) => comm_ids.get_id(e).to_le_bytes().to_vec(),
-        Value::Vec(list) => values_to_le_bytes(list, comm_ids),
-    }).collect()
+fn is_disconnect(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<std::io::Error>().is_some_and(|e| matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+    ))
 }
 
-fn decode_runtime_value(proto_variable: &ProtoVariable, comm_ids: &CommIds) -> Value {
-    let meta_data = proto_variable.meta_data.as_ref().unwrap();
-    if meta_data.data_type == DataType::Double as i32 {
-        if meta_data.dimensions[0] > 1 {
-            Value::Vec(proto_variable.data.chunks(8).map(|d| Value::Number(le_bytes_to_f64(d))).collect())
+/// Fill the buffer from the stream, or fail with `TimedOut` if that takes until the deadline
+fn read_exact_before(stream: &mut TcpStream, buf: &mut [u8], deadline: Option<Instant>) -> std::io::Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            stream.set_read_timeout(Some(remaining))?;
         }
-        else {
-            Value::Number(le_bytes_to_f64(&proto_variable.data))
+        match stream.read(&mut buf[read..]) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Err(ErrorKind::TimedOut.into()),
+            Err(e) => return Err(e),
         }
     }
-    else if meta_data.data_type == DataType::UncertainDouble as i32 {
-        if meta_data.dimensions[0] > 1 {
-            Value::Vec(proto_variable.data.chunks(16).map(|d| Value::UncertainNumber(le_bytes_to_f64(&d[..8]), le_bytes_to_f64(&d[8..]))).collect())
+
+    Ok(())
+}
+
+fn accept_connection(listener: &TcpListener, timeout: Option<Duration>) -> anyhow::Result<TcpStream> {
+    let Some(timeout) = timeout else {
+        return Ok(listener.accept()?.0);
+    };
+
+    // The listener has no accept timeout, so poll it without blocking until the deadline
+    let deadline = Instant::now() + timeout;
+    listener.set_nonblocking(true)?;
+    let stream = loop {
+        match listener.accept() {
+            Ok((stream, _)) => break stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    listener.set_nonblocking(false)?;
+                    bail!("No controller connected within {timeout:?}");
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => return Err(e.into()),
         }
-        else {
-            Value::UncertainNumber(le_bytes_to_f64(&proto_variable.data[..8]), le_bytes_to_f64(&proto_variable.data[8..]))
+    };
+    listener.set_nonblocking(false)?;
+    stream.set_nonblocking(false)?;
+
+    Ok(stream)
+}
+
+impl Environment for TcpInterface {
+    fn observe(&mut self, system: &mut System) -> anyhow::Result<InputEvent> {
+        loop {
+            let update = self.update_variables()?;
+            if let Some(event) = apply_update(update, system) {
+                return Ok(event);
+            }
         }
     }
-    else if meta_data.data_type == DataType::Int64 as i32 {
-        if meta_data.dimensions[0] > 1 {
-            Value::Vec(proto_variable.data.chunks(8).map(|d| Value::Number(le_bytes_to_i64(d) as f64)).collect())
+
+    fn observation_time(&self) -> Option<ObservationTime> {
+        self.observation_time
+    }
+
+    fn execute(&mut self, command: &RuntimeCommand, _system: &mut System) -> anyhow::Result<()> {
+        self.execute_command(command)
+    }
+
+    fn report_goal(&mut self, event: &GoalEvent) -> anyhow::Result<()> {
+        self.send_goal_status(event)
+    }
+}
+
+/// Ids of the entities, objects and commands of a setup message. Ids of earlier setups are not kept,
+/// the controller could have given them to something else
+pub fn setup_comm_ids(setup_message: &SetupMessage) -> CommIds {
+    let mut comm_ids = CommIds::new();
+    comm_ids.insert_map(&setup_message.entities);
+    comm_ids.insert_map(&setup_message.objects);
+    comm_ids.insert_map(&setup_message.commands);
+    comm_ids
+}
+
+/// The controller sets the timestamp or the time span of its data messages in milliseconds, zero when it has no clock
+pub fn observation_time(timestamp: u64, data_message: &DataMessage) -> Option<ObservationTime> {
+    if timestamp != 0 {
+        Some(ObservationTime::Timestamp(timestamp))
+    } else if data_message.time_span != 0 {
+        Some(ObservationTime::Span(data_message.time_span))
+    } else {
+        None
+    }
+}
+
+/// Replace the observed variables of the system with the ones the controller sent.
+/// Goal updates are applied right away and return None, since the observation is still to come
+pub fn apply_update(update: TcpUpdate, system: &mut System) -> Option<InputEvent> {
+    match update {
+        TcpUpdate::Variables(variables) => {
+            system.current_state.variables = variables;
+            Some(InputEvent::Observed)
         }
-        else {
-            Value::Number(le_bytes_to_i64(&proto_variable.data) as f64)
+        TcpUpdate::Goal(goal_update) => {
+            apply_goal_update(goal_update, system);
+            None
         }
+        TcpUpdate::NoData => Some(InputEvent::NoObservation),
+        TcpUpdate::Restarted => Some(InputEvent::Restarted),
+        TcpUpdate::Stopped => Some(InputEvent::Stopped),
     }
-    else if meta_data.data_type == DataType::ConstInt64 as i32 {
-        if meta_data.dimensions[0] > 1 {
-            Value::Vec(proto_variable.data.chunks(8).map(|d| Value::ConstantNumber(le_bytes_to_i64(d) as f64)).collect())
+}
+
+pub fn apply_goal_update(update: GoalUpdate, system: &mut System) {
+    let now = system.current_state.time.end();
+    match update {
+        GoalUpdate::Set(mut goal) => {
+            goal.deadline = goal.deadline.map(|timeout| now + timeout);
+            log::info!("Controller set goal {goal}");
+            match system.goals.iter_mut().find(|g| g.name == goal.name) {
+                Some(existing) => *existing = goal,
+                None => system.goals.push(goal),
+            }
+        }
+        GoalUpdate::ReplaceAll(mut goal) => {
+            goal.deadline = goal.deadline.map(|timeout| now + timeout);
+            log::info!("Controller replaced all goals with {goal}");
+            system.goals = vec![goal];
         }
-        else {
-            Value::ConstantNumber(le_bytes_to_i64(&proto_variable.data) as f64)
+        GoalUpdate::Cancel(name) if name.is_empty() => {
+            log::info!("Controller cancelled all goals");
+            system.goals.clear();
         }
+        GoalUpdate::Cancel(name) => {
+            log::info!("Controller cancelled goal {name}");
+            if !system.goals.iter().any(|g| g.name == name) {
+                log::warn!("Goal {name} to cancel does not exist");
+            }
+            system.goals.retain(|g| g.name != name);
+        }
+    }
+}
+
+/// The facts of the goal are variables like in data messages, the goal holds when all of them have the sent values
+pub fn decode_goal_message(message: GoalMessage, comm_ids: &CommIds) -> anyhow::Result<GoalUpdate> {
+    let operation = goal_message::Operation::try_from(message.operation)
+        .map_err(|_| anyhow!("Unknown goal operation {}", message.operation))?;
+    if operation == goal_message::Operation::Cancel {
+        return Ok(GoalUpdate::Cancel(message.name));
+    }
+    if message.name.is_empty() {
+        bail!("Goal without a name");
     }
-    else if meta_data.data_type == DataType::CommunicationId as i32 {
-        let id = le_bytes_to_i64(&proto_variable.data) as i32;
-        if id != -1 {
-            Value::Vec(vec![Value::EntityId(comm_ids.get_name(id).to_owned())])
+
+    let facts = decode_variables(message.facts, comm_ids)?
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| (&a.entity_id, &a.var_name).cmp(&(&b.entity_id, &b.var_name)))
+        .map(|(key, value)| Fact::new(MkVal {
+            entity_id: EntityPatternValue::EntityId(key.entity_id),
+            var_name: key.var_name,
+            value: PatternItem::Value(value),
+            assumption: false,
+        }, TimePatternRange::wildcard()))
+        .collect_vec();
+    let mut goal = Goal::new(&message.name, facts, message.priority);
+    goal.deadline = (message.timeout != 0).then_some(message.timeout);
+    goal.policy = match goal_message::Policy::try_from(message.policy) {
+        Ok(goal_message::Policy::Retry) => GoalPolicy::Retry,
+        Ok(goal_message::Policy::Drive) => GoalPolicy::Drive,
+        Ok(goal_message::Policy::Abandon) => GoalPolicy::AbandonAfter(message.max_attempts as usize),
+        Err(_) => bail!("Unknown goal policy {}", message.policy),
+    };
+
+    Ok(match operation {
+        goal_message::Operation::ReplaceAll => GoalUpdate::ReplaceAll(goal),
+        _ => GoalUpdate::Set(goal),
+    })
+}
+
+pub fn decode_variables(variables: Vec<ProtoVariable>, comm_ids: &CommIds) -> anyhow::Result<HashMap<EntityVariableKey, Value>> {
+    variables.into_iter().map(|v| {
+        let desc = v.meta_data.as_ref().context("Variable without meta data")?;
+        let key = EntityVariableKey::new(comm_ids.get_name(desc.entity_id)?, comm_ids.get_name(desc.id)?);
+        let value = decode_runtime_value(&v, comm_ids)
+            .with_context(|| format!("Failed to decode {} {}", key.entity_id, key.var_name))?;
+
+        Ok((key, value))
+    }).collect()
+}
+
+/// Encode command parameters in the layout the controller declared for the command
+pub fn values_to_le_bytes(values: &[Value], data_type: DataType, comm_ids: &CommIds) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for value in values {
+        bytes.extend(value_to_le_bytes(value, data_type, comm_ids)?);
+    }
+
+    Ok(bytes)
+}
+
+fn value_to_le_bytes(value: &Value, data_type: DataType, comm_ids: &CommIds) -> anyhow::Result<Vec<u8>> {
+    if matches!(data_type, DataType::Bool | DataType::Bytes) {
+        bail!("{} data is not supported", data_type.as_str_name());
+    }
+    let number = match value {
+        // An id of -1 means that nothing is referenced
+        Value::Vec(list) if list.is_empty() && data_type == DataType::CommunicationId => return Ok((-1i64).to_le_bytes().to_vec()),
+        Value::Vec(list) => return values_to_le_bytes(list, data_type, comm_ids),
+        Value::String(v) if data_type == DataType::String => return Ok(v.as_bytes().to_vec()),
+        Value::EntityId(e) if matches!(data_type, DataType::CommunicationId | DataType::Int64 | DataType::ConstInt64) => {
+            return Ok((comm_ids.try_get_id(e)? as i64).to_le_bytes().to_vec());
         }
-        else {
-            Value::Vec(vec![])
+        Value::Number(v) | Value::ConstantNumber(v) => *v,
+        Value::UncertainNumber(m, s) if data_type == DataType::UncertainDouble => {
+            return Ok([m.to_le_bytes(), s.to_le_bytes()].concat());
         }
+        // Std should probably never be sent to the controller unless it asks for it
+        Value::UncertainNumber(m, _) => *m,
+        _ => bail!("Can't encode {value} as {}", data_type.as_str_name()),
+    };
+
+    Ok(match data_type {
+        DataType::Double => number.to_le_bytes().to_vec(),
+        DataType::UncertainDouble => [number.to_le_bytes(), 0f64.to_le_bytes()].concat(),
+        DataType::Int64 | DataType::ConstInt64 | DataType::CommunicationId => (number.round() as i64).to_le_bytes().to_vec(),
+        DataType::String => number.to_string().into_bytes(),
+        DataType::Bool | DataType::Bytes => unreachable!(),
+    })
+}
+
+/// Decode the data of a variable into a value, or nested vectors of values if it has more than one element.
+/// A variable with the single dimension 1 is a plain value. Values have no type for booleans and bytes,
+/// so BOOL and BYTES variables are rejected
+pub fn decode_runtime_value(proto_variable: &ProtoVariable, comm_ids: &CommIds) -> anyhow::Result<Value> {
+    let meta_data = proto_variable.meta_data.as_ref().context("Variable without meta data")?;
+    let data_type = DataType::try_from(meta_data.data_type)
+        .map_err(|_| anyhow!("Unsupported data type {} received", meta_data.data_type))?;
+    let data = &proto_variable.data;
+    if matches!(data_type, DataType::Bool | DataType::Bytes) {
+        bail!("{} data is not supported", data_type.as_str_name());
+    }
+
+    // A string is always a single value however many bytes it has
+    if data_type == DataType::String {
+        return Ok(Value::String(le_bytes_to_string(data)?));
+    }
+
+    let element_size = match data_type {
+        DataType::UncertainDouble => 16,
+        _ => 8,
+    };
+    let mut dimensions = meta_data.dimensions.iter().map(|d| *d as usize).collect_vec();
+    if dimensions.is_empty() {
+        dimensions.push(data.len() / element_size);
     }
-    else if meta_data.data_type == DataType::String as i32 {
-        Value::String(le_bytes_to_string(&proto_variable.data))
+    let element_count: usize = dimensions.iter().product();
+    if data.len() != element_count * element_size {
+        bail!(
+            "Expected {} bytes for {} with dimensions {:?}, got {}",
+            element_count * element_size, data_type.as_str_name(), dimensions, data.len()
+        );
     }
-    else {
-        panic!("Unsupported data type received")
+
+    let elements = data.chunks(element_size).map(|d| Ok(match data_type {
+        DataType::Double => Value::Number(le_bytes_to_f64(d)),
+        DataType::UncertainDouble => Value::UncertainNumber(le_bytes_to_f64(&d[..8]), le_bytes_to_f64(&d[8..])),
+        DataType::Int64 => Value::Number(le_bytes_to_i64(d) as f64),
+        DataType::ConstInt64 => Value::ConstantNumber(le_bytes_to_i64(d) as f64),
+        // An id of -1 means that nothing is referenced
+        DataType::CommunicationId => match le_bytes_to_i64(d) as i32 {
+            -1 => Value::Vec(vec![]),
+            id => Value::EntityId(comm_ids.get_name(id)?.to_owned()),
+        },
+        DataType::String | DataType::Bool | DataType::Bytes => unreachable!(),
+    })).collect::<anyhow::Result<Vec<_>>>()?;
+
+    if dimensions == [1] {
+        let element = elements.into_iter().next().unwrap();
+        // Single ids are wrapped in a list so they can be compared with an empty list when nothing is referenced
+        return Ok(match element {
+            Value::EntityId(_) => Value::Vec(vec![element]),
+            element => element,
+        });
     }
+
+    Ok(nest_elements(elements, &dimensions))
 }
 
-fn le_bytes_to_string(slice: &[u8]) -> String {
-    String::from_utf8(slice.to_vec()).expect("Failed to decode invalid UTF-8 string")
+fn nest_elements(elements: Vec<Value>, dimensions: &[usize]) -> Value {
+    if dimensions.len() <= 1 {
+        return Value::Vec(elements);
+    }
+    let inner_size: usize = dimensions[1..].iter().product();
+    if inner_size == 0 {
+        return Value::Vec((0..dimensions[0]).map(|_| nest_elements(Vec::new(), &dimensions[1..])).collect());
+    }
+
+    Value::Vec(elements
+        .into_iter()
+        .chunks(inner_size)
+        .into_iter()
+        .map(|chunk| nest_elements(chunk.collect(), &dimensions[1..]))
+        .collect())
+}
+
+fn le_bytes_to_string(slice: &[u8]) -> anyhow::Result<String> {
+    String::from_utf8(slice.to_vec()).context("Failed to decode invalid UTF-8 string")
 }
 
 fn le_bytes_to_f64(slice: &[u8]) -> f64 {
@@ -214,3 +653,162 @@ fn le_bytes_to_i64(slice: &[u8]) -> i64 {
     let bytes: [u8; 8] = slice.try_into().expect("Incorrect slice length");
     i64::from_le_bytes(bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::{Duration, Instant};
+    use prost::Message;
+    use crate::interfaces::tcp_interface::{decode_runtime_value, nest_elements, values_to_le_bytes, TcpConfig, TcpInterface};
+    use crate::interfaces::CommIds;
+    use crate::protobuf::variable_description::DataType;
+    use crate::protobuf::{tcp_message, ProtoVariable, StopMessage, TcpMessage, VariableDescription};
+    use crate::types::value::Value;
+
+    fn numbers(values: &[f64]) -> Vec<Value> {
+        values.iter().map(|v| Value::Number(*v)).collect()
+    }
+
+    fn doubles(values: &[f64], dimensions: &[u64]) -> ProtoVariable {
+        ProtoVariable {
+            meta_data: Some(VariableDescription {
+                entity_id: 1,
+                id: 2,
+                data_type: DataType::Double as i32,
+                dimensions: dimensions.to_vec(),
+                opcode_string_handle: String::new(),
+            }),
+            data: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        }
+    }
+
+    fn comm_ids() -> CommIds {
+        let mut comm_ids = CommIds::new();
+        comm_ids.insert(1, "h");
+        comm_ids.insert(2, "position");
+        comm_ids.insert(3, "b_0");
+        comm_ids
+    }
+
+    #[test]
+    fn elements_are_nested_by_dimensions() {
+        let elements = numbers(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(nest_elements(elements.clone(), &[6]), Value::Vec(elements.clone()));
+        assert_eq!(nest_elements(elements.clone(), &[2, 3]), Value::Vec(vec![
+            Value::Vec(numbers(&[1.0, 2.0, 3.0])),
+            Value::Vec(numbers(&[4.0, 5.0, 6.0])),
+        ]));
+        assert_eq!(nest_elements(elements, &[3, 2, 1]), Value::Vec(vec![
+            Value::Vec(vec![Value::Vec(numbers(&[1.0])), Value::Vec(numbers(&[2.0]))]),
+            Value::Vec(vec![Value::Vec(numbers(&[3.0])), Value::Vec(numbers(&[4.0]))]),
+            Value::Vec(vec![Value::Vec(numbers(&[5.0])), Value::Vec(numbers(&[6.0]))]),
+        ]));
+        // Inner dimensions of zero still give the outer lists
+        assert_eq!(nest_elements(Vec::new(), &[2, 0]), Value::Vec(vec![Value::Vec(vec![]), Value::Vec(vec![])]));
+    }
+
+    #[test]
+    fn data_has_to_match_the_dimensions() {
+        let comm_ids = comm_ids();
+        assert_eq!(decode_runtime_value(&doubles(&[1.5], &[1]), &comm_ids).unwrap(), Value::Number(1.5));
+        // Without dimensions the data is a list of as many elements as it holds
+        assert_eq!(decode_runtime_value(&doubles(&[1.5, 2.5], &[]), &comm_ids).unwrap(), Value::Vec(numbers(&[1.5, 2.5])));
+        assert_eq!(
+            decode_runtime_value(&doubles(&[1.0, 2.0, 3.0, 4.0], &[2, 2]), &comm_ids).unwrap(),
+            Value::Vec(vec![Value::Vec(numbers(&[1.0, 2.0])), Value::Vec(numbers(&[3.0, 4.0]))])
+        );
+
+        let error = decode_runtime_value(&doubles(&[1.0, 2.0, 3.0], &[2, 2]), &comm_ids).unwrap_err();
+        assert_eq!(error.to_string(), "Expected 32 bytes for DOUBLE with dimensions [2, 2], got 24");
+        assert!(decode_runtime_value(&doubles(&[1.0], &[2]), &comm_ids).is_err());
+    }
+
+    #[test]
+    fn bool_and_bytes_are_rejected() {
+        let comm_ids = comm_ids();
+        for data_type in [DataType::Bool, DataType::Bytes] {
+            let mut variable = doubles(&[], &[1]);
+            variable.meta_data.as_mut().unwrap().data_type = data_type as i32;
+            variable.data = vec![1];
+            assert!(decode_runtime_value(&variable, &comm_ids).is_err());
+            assert!(values_to_le_bytes(&numbers(&[1.0]), data_type, &comm_ids).is_err());
+        }
+    }
+
+    #[test]
+    fn ids_are_encoded_with_their_communication_id() {
+        let comm_ids = comm_ids();
+        let encoded = values_to_le_bytes(&[Value::Vec(vec![Value::EntityId("b_0".to_string())])], DataType::CommunicationId, &comm_ids).unwrap();
+        assert_eq!(encoded, 3i64.to_le_bytes());
+        // Holding nothing is sent as the id -1
+        let encoded = values_to_le_bytes(&[Value::Vec(vec![])], DataType::CommunicationId, &comm_ids).unwrap();
+        assert_eq!(encoded, (-1i64).to_le_bytes());
+
+        let error = values_to_le_bytes(&[Value::EntityId("b_9".to_string())], DataType::CommunicationId, &comm_ids).unwrap_err();
+        assert_eq!(error.to_string(), "Name b_9 has no registered communication id");
+    }
+
+    /// Interface connected to a controller played by the returned stream, without any setup
+    fn connected(read_timeout: Duration) -> (TcpInterface, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let controller = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = listener.accept().unwrap().0;
+        stream.set_read_timeout(Some(read_timeout)).unwrap();
+        let tcp_interface = TcpInterface {
+            config: TcpConfig { read_timeout: Some(read_timeout), ..TcpConfig::default() },
+            listener,
+            stream,
+            comm_ids: CommIds::new(),
+            command_descriptions: HashMap::new(),
+            recorder: None,
+            observation_time: None,
+        };
+        (tcp_interface, controller)
+    }
+
+    fn stop_message() -> Vec<u8> {
+        let message = TcpMessage {
+            message_type: tcp_message::Type::Stop as i32,
+            timestamp: 0,
+            message: Some(tcp_message::Message::StopMessage(StopMessage {})),
+        }.encode_to_vec();
+        [(message.len() as u64).to_le_bytes().to_vec(), message].concat()
+    }
+
+    #[test]
+    fn nothing_sent_in_time_is_no_message() {
+        let (mut tcp_interface, _controller) = connected(Duration::from_millis(50));
+        assert!(tcp_interface.listen_for_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn message_that_arrives_in_parts_is_read() {
+        let (mut tcp_interface, mut controller) = connected(Duration::from_secs(5));
+        let message = stop_message();
+        let sender = std::thread::spawn(move || {
+            for part in message.chunks(3) {
+                controller.write_all(part).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            controller
+        });
+        let received = tcp_interface.listen_for_message().unwrap().unwrap();
+        assert_eq!(received.message_type, tcp_message::Type::Stop as i32);
+        sender.join().unwrap();
+    }
+
+    /// The rest of a message that has started arriving has to arrive within the timeout too
+    #[test]
+    fn stalling_in_the_middle_of_a_message_is_an_error() {
+        let (mut tcp_interface, mut controller) = connected(Duration::from_millis(200));
+        controller.write_all(&stop_message()[..5]).unwrap();
+        let started = Instant::now();
+        let error = tcp_interface.listen_for_message().unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(error.to_string(), "Controller stopped sending in the middle of a message");
+        // The read timeout for the next messages is kept
+        assert_eq!(tcp_interface.stream.read_timeout().unwrap(), Some(Duration::from_millis(200)));
+    }
+}
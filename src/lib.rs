@@ -0,0 +1,10 @@
+pub mod types;
+pub mod runtime;
+pub mod interfaces;
+pub mod utils;
+pub mod replicode;
+mod visualize;
+
+pub mod protobuf {
+    include!(concat!(env!("OUT_DIR"), "/tcp_io_device.rs"));
+}
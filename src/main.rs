@@ -1,19 +1,79 @@
-pub mod types;
-pub mod runtime;
-pub mod interfaces;
-pub mod utils;
-mod visualize;
+mod cli;
 
-pub mod protobuf {
-    include!(concat!(env!("OUT_DIR"), "/tcp_io_device.rs"));
-}
+use std::fs;
+use std::process::exit;
+use anyhow::Context;
+use itertools::Itertools;
+use aera_exp::{replicode, runtime};
+use crate::cli::{Args, CliAction, InterfaceKind, SeedSource};
+use aera_exp::interfaces::hand_grab_sphere_world::HandGrabSphereWorld;
+use aera_exp::interfaces::replay_interface::ReplayInterface;
+use aera_exp::interfaces::tcp_interface::TcpInterface;
+use aera_exp::interfaces::NoEnvironment;
+use aera_exp::runtime::SimulatedRobotFrames;
+use aera_exp::runtime::seeds::{builtin_seed, BUILTIN_SEEDS};
+use aera_exp::types::runtime::System;
 
 fn main() {
-    setup_logging();
+    let args = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(CliAction::Run(args)) => args,
+        Ok(CliAction::ListSeeds) => {
+            println!("{}", BUILTIN_SEEDS.iter().map(|(name, _)| name).join("\n"));
+            return;
+        }
+        Ok(CliAction::Help) => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{e:#}\n\n{}", cli::USAGE);
+            exit(2);
+        }
+    };
+    setup_logging(&args.log_level);
+
+    if let Err(e) = run(args) {
+        log::error!("{e:#}");
+        exit(1);
+    }
+}
 
-    runtime::run_with_tcp();
+fn run(args: Args) -> anyhow::Result<()> {
+    let seed = match &args.seed {
+        SeedSource::Builtin(name) => {
+            let seed = builtin_seed(name).context("Unknown seed")?;
+            Box::new(seed) as Box<dyn FnOnce(&mut System)>
+        }
+        SeedSource::File(path) => {
+            let source = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read seed file {}", path.display()))?;
+            // Parse once up front so errors in the seed are reported before connecting to anything
+            replicode::parse_into_system(&source, &mut System::new())
+                .with_context(|| format!("Invalid seed file {}", path.display()))?;
+            Box::new(move |system: &mut System| {
+                replicode::parse_into_system(&source, system).expect("Seed file was already validated");
+            })
+        }
+    };
+
+    let options = &args.run_options;
+    match args.interface {
+        InterfaceKind::Tcp => {
+            let tcp_interface = TcpInterface::connect(&args.tcp).context("Failed to connect to controller with TCP")?;
+            runtime::run_aera(seed, tcp_interface, options)?
+        }
+        InterfaceKind::Sim => runtime::run_aera(seed, HandGrabSphereWorld::new(), options)?,
+        InterfaceKind::Frames => runtime::run_aera(seed, SimulatedRobotFrames::new(), options)?,
+        InterfaceKind::Replay => {
+            let session = args.session.as_ref().context("No session to replay")?;
+            runtime::run_aera(seed, ReplayInterface::open(session)?, options)?
+        }
+        InterfaceKind::None => runtime::run_aera(seed, NoEnvironment, options)?,
+    };
+
+    Ok(())
 }
 
-fn setup_logging() {
-    simple_log::quick!();
-}
\ No newline at end of file
+fn setup_logging(level: &str) {
+    simple_log::quick!(level);
+}
@@ -0,0 +1,141 @@
+use anyhow::bail;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenKind {
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Pipe,
+    Comma,
+    /// A lone `:`, used for wildcard time values
+    Colon,
+    /// `::`, the wildcard pattern
+    Any,
+    /// `name:`
+    Binding(String),
+    Ident(String),
+    /// Kept as text since it is read as an integer or a float depending on where it appears
+    Number(String),
+    Str(String),
+    /// One of `+ - * /`
+    Operator(char),
+    /// Text after `;` until the end of the line
+    Comment(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+}
+
+pub fn tokenize(source: &str) -> anyhow::Result<Vec<Token>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let kind = match c {
+            '\n' => {
+                line += 1;
+                i += 1;
+                continue;
+            }
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            '[' => TokenKind::LBracket,
+            ']' => TokenKind::RBracket,
+            '|' => TokenKind::Pipe,
+            ',' => TokenKind::Comma,
+            ';' => {
+                let start = i + 1;
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                tokens.push(Token { kind: TokenKind::Comment(chars[start..i].iter().collect::<String>().trim().to_string()), line });
+                continue;
+            }
+            ':' => {
+                if chars.get(i + 1) == Some(&':') {
+                    i += 1;
+                    TokenKind::Any
+                } else {
+                    TokenKind::Colon
+                }
+            }
+            '"' => {
+                let start = i + 1;
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i] == '\n' {
+                        line += 1;
+                    }
+                    i += 1;
+                }
+                if i == chars.len() {
+                    bail!("Unterminated string starting on line {line}");
+                }
+                TokenKind::Str(chars[start..i].iter().collect())
+            }
+            '-' | '+' if chars.get(i + 1).is_some_and(|n| n.is_ascii_digit()) => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && is_number_char(chars[i], chars[i - 1]) {
+                    i += 1;
+                }
+                tokens.push(Token { kind: TokenKind::Number(chars[start..i].iter().collect()), line });
+                continue;
+            }
+            '+' | '-' | '*' | '/' => TokenKind::Operator(c),
+            c if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && is_number_char(chars[i], chars[i - 1]) {
+                    i += 1;
+                }
+                tokens.push(Token { kind: TokenKind::Number(chars[start..i].iter().collect()), line });
+                continue;
+            }
+            c if is_ident_start(c) => {
+                let start = i;
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+                let name: String = chars[start..i].iter().collect();
+                // A single colon directly after a name makes it a binding, while two colons would be a wildcard.
+                // Three colons is a binding followed by a wildcard
+                let colons = chars[i..].iter().take_while(|c| **c == ':').count();
+                if colons == 1 || colons == 3 {
+                    i += 1;
+                    tokens.push(Token { kind: TokenKind::Binding(name), line });
+                } else {
+                    tokens.push(Token { kind: TokenKind::Ident(name), line });
+                }
+                continue;
+            }
+            c => bail!("Unexpected character '{c}' on line {line}"),
+        };
+        tokens.push(Token { kind, line });
+        i += 1;
+    }
+
+    Ok(tokens)
+}
+
+fn is_number_char(c: char, previous: char) -> bool {
+    c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || (matches!(c, '-' | '+') && matches!(previous, 'e' | 'E'))
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
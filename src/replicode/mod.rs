@@ -0,0 +1,140 @@
+pub mod lexer;
+pub mod parser;
+pub mod writer;
+
+use std::fs;
+use std::path::Path;
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use crate::replicode::parser::{Item, Parser};
+use crate::runtime::knowledge::find_dangling_references;
+use crate::types::pattern::PatternItem;
+use crate::types::runtime::System;
+use crate::types::{EntityPatternValue, EntityVariableKey};
+
+pub use writer::write_system;
+
+pub fn parse(source: &str) -> anyhow::Result<Vec<Item>> {
+    let tokens = lexer::tokenize(source)?;
+    Parser::new(tokens).parse_items()
+}
+
+/// Add everything in the Replicode source to the system.
+/// Models and csts may be declared in any order, but every icst and imdl has to refer to one that exists
+pub fn parse_into_system(source: &str, system: &mut System) -> anyhow::Result<()> {
+    for item in parse(source)? {
+        match item {
+            Item::Model(model) => {
+                system.models.insert(model.model_id.clone(), *model);
+            }
+            Item::Cst(cst) => {
+                system.csts.insert(cst.cst_id.clone(), cst);
+            }
+            Item::Entity { entity_id, class } => system.create_entity(&entity_id, &class),
+            Item::Variable(mk_val) => match (&mk_val.entity_id, &mk_val.value, mk_val.assumption) {
+                (EntityPatternValue::EntityId(entity_id), PatternItem::Value(value), false) => {
+                    system.current_state.variables.insert(EntityVariableKey::new(entity_id, &mk_val.var_name), value.clone());
+                }
+                _ => bail!("Initial state can only contain mk.val with an entity and a value, got {mk_val}"),
+            },
+            Item::Goal(mut goal) => {
+                if goal.name.is_empty() {
+                    goal.name = format!("goal_{}", system.goals.len());
+                }
+                system.goals.push(goal);
+            }
+            Item::BabbleCommand(command) => system.babble_command.push(command),
+        }
+    }
+
+    let dangling = find_dangling_references(&system.models, &system.csts);
+    if !dangling.is_empty() {
+        bail!("Replicode contains dangling reference(s):\n{}", dangling.iter().map(|r| format!("  {r}")).join("\n"));
+    }
+
+    Ok(())
+}
+
+pub fn load_file(path: impl AsRef<Path>, system: &mut System) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read Replicode file {}", path.display()))?;
+    parse_into_system(&source, system)
+        .with_context(|| format!("Failed to load Replicode file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replicode::{parse_into_system, write_system};
+    use crate::runtime::seeds::BUILTIN_SEEDS;
+    use crate::types::runtime::System;
+    use crate::types::value::Value;
+    use crate::types::EntityVariableKey;
+
+    /// Writing a seed, parsing it and writing it again gives the same text
+    #[test]
+    fn builtin_seeds_round_trip() {
+        for (name, seed) in BUILTIN_SEEDS {
+            let mut system = System::new();
+            seed(&mut system);
+            let written = write_system(&system);
+
+            let mut parsed = System::new();
+            parse_into_system(&written, &mut parsed).unwrap_or_else(|e| panic!("Failed to parse seed {name}: {e:#}"));
+            assert_eq!(write_system(&parsed), written, "Seed {name} changed after parsing");
+        }
+    }
+
+    #[test]
+    fn constants_are_marked_explicitly() {
+        let mut system = System::new();
+        parse_into_system("(mk.val h speed (const 1.25))\n(mk.val h position 1.5)", &mut system).unwrap();
+        let speed = &system.current_state.variables[&EntityVariableKey::new("h", "speed")];
+        let position = &system.current_state.variables[&EntityVariableKey::new("h", "position")];
+        assert!(matches!(speed, Value::ConstantNumber(n) if *n == 1.25));
+        assert!(matches!(position, Value::Number(n) if *n == 1.5));
+        assert_eq!(speed.to_string(), "(const 1.25)");
+    }
+
+    #[test]
+    fn numbers_keep_full_precision() {
+        let mut system = System::new();
+        parse_into_system("(mk.val h position 0.123456789)\n(mk.val h speed (uncertain 1.000001 0.0625))", &mut system).unwrap();
+        let position = &system.current_state.variables[&EntityVariableKey::new("h", "position")];
+        let speed = &system.current_state.variables[&EntityVariableKey::new("h", "speed")];
+        assert_eq!(position.to_string(), "0.123456789");
+        assert_eq!(speed.to_string(), "(uncertain 1.000001 0.0625)");
+
+        let mut parsed = System::new();
+        parse_into_system(&write_system(&system), &mut parsed).unwrap();
+        assert_eq!(parsed.current_state.variables, system.current_state.variables);
+    }
+
+    /// Time bindings are written after the pattern of the fact, as in `(fact X T0: T1:)`
+    #[test]
+    fn timed_models_round_trip() {
+        let mut system = System::new();
+        parse_into_system("
+mdl_step:(mdl [] []
+  (fact (cmd step [r: ]) : :)
+  (fact (mk.val r: pos np:) : :)
+[]
+  np:(+ p: 1)
+[]
+  p:(- np: 1)
+); Success count: 5, Failure count: 0
+", &mut system).unwrap();
+        system.models.get_mut("mdl_step").unwrap().set_delay_window(80, 150);
+        let model = &system.models["mdl_step"];
+        assert_eq!(model.left.to_string(), "(fact (cmd step [r: ]) T0: T1:)");
+        assert_eq!(model.right.to_string(), "(fact (mk.val r: pos np:) T2: T3:)");
+
+        let mut parsed = System::new();
+        parse_into_system(&write_system(&system), &mut parsed).unwrap();
+        let parsed_model = &parsed.models["mdl_step"];
+        assert_eq!(parsed_model.left.time_range, model.left.time_range);
+        assert_eq!(parsed_model.right.time_range, model.right.time_range);
+        assert_eq!(parsed_model.to_string(), model.to_string());
+        assert_eq!(parsed_model.delay_window(), Some((80, 150)));
+    }
+}
@@ -0,0 +1,471 @@
+use anyhow::{anyhow, bail, Context};
+use crate::replicode::lexer::{Token, TokenKind};
+use crate::types::cst::{Cst, ICst};
+use crate::types::functions::Function;
+use crate::types::models::{IMdl, Mdl, MdlLeftValue, MdlRightValue};
+use crate::types::pattern::PatternItem;
+use crate::types::runtime::RuntimeCommand;
+use crate::types::value::Value;
+use crate::types::{Command, EntityDeclaration, EntityPatternValue, Fact, Goal, GoalPolicy, MkVal, TimePatternRange, TimePatternValue};
+
+/// Everything that can appear at the top level of a Replicode file
+#[derive(Clone, Debug)]
+pub enum Item {
+    Model(Box<Mdl>),
+    Cst(Cst),
+    Entity { entity_id: String, class: String },
+    Variable(MkVal),
+    /// The name is empty when the goal has no label
+    Goal(Goal),
+    BabbleCommand(RuntimeCommand),
+}
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Parser {
+        Parser { tokens, pos: 0 }
+    }
+
+    pub fn parse_items(&mut self) -> anyhow::Result<Vec<Item>> {
+        let mut items = Vec::new();
+        while self.peek().is_some() {
+            let line = self.line();
+            let item = self.parse_item().with_context(|| format!("Failed to parse item starting on line {line}"))?;
+            items.push(item);
+        }
+
+        Ok(items)
+    }
+
+    fn parse_item(&mut self) -> anyhow::Result<Item> {
+        if let Some(TokenKind::Binding(id)) = self.peek().cloned() {
+            self.pos += 1;
+            self.expect(TokenKind::LParen)?;
+            return match self.expect_ident()?.as_str() {
+                "mdl" => Ok(Item::Model(Box::new(self.parse_mdl_body(id)?))),
+                "cst" => Ok(Item::Cst(self.parse_cst_body(id)?)),
+                "goal" => Ok(Item::Goal(self.parse_goal_body(&id)?)),
+                other => bail!("Expected mdl, cst or goal after {id}:, got {other}"),
+            };
+        }
+
+        self.expect(TokenKind::LParen)?;
+        match self.expect_ident()?.as_str() {
+            head @ ("mk.val" | "mk.assumption") => {
+                let mk_val = self.parse_mk_val_body(head == "mk.assumption")?;
+                match (&mk_val.entity_id, mk_val.var_name.as_str(), &mk_val.value) {
+                    (EntityPatternValue::EntityId(entity_id), "essence", PatternItem::Value(Value::EntityId(class))) => {
+                        Ok(Item::Entity { entity_id: entity_id.clone(), class: class.clone() })
+                    }
+                    _ => Ok(Item::Variable(mk_val)),
+                }
+            }
+            "goal" => Ok(Item::Goal(self.parse_goal_body("")?)),
+            "cmd" => {
+                let name = self.expect_ident()?;
+                let entity_id = match self.parse_entity()? {
+                    EntityPatternValue::EntityId(id) => id,
+                    EntityPatternValue::Binding(b) => bail!("Babble command can't use binding {b}:"),
+                };
+                let mut params = Vec::new();
+                while self.peek() != Some(&TokenKind::RParen) {
+                    params.push(self.parse_value()?);
+                }
+                self.expect(TokenKind::RParen)?;
+                Ok(Item::BabbleCommand(RuntimeCommand::new(name, entity_id, params)))
+            }
+            other => bail!("Unexpected top level expression {other}"),
+        }
+    }
+
+    fn parse_mdl_body(&mut self, model_id: String) -> anyhow::Result<Mdl> {
+        self.expect_empty_list()?;
+        self.expect_empty_list()?;
+        let left = self.parse_fact(Parser::parse_mdl_left_value)?;
+        let right = self.parse_fact(Parser::parse_mdl_right_value)?;
+        let forward_computed = self.parse_guards()?;
+        let backward_computed = self.parse_guards()?;
+        self.expect(TokenKind::RParen)?;
+        let (success_count, failure_count) = self.parse_counts_comment();
+
+        Ok(Mdl {
+            model_id,
+            left,
+            right,
+            success_count,
+            failure_count,
+            forward_computed,
+            backward_computed,
+        })
+    }
+
+    /// Goals are written as `(goal facts... priority deadline policy)` where everything after the facts is optional.
+    /// The deadline is a time or `:` for none, the policy is `retry`, `drive` or `abandon <attempts>`
+    fn parse_goal_body(&mut self, name: &str) -> anyhow::Result<Goal> {
+        let mut facts = Vec::new();
+        while self.peek() == Some(&TokenKind::LParen) {
+            facts.push(self.parse_fact(Parser::parse_mk_val)?);
+        }
+        let mut goal = Goal::new(name, facts, 0);
+        if let Some(TokenKind::Number(priority)) = self.peek().cloned() {
+            self.pos += 1;
+            goal.priority = priority.parse().with_context(|| format!("Invalid goal priority {priority}"))?;
+        }
+        if matches!(self.peek(), Some(TokenKind::Number(_) | TokenKind::Colon)) {
+            goal.deadline = match self.parse_time_value()? {
+                TimePatternValue::Time(deadline) => Some(deadline),
+                _ => None,
+            };
+        }
+        if let Some(TokenKind::Ident(policy)) = self.peek().cloned() {
+            self.pos += 1;
+            goal.policy = match policy.as_str() {
+                "retry" => GoalPolicy::Retry,
+                "drive" => GoalPolicy::Drive,
+                "abandon" => match self.next()? {
+                    TokenKind::Number(n) => GoalPolicy::AbandonAfter(n.parse().with_context(|| format!("Invalid number of attempts {n}"))?),
+                    other => bail!("Expected number of attempts after abandon, got {other:?} on line {}", self.line()),
+                },
+                other => bail!("Unknown goal policy {other} on line {}", self.line()),
+            };
+        }
+        self.expect(TokenKind::RParen)?;
+
+        Ok(goal)
+    }
+
+    fn parse_cst_body(&mut self, cst_id: String) -> anyhow::Result<Cst> {
+        self.expect_empty_list()?;
+        self.expect_empty_list()?;
+        let mut cst = Cst::new(cst_id);
+        while self.peek() == Some(&TokenKind::LParen) {
+            let fact = self.parse_fact(Parser::parse_mk_val)?;
+            match (&fact.pattern.entity_id, fact.pattern.var_name.as_str(), &fact.pattern.value) {
+                (EntityPatternValue::Binding(binding), "essence", PatternItem::Value(Value::EntityId(class))) => {
+                    cst.entities.push(EntityDeclaration::new(binding, class));
+                }
+                _ => cst.facts.push(fact),
+            }
+        }
+        self.expect(TokenKind::Pipe)?;
+        self.expect_empty_list()?;
+        self.expect(TokenKind::Pipe)?;
+        self.expect_empty_list()?;
+        self.expect(TokenKind::RParen)?;
+        (cst.success_count, cst.failure_count) = self.parse_counts_comment();
+
+        Ok(cst)
+    }
+
+    /// Guards are written as `[] binding:function ...`, or as `|[]` when there are none
+    fn parse_guards(&mut self) -> anyhow::Result<Vec<(String, Function)>> {
+        if self.peek() == Some(&TokenKind::Pipe) {
+            self.pos += 1;
+            self.expect_empty_list()?;
+            return Ok(Vec::new());
+        }
+        self.expect_empty_list()?;
+        let mut guards = Vec::new();
+        while let Some(TokenKind::Binding(binding)) = self.peek().cloned() {
+            self.pos += 1;
+            guards.push((binding, self.parse_function()?));
+        }
+
+        Ok(guards)
+    }
+
+    /// Success and failure counts are only written in the comment after a model or cst.
+    /// Counts of a newly created model are used if the comment is missing
+    fn parse_counts_comment(&mut self) -> (usize, usize) {
+        let Some(Token { kind: TokenKind::Comment(comment), .. }) = self.tokens.get(self.pos) else {
+            return (1, 0);
+        };
+        let count_after = |label: &str| -> Option<usize> {
+            let start = comment.find(label)? + label.len();
+            comment[start..].split(',').next()?.trim().parse().ok()
+        };
+        let counts = (count_after("Success count:").unwrap_or(1), count_after("Failure count:").unwrap_or(0));
+        self.pos += 1;
+
+        counts
+    }
+
+    fn parse_fact<T: Clone>(&mut self, parse_pattern: fn(&mut Parser) -> anyhow::Result<T>) -> anyhow::Result<Fact<T>> {
+        self.expect(TokenKind::LParen)?;
+        let anti = if self.peek() == Some(&TokenKind::Pipe) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        };
+        let head = self.expect_ident()?;
+        if head != "fact" {
+            bail!("Expected fact, got {head} on line {}", self.line());
+        }
+        let pattern = parse_pattern(self)?;
+        let from = self.parse_time_value()?;
+        let to = self.parse_time_value()?;
+        self.expect(TokenKind::RParen)?;
+
+        Ok(Fact { pattern, time_range: TimePatternRange::new(from, to), anti })
+    }
+
+    fn parse_time_value(&mut self) -> anyhow::Result<TimePatternValue> {
+        match self.next()? {
+            TokenKind::Colon => Ok(TimePatternValue::Any),
+            TokenKind::Binding(b) => Ok(TimePatternValue::Binding(b)),
+            TokenKind::Number(n) => Ok(TimePatternValue::Time(n.parse().with_context(|| format!("Invalid time {n}"))?)),
+            other => bail!("Expected time value, got {other:?} on line {}", self.line()),
+        }
+    }
+
+    fn parse_mdl_left_value(&mut self) -> anyhow::Result<MdlLeftValue> {
+        self.expect(TokenKind::LParen)?;
+        match self.expect_ident()?.as_str() {
+            "cmd" => Ok(MdlLeftValue::Command(self.parse_command_body()?)),
+            "icst" => Ok(MdlLeftValue::ICst(self.parse_icst_body()?)),
+            "imdl" => Ok(MdlLeftValue::IMdl(self.parse_imdl_body()?)),
+            head @ ("mk.val" | "mk.assumption") => Ok(MdlLeftValue::MkVal(self.parse_mk_val_body(head == "mk.assumption")?)),
+            other => bail!("Unexpected model lhs {other} on line {}", self.line()),
+        }
+    }
+
+    fn parse_mdl_right_value(&mut self) -> anyhow::Result<MdlRightValue> {
+        self.expect(TokenKind::LParen)?;
+        match self.expect_ident()?.as_str() {
+            "imdl" => Ok(MdlRightValue::IMdl(self.parse_imdl_body()?)),
+            head @ ("mk.val" | "mk.assumption") => Ok(MdlRightValue::MkVal(self.parse_mk_val_body(head == "mk.assumption")?)),
+            other => bail!("Unexpected model rhs {other} on line {}", self.line()),
+        }
+    }
+
+    fn parse_mk_val(&mut self) -> anyhow::Result<MkVal> {
+        self.expect(TokenKind::LParen)?;
+        match self.expect_ident()?.as_str() {
+            head @ ("mk.val" | "mk.assumption") => self.parse_mk_val_body(head == "mk.assumption"),
+            other => bail!("Expected mk.val, got {other} on line {}", self.line()),
+        }
+    }
+
+    fn parse_mk_val_body(&mut self, assumption: bool) -> anyhow::Result<MkVal> {
+        let entity_id = self.parse_entity()?;
+        let var_name = self.expect_ident()?;
+        let value = self.parse_pattern_item()?;
+        self.expect(TokenKind::RParen)?;
+
+        Ok(MkVal { entity_id, var_name, value, assumption })
+    }
+
+    fn parse_command_body(&mut self) -> anyhow::Result<Command> {
+        let name = self.expect_ident()?;
+        self.expect(TokenKind::LBracket)?;
+        let entity_id = self.parse_entity()?;
+        let params = self.parse_pattern_items_until(TokenKind::RBracket)?;
+        self.expect(TokenKind::RParen)?;
+
+        Ok(Command { name, entity_id, params })
+    }
+
+    fn parse_icst_body(&mut self) -> anyhow::Result<ICst> {
+        let cst_id = self.expect_ident()?;
+        self.expect(TokenKind::LBracket)?;
+        let params = self.parse_pattern_items_until(TokenKind::RBracket)?;
+        self.expect(TokenKind::RParen)?;
+
+        Ok(ICst { cst_id, params })
+    }
+
+    fn parse_imdl_body(&mut self) -> anyhow::Result<IMdl> {
+        let model_id = self.expect_ident()?;
+        self.expect(TokenKind::LBracket)?;
+        let params = self.parse_pattern_items_until(TokenKind::RBracket)?;
+        self.expect(TokenKind::Pipe)?;
+        let mut imdl = IMdl::new(model_id, params);
+        while let Some(TokenKind::Binding(binding)) = self.peek().cloned() {
+            self.pos += 1;
+            imdl.fwd_guard_bindings.insert(binding, self.parse_value()?);
+            if self.peek() == Some(&TokenKind::Comma) {
+                self.pos += 1;
+            }
+        }
+        self.expect(TokenKind::RParen)?;
+
+        Ok(imdl)
+    }
+
+    fn parse_entity(&mut self) -> anyhow::Result<EntityPatternValue> {
+        match self.next()? {
+            TokenKind::Binding(b) => Ok(EntityPatternValue::Binding(b)),
+            TokenKind::Ident(id) | TokenKind::Number(id) => Ok(EntityPatternValue::EntityId(id)),
+            other => bail!("Expected entity, got {other:?} on line {}", self.line()),
+        }
+    }
+
+    fn parse_pattern_items_until(&mut self, end: TokenKind) -> anyhow::Result<Vec<PatternItem>> {
+        let mut items = Vec::new();
+        while self.peek() != Some(&end) {
+            items.push(self.parse_pattern_item()?);
+        }
+        self.expect(end)?;
+
+        Ok(items)
+    }
+
+    /// A list is read as a value when all of its items are values, since both are displayed the same way
+    fn parse_pattern_item(&mut self) -> anyhow::Result<PatternItem> {
+        match self.peek() {
+            Some(TokenKind::Any) => {
+                self.pos += 1;
+                Ok(PatternItem::Any)
+            }
+            Some(TokenKind::Binding(b)) => {
+                let b = b.clone();
+                self.pos += 1;
+                Ok(PatternItem::Binding(b))
+            }
+            Some(TokenKind::LBracket) => {
+                self.pos += 1;
+                let items = self.parse_pattern_items_until(TokenKind::RBracket)?;
+                Ok(pattern_list(items))
+            }
+            _ => Ok(PatternItem::Value(self.parse_value()?)),
+        }
+    }
+
+    fn parse_value(&mut self) -> anyhow::Result<Value> {
+        match self.next()? {
+            TokenKind::Number(n) => Ok(Value::Number(n.parse().with_context(|| format!("Invalid number {n}"))?)),
+            TokenKind::Str(s) => Ok(Value::String(s)),
+            TokenKind::Ident(id) => Ok(Value::EntityId(id)),
+            TokenKind::LBracket => {
+                let mut values = Vec::new();
+                while self.peek() != Some(&TokenKind::RBracket) {
+                    values.push(self.parse_value()?);
+                }
+                self.expect(TokenKind::RBracket)?;
+                Ok(Value::Vec(values))
+            }
+            TokenKind::LParen => {
+                let head = self.expect_ident()?;
+                let value = match head.as_str() {
+                    "uncertain" => Value::UncertainNumber(self.parse_f64()?, self.parse_f64()?),
+                    "const" => Value::ConstantNumber(self.parse_f64()?),
+                    _ => bail!("Expected uncertain value or constant, got {head} on line {}", self.line()),
+                };
+                self.expect(TokenKind::RParen)?;
+                Ok(value)
+            }
+            other => bail!("Expected value, got {other:?} on line {}", self.line()),
+        }
+    }
+
+    fn parse_function(&mut self) -> anyhow::Result<Function> {
+        self.peek();
+        match (self.tokens.get(self.pos).map(|t| &t.kind), self.tokens.get(self.pos + 1).map(|t| &t.kind)) {
+            (Some(TokenKind::LParen), Some(TokenKind::Operator(op))) => {
+                let op = *op;
+                self.pos += 2;
+                let f1 = Box::new(self.parse_function()?);
+                let f2 = Box::new(self.parse_function()?);
+                self.expect(TokenKind::RParen)?;
+                Ok(match op {
+                    '+' => Function::Add(f1, f2),
+                    '-' => Function::Sub(f1, f2),
+                    '*' => Function::Mul(f1, f2),
+                    _ => Function::Div(f1, f2),
+                })
+            }
+            (Some(TokenKind::LParen), Some(TokenKind::Ident(head))) if head == "toEntityId" || head == "toNumber" => {
+                let is_entity_id = head == "toEntityId";
+                self.pos += 2;
+                let f = Box::new(self.parse_function()?);
+                self.expect(TokenKind::RParen)?;
+                Ok(if is_entity_id { Function::ConvertToEntityId(f) } else { Function::ConvertToNumber(f) })
+            }
+            (Some(TokenKind::LBracket), _) => {
+                self.pos += 1;
+                let mut functions = Vec::new();
+                while self.peek() != Some(&TokenKind::RBracket) {
+                    functions.push(self.parse_function()?);
+                }
+                self.expect(TokenKind::RBracket)?;
+                // Lists of plain values are displayed the same way as a list function
+                if functions.iter().all(|f| matches!(f, Function::Value(_))) {
+                    let items = functions.into_iter().map(|f| match f {
+                        Function::Value(p) => p,
+                        _ => unreachable!(),
+                    }).collect();
+                    Ok(Function::Value(pattern_list(items)))
+                } else {
+                    Ok(Function::List(functions))
+                }
+            }
+            _ => Ok(Function::Value(self.parse_pattern_item()?)),
+        }
+    }
+
+    fn parse_f64(&mut self) -> anyhow::Result<f64> {
+        match self.next()? {
+            TokenKind::Number(n) => n.parse().with_context(|| format!("Invalid number {n}")),
+            other => bail!("Expected number, got {other:?} on line {}", self.line()),
+        }
+    }
+
+    fn expect_empty_list(&mut self) -> anyhow::Result<()> {
+        self.expect(TokenKind::LBracket)?;
+        self.expect(TokenKind::RBracket)
+    }
+
+    fn expect_ident(&mut self) -> anyhow::Result<String> {
+        match self.next()? {
+            TokenKind::Ident(id) => Ok(id),
+            other => bail!("Expected name, got {other:?} on line {}", self.line()),
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> anyhow::Result<()> {
+        let next = self.next()?;
+        if next != kind {
+            bail!("Expected {kind:?}, got {next:?} on line {}", self.line());
+        }
+
+        Ok(())
+    }
+
+    fn next(&mut self) -> anyhow::Result<TokenKind> {
+        let kind = self.peek().cloned().ok_or_else(|| anyhow!("Unexpected end of file"))?;
+        self.pos += 1;
+
+        Ok(kind)
+    }
+
+    /// Comments are skipped unless they are read explicitly right after a model or cst
+    fn peek(&mut self) -> Option<&TokenKind> {
+        while matches!(self.tokens.get(self.pos), Some(Token { kind: TokenKind::Comment(_), .. })) {
+            self.pos += 1;
+        }
+        self.tokens.get(self.pos).map(|t| &t.kind)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos.min(self.tokens.len().saturating_sub(1)))
+            .map(|t| t.line)
+            .unwrap_or(0)
+    }
+}
+
+fn pattern_list(items: Vec<PatternItem>) -> PatternItem {
+    if items.iter().all(|i| matches!(i, PatternItem::Value(_))) {
+        PatternItem::Value(Value::Vec(items.into_iter().map(|i| match i {
+            PatternItem::Value(v) => v,
+            _ => unreachable!(),
+        }).collect()))
+    } else {
+        PatternItem::Vec(items)
+    }
+}
@@ -0,0 +1,35 @@
+use std::fmt::Write;
+use itertools::Itertools;
+use crate::types::runtime::System;
+
+/// Write entities, initial state, csts, models, goals and babble commands of the system
+/// in the same syntax that the parser reads. Everything is sorted so the output is stable
+pub fn write_system(system: &System) -> String {
+    let mut output = String::new();
+
+    for (class, entities) in system.entities_in_classes.iter().sorted_by_key(|(class, _)| *class) {
+        for entity in entities {
+            writeln!(output, "(mk.val {entity} essence {class})").unwrap();
+        }
+    }
+    for (key, value) in system.current_state.variables.iter().sorted_by_key(|(key, _)| (&key.entity_id, &key.var_name)) {
+        writeln!(output, "(mk.val {} {} {value})", key.entity_id, key.var_name).unwrap();
+    }
+    writeln!(output).unwrap();
+
+    for (_, cst) in system.csts.iter().sorted_by_key(|(id, _)| *id) {
+        writeln!(output, "{cst}\n").unwrap();
+    }
+    for (_, model) in system.models.iter().sorted_by_key(|(id, _)| *id) {
+        writeln!(output, "{model}\n").unwrap();
+    }
+
+    for goal in &system.goals {
+        writeln!(output, "{goal}").unwrap();
+    }
+    for command in &system.babble_command {
+        writeln!(output, "{command}").unwrap();
+    }
+
+    output
+}
@@ -0,0 +1,720 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+use anyhow::Context;
+use itertools::Itertools;
+use crate::interfaces::{Environment, InputEvent};
+use crate::runtime::clock::Clock;
+use crate::runtime::goals::{all_goals_done, highest_priority_goal, select_goal, update_goals, GoalEvent};
+use crate::runtime::{forgetting, learning};
+use crate::runtime::runtime_main::{RunMode, RunOptions};
+use crate::runtime::simulation::backward::backward_chain;
+use crate::runtime::simulation::forward::{forward_chain, predict_all_changes_of_command, ForwardChainSearch};
+use crate::runtime::simulation::sim_debugger::try_to_find_expected_path;
+use crate::runtime::utils::{compute_assumptions, compute_instantiated_states};
+use crate::types::models::IMdl;
+use crate::types::runtime::{RuntimeCommand, System, SystemState};
+use crate::types::value::Value;
+use crate::types::{EntityVariableKey, Fact, Goal, MkVal};
+
+/// Why the agent stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Termination {
+    AllGoalsAchieved,
+    StepBudget,
+    WallClock,
+    /// The environment sent stop or ended its episode
+    EnvironmentStopped,
+    /// Stopped by the run mode, after the first planned command or after debugging the expected path
+    RunMode,
+}
+
+/// What happened in one step of the agent
+#[derive(Clone, Debug)]
+pub struct StepResult {
+    pub step: usize,
+    /// False if the environment sent nothing, then nothing else happened in the step
+    pub observed: bool,
+    pub executed_command: Option<RuntimeCommand>,
+    /// True if the command came from the babble commands instead of planning
+    pub babbled: bool,
+    /// Name of the goal the executed command was planned for
+    pub goal: Option<String>,
+    /// Goals that were achieved, missed, abandoned or found to have no plan in this step
+    pub goal_events: Vec<GoalEvent>,
+    /// Names of the models that were learned from the observation of this step
+    pub learned_models: Vec<String>,
+    /// Names of the models that were forgotten in this step, because they kept failing or were not used
+    pub forgotten_models: Vec<String>,
+    /// Changes predicted for the executed command, checked against the next observation
+    pub predictions: Vec<(EntityVariableKey, Value, IMdl)>,
+    /// True if planning was done in this step, false if the command came from the plan committed to in an earlier step
+    pub replanned: bool,
+    /// Set when the agent has stopped, `step` should not be called again
+    pub termination: Option<Termination>,
+}
+
+impl StepResult {
+    fn new(step: usize) -> StepResult {
+        StepResult {
+            step,
+            observed: false,
+            executed_command: None,
+            babbled: false,
+            goal: None,
+            goal_events: Vec::new(),
+            learned_models: Vec::new(),
+            forgotten_models: Vec::new(),
+            predictions: Vec::new(),
+            replanned: false,
+            termination: None,
+        }
+    }
+}
+
+/// Plan that is followed as long as the observations are as predicted and the goal stays the same
+struct CommittedPlan {
+    goal: String,
+    facts: Vec<Fact<MkVal>>,
+    priority: i32,
+    commands: Vec<RuntimeCommand>,
+}
+
+/// Runs AERA in an environment one step at a time. Each step observes the environment, learns from the observation
+/// and executes either the next babble command or the first command of a plan for the selected goal
+pub struct Agent<E: Environment> {
+    system: System,
+    environment: E,
+    options: RunOptions,
+    last_state: SystemState,
+    last_executed_command: Option<RuntimeCommand>,
+    last_was_babble_command: bool,
+    predicted_changes: Vec<(EntityVariableKey, Value, IMdl)>,
+    /// Search for each goal that is continued across steps when planning has a time budget
+    searches: HashMap<String, ForwardChainSearch>,
+    committed_plan: Option<CommittedPlan>,
+    steps: usize,
+    started: Instant,
+    clock: Clock,
+    termination: Option<Termination>,
+}
+
+impl<E: Environment> Agent<E> {
+    /// Seed the system, load the knowledge file if one is given and set up the environment
+    pub fn new(seed: impl FnOnce(&mut System), mut environment: E, options: RunOptions) -> anyhow::Result<Self> {
+        let mut system = System::new();
+        seed(&mut system);
+        if let Some(path) = &options.load_knowledge {
+            system.load_knowledge(path).context("Failed to load knowledge")?;
+            log::info!("Loaded {} models and {} csts from {}", system.models.len(), system.csts.len(), path.display());
+        }
+        environment.setup(&mut system).context("Failed to set up environment")?;
+
+        Ok(Self {
+            last_state: system.current_state.clone(),
+            clock: Clock::new(options.tick, options.fast),
+            system,
+            environment,
+            options,
+            last_executed_command: None,
+            last_was_babble_command: true,
+            predicted_changes: Vec::new(),
+            searches: HashMap::new(),
+            committed_plan: None,
+            steps: 0,
+            started: Instant::now(),
+            termination: None,
+        })
+    }
+
+    pub fn system(&self) -> &System {
+        &self.system
+    }
+
+    pub fn environment(&self) -> &E {
+        &self.environment
+    }
+
+    pub fn into_environment(self) -> E {
+        self.environment
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn termination(&self) -> Option<Termination> {
+        self.termination
+    }
+
+    /// Step until one of the termination conditions is met
+    pub fn run(&mut self) -> anyhow::Result<Termination> {
+        loop {
+            if let Some(termination) = self.step()?.termination {
+                return Ok(termination);
+            }
+            self.clock.wait_for_tick();
+        }
+    }
+
+    pub fn step(&mut self) -> anyhow::Result<StepResult> {
+        let mut result = StepResult::new(self.steps);
+        if let Some(termination) = self.termination {
+            result.termination = Some(termination);
+            return Ok(result);
+        }
+        if let Some(termination) = self.budget_exhausted() {
+            return Ok(self.terminate(result, termination));
+        }
+        self.steps += 1;
+        result.step = self.steps;
+
+        // Update state from interface
+        log::debug!("Waiting for variables");
+        match self.environment.observe(&mut self.system).context("Failed to observe environment")? {
+            InputEvent::Observed => {
+                result.observed = true;
+                self.clock.observed(self.environment.observation_time(), &mut self.system);
+            }
+            InputEvent::NoObservation => {
+                log::warn!("No observation received, skipping step");
+                return Ok(self.finish_step(result));
+            }
+            InputEvent::Stopped => {
+                log::info!("Environment stopped");
+                return Ok(self.terminate(result, Termination::EnvironmentStopped));
+            }
+            InputEvent::Restarted => {
+                log::info!("Environment restarted, dropping the plan and the searches");
+                self.forget_environment_state();
+                return Ok(self.finish_step(result));
+            }
+        }
+        let as_predicted = self.last_executed_command.is_some()
+            && !self.last_was_babble_command
+            && observation_as_predicted(&self.last_state, &self.system.current_state, &self.predicted_changes);
+        // Learn new csts and models, this needs to happen before instantiating csts so we can instantiate the new csts
+        let known_csts: HashSet<String> = self.system.csts.keys().cloned().collect();
+        if let Some(cmd) = &self.last_executed_command {
+            let known_models: HashSet<String> = self.system.models.keys().cloned().collect();
+            learning::extract_patterns(cmd, self.steps, &mut self.system, &self.last_state, &self.predicted_changes);
+            result.learned_models = self.system.models.keys().filter(|name| !known_models.contains(*name)).cloned().sorted().collect();
+        }
+        result.forgotten_models = forgetting::forget(&mut self.system, &self.options.forgetting);
+        if !result.learned_models.is_empty()
+            || !result.forgotten_models.is_empty()
+            || self.system.csts.len() != known_csts.len()
+            || self.system.csts.keys().any(|id| !known_csts.contains(id))
+        {
+            self.save_knowledge();
+        }
+        let system = &mut self.system;
+        system.current_state.instansiated_csts = compute_instantiated_states(system, &system.current_state);
+        system.current_state.variables.extend(compute_assumptions(system, &system.current_state));
+        system.current_state.instansiated_csts = compute_instantiated_states(system, &system.current_state);
+        self.last_state = system.current_state.clone();
+        self.update_searches(!result.learned_models.is_empty() || !result.forgotten_models.is_empty());
+        let system = &mut self.system;
+
+        log::debug!("Got variables");
+        print_all_variables(&system.current_state);
+
+        log::debug!("Instantiated composite states");
+        for state in system.current_state.instansiated_csts.values().flatten() {
+            log::debug!("{}", state.icst_for_cst());
+        }
+
+        result.goal_events = update_goals(system, !self.last_was_babble_command);
+        if !system.goals.is_empty() && all_goals_done(system) {
+            log::info!("All goals done");
+            if self.options.stop_when_goals_achieved {
+                self.report_goal_events(&result.goal_events)?;
+                return Ok(self.terminate(result, Termination::AllGoalsAchieved));
+            }
+        }
+
+        let mut path = if system.babble_command.is_empty() {
+            if self.options.mode == RunMode::DebugExpectedPath {
+                if let Some(fact) = highest_priority_goal(system).and_then(|i| system.goals[i].facts.first()) {
+                    try_to_find_expected_path(fact, system);
+                    return Ok(self.terminate(result, Termination::RunMode));
+                }
+            }
+            self.last_was_babble_command = false;
+
+            match self.committed_plan.take().filter(|plan| as_predicted && is_plan_still_wanted(plan, system)) {
+                Some(plan) => {
+                    log::debug!("Observation was as predicted, continuing the plan for goal {}", plan.goal);
+                    result.goal = Some(plan.goal.clone());
+                    let path = plan.commands.clone();
+                    self.committed_plan = Some(plan);
+                    path
+                }
+                None => {
+                    result.replanned = true;
+                    let deadline = self.options.planning_budget.map(|budget| Instant::now() + budget);
+                    let threads = self.options.planning_threads;
+                    let searches = &mut self.searches;
+                    searches.retain(|name, _| system.goals.iter().any(|goal| &goal.name == name && goal.is_active()));
+                    // Goals whose plan doesn't reach them yet, these are not committed to
+                    let mut partial_plans = HashSet::new();
+                    let planned = select_goal(system, &mut result.goal_events, |i, system| match deadline {
+                        Some(deadline) => continue_planning_for_goal(&system.goals[i], searches, deadline, threads, system)
+                            .map(|(path, complete)| {
+                                if !complete {
+                                    partial_plans.insert(i);
+                                }
+                                path
+                            }),
+                        None => Some(plan_for_goal(&system.goals[i].facts, threads, system)),
+                    });
+                    match planned {
+                        Some((i, path)) => {
+                            let goal = &system.goals[i];
+                            log::debug!("Pursuing goal {}", goal.name);
+                            result.goal = Some(goal.name.clone());
+                            if !partial_plans.contains(&i) {
+                                self.committed_plan = Some(CommittedPlan {
+                                    goal: goal.name.clone(),
+                                    facts: goal.facts.clone(),
+                                    priority: goal.priority,
+                                    commands: path.clone(),
+                                });
+                            }
+                            path
+                        }
+                        None => Vec::new(),
+                    }
+                }
+            }
+        } else {
+            let command = system.babble_command.remove(0);
+            self.last_was_babble_command = true;
+            self.committed_plan = None;
+
+            vec![command]
+        };
+        result.babbled = self.last_was_babble_command;
+        self.report_goal_events(&result.goal_events)?;
+
+        // Send command with interface
+        if !path.is_empty() {
+            self.environment.execute(&path[0], &mut self.system).context("Failed to execute command")?;
+            log::info!("Executed command {:?}", &path[0]);
+            self.predicted_changes = predict_all_changes_of_command(&path[0], false, &self.system);
+            result.predictions = self.predicted_changes.clone();
+            result.executed_command = Some(path[0].clone());
+            self.last_executed_command = Some(path.remove(0));
+            if let Some(plan) = &mut self.committed_plan {
+                plan.commands.remove(0);
+                if plan.commands.is_empty() {
+                    self.committed_plan = None;
+                }
+            }
+
+            if self.options.mode == RunMode::PlanOnce && !self.last_was_babble_command {
+                log::info!("Stopping after first planned command");
+                return Ok(self.terminate(result, Termination::RunMode));
+            }
+        }
+        else {
+            log::info!("No action found with forward chaining");
+            self.environment.execute(&RuntimeCommand {
+                name: "no_action".to_string(),
+                entity_id: "sys".to_string(),
+                params: Vec::new(),
+            }, &mut self.system).context("Failed to execute command")?;
+            self.predicted_changes.clear();
+            self.last_executed_command = None;
+            self.committed_plan = None;
+        }
+
+        if self.environment.is_episode_done(&self.system) {
+            log::info!("Episode done");
+            return Ok(self.terminate(result, Termination::EnvironmentStopped));
+        }
+
+        self.clock.advance(&mut self.system);
+        Ok(self.finish_step(result))
+    }
+
+    /// Keep the searches if the last step executed a planned command and the observed state is the predicted one,
+    /// otherwise the searches start over. They also start over when models have been learned or forgotten
+    fn update_searches(&mut self, models_changed: bool) {
+        let Some(command) = self.last_executed_command.as_ref().filter(|_| !self.last_was_babble_command && !models_changed) else {
+            self.searches.clear();
+            return;
+        };
+        let state = &self.system.current_state;
+        self.searches.retain(|_, search| search.advance(command, state));
+    }
+
+    /// Plans, searches and predictions are about the state the environment had, the last command is not learned from
+    fn forget_environment_state(&mut self) {
+        self.committed_plan = None;
+        self.searches.clear();
+        self.last_executed_command = None;
+        self.predicted_changes.clear();
+    }
+
+    /// Write what has been learned to the knowledge file, if there is one
+    fn save_knowledge(&self) {
+        let Some(path) = &self.options.save_knowledge else {
+            return;
+        };
+        match self.system.save_knowledge(path) {
+            Ok(()) => log::debug!("Written knowledge to {}", path.display()),
+            Err(e) => log::error!("Failed to save knowledge: {e:#}"),
+        }
+    }
+
+    fn report_goal_events(&mut self, events: &[GoalEvent]) -> anyhow::Result<()> {
+        for event in events {
+            self.environment.report_goal(event).context("Failed to report goal")?;
+        }
+        Ok(())
+    }
+
+    fn budget_exhausted(&self) -> Option<Termination> {
+        if self.options.max_steps.is_some_and(|max_steps| self.steps >= max_steps) {
+            log::info!("Stopping after {} steps", self.steps);
+            Some(Termination::StepBudget)
+        } else if self.options.max_duration.is_some_and(|max_duration| self.started.elapsed() >= max_duration) {
+            log::info!("Stopping after {:.1}s", self.started.elapsed().as_secs_f64());
+            Some(Termination::WallClock)
+        } else {
+            None
+        }
+    }
+
+    /// Check the budgets at the end of the step, so the caller knows right away when the last step was taken
+    fn finish_step(&mut self, mut result: StepResult) -> StepResult {
+        match self.budget_exhausted() {
+            Some(termination) => self.terminate(result, termination),
+            None => {
+                result.termination = None;
+                result
+            }
+        }
+    }
+
+    /// Stop the agent and save the knowledge, so the next run can continue from here
+    fn terminate(&mut self, mut result: StepResult, termination: Termination) -> StepResult {
+        self.termination = Some(termination);
+        result.termination = Some(termination);
+        self.save_knowledge();
+        result
+    }
+}
+
+/// Plan with backward chaining from the goal, followed by forward chaining towards it.
+/// Returns an empty plan if the goal can't be reached
+fn plan_for_goal(goal: &Vec<Fact<MkVal>>, threads: usize, system: &System) -> Vec<RuntimeCommand> {
+    // Perform backward chaining
+    let bwd_result = backward_chain(goal, system);
+    log::debug!("Results of backward chaining");
+    for (mdl, _) in &bwd_result {
+        log::debug!("{mdl}");
+    }
+
+    // Perform forward chaining
+    let path = forward_chain(goal, &bwd_result, system, threads);
+    log::debug!("Results of forward chaining");
+    log::debug!("Goal reachable: {}", !path.is_empty());
+    log::debug!("{}", path.iter().map(|cmd| cmd.to_string()).collect::<Vec<String>>().join(", "));
+
+    path
+}
+
+/// Continue planning for the goal until the deadline, with the search from earlier steps if the goal is unchanged.
+/// Returns the plan that reaches the goal if it has been found, otherwise the partial plan that gets closest to it,
+/// along with whether the plan reaches the goal. Returns None if there is no partial plan yet and the search is not finished
+fn continue_planning_for_goal(
+    goal: &Goal,
+    searches: &mut HashMap<String, ForwardChainSearch>,
+    deadline: Instant,
+    threads: usize,
+    system: &System,
+) -> Option<(Vec<RuntimeCommand>, bool)> {
+    let search = match searches.entry(goal.name.clone()) {
+        Entry::Occupied(entry) if entry.get().goal() == &goal.facts => entry.into_mut(),
+        entry => {
+            let search = ForwardChainSearch::new(&goal.facts, &backward_chain(&goal.facts, system), &system.current_state)
+                .with_threads(threads);
+            entry.insert_entry(search).into_mut()
+        }
+    };
+
+    let plan = search.run(system, deadline);
+    if plan.complete {
+        log::debug!(
+            "Goal reachable with probability {:.3}: {}",
+            plan.success_probability,
+            plan.commands.iter().map(|cmd| cmd.to_string()).join(", ")
+        );
+        return Some((plan.commands, true));
+    }
+    if plan.exhausted {
+        return Some((Vec::new(), true));
+    }
+    if plan.commands.is_empty() {
+        return None;
+    }
+    log::debug!(
+        "Partial plan ending {:.3} from the goal: {}",
+        plan.goal_distance,
+        plan.commands.iter().map(|cmd| cmd.to_string()).join(", ")
+    );
+
+    Some((plan.commands, false))
+}
+
+/// True if every predicted change happened and nothing changed that wasn't predicted
+fn observation_as_predicted(state_before: &SystemState, state: &SystemState, predicted_changes: &[(EntityVariableKey, Value, IMdl)]) -> bool {
+    for (key, predicted_value, model) in predicted_changes {
+        if state.variables.get(key).is_some_and(|value| value != predicted_value) {
+            log::debug!("Prediction {predicted_value} on {key:?} of model {} failed", model.model_id);
+            return false;
+        }
+    }
+    for (key, value) in &state.variables {
+        if Some(value) != state_before.variables.get(key) && !predicted_changes.iter().any(|(k, v, _)| key == k && value == v) {
+            log::debug!("Unexpected change to {value} on {key:?}");
+            return false;
+        }
+    }
+
+    true
+}
+
+/// True if the goal of the plan is still active and unchanged, and no goal with a higher priority has become active
+fn is_plan_still_wanted(plan: &CommittedPlan, system: &System) -> bool {
+    let Some(goal) = system.goals.iter().find(|goal| goal.name == plan.goal) else {
+        return false;
+    };
+
+    goal.is_active()
+        && goal.facts == plan.facts
+        && goal.priority == plan.priority
+        && highest_priority_goal(system).is_none_or(|i| system.goals[i].priority <= plan.priority)
+}
+
+fn print_all_variables(state: &SystemState) {
+    for (key, value) in &state.variables {
+        let entity = &key.entity_id;
+        let variable = &key.var_name;
+        log::debug!("(mk.val {entity} {variable} {value})");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use crate::interfaces::{Environment, InputEvent};
+    use crate::replicode::parse_into_system;
+    use crate::runtime::agent::{is_plan_still_wanted, observation_as_predicted, Agent};
+    use crate::runtime::goals::{GoalEvent, GoalEventKind};
+    use crate::runtime::RunOptions;
+    use crate::types::models::IMdl;
+    use crate::types::runtime::{RuntimeCommand, System, SystemState};
+    use crate::types::value::Value;
+    use crate::types::EntityVariableKey;
+
+    /// World kept in memory, where every command changes the variables as the given effect says
+    struct ScriptedEnvironment {
+        initial_variables: HashMap<EntityVariableKey, Value>,
+        variables: HashMap<EntityVariableKey, Value>,
+        effect: fn(&RuntimeCommand, &mut HashMap<EntityVariableKey, Value>),
+        executed: Vec<RuntimeCommand>,
+        observations: usize,
+        /// Observation at which the world starts over from its initial variables
+        restart_at: Option<usize>,
+    }
+
+    impl ScriptedEnvironment {
+        fn new(variables: &[(&str, &str, f64)], effect: fn(&RuntimeCommand, &mut HashMap<EntityVariableKey, Value>)) -> Self {
+            let variables: HashMap<_, _> = variables.iter().map(|(e, v, n)| (EntityVariableKey::new(e, v), Value::Number(*n))).collect();
+            Self {
+                initial_variables: variables.clone(),
+                variables,
+                effect,
+                executed: Vec::new(),
+                observations: 0,
+                restart_at: None,
+            }
+        }
+    }
+
+    impl Environment for ScriptedEnvironment {
+        fn observe(&mut self, system: &mut System) -> anyhow::Result<InputEvent> {
+            self.observations += 1;
+            if self.restart_at == Some(self.observations) {
+                self.variables = self.initial_variables.clone();
+                return Ok(InputEvent::Restarted);
+            }
+            system.current_state.variables = self.variables.clone();
+            Ok(InputEvent::Observed)
+        }
+
+        fn execute(&mut self, command: &RuntimeCommand, _system: &mut System) -> anyhow::Result<()> {
+            (self.effect)(command, &mut self.variables);
+            self.executed.push(command.clone());
+            Ok(())
+        }
+    }
+
+    fn options() -> RunOptions {
+        RunOptions { fast: true, planning_threads: 1, save_knowledge: None, ..RunOptions::default() }
+    }
+
+    /// A robot that moves one position forward with each step command, and doesn't know that yet
+    const ROBOT: &str = "
+(mk.val r essence robot)
+(cmd step r)
+(cmd step r)
+(cmd step r)
+(cmd step r)
+(cmd step r)
+goal_0:(goal (fact (mk.val r pos 8) : :) 1 : retry)
+";
+
+    fn step_forward(command: &RuntimeCommand, variables: &mut HashMap<EntityVariableKey, Value>) {
+        if command.name == "step" {
+            let pos = variables.get_mut(&EntityVariableKey::new(&command.entity_id, "pos")).unwrap();
+            *pos = Value::Number(pos.as_number() + 1.0);
+        }
+    }
+
+    /// The babble commands show what stepping does, and the learned model is then used to plan towards the goal
+    #[test]
+    fn babbles_then_learns_then_plans() {
+        let environment = ScriptedEnvironment::new(&[("r", "pos", 0.0)], step_forward);
+        let mut agent = Agent::new(|system| parse_into_system(ROBOT, system).unwrap(), environment, options()).unwrap();
+        let results = (0..9).map(|_| agent.step().unwrap()).collect::<Vec<_>>();
+        let step = RuntimeCommand::new("step".to_string(), "r".to_string(), Vec::new());
+
+        for result in &results[..5] {
+            assert!(result.babbled);
+            assert_eq!(result.executed_command.as_ref(), Some(&step));
+            assert_eq!(result.goal, None);
+        }
+        // The first babble command is learned from when its outcome is observed in the next step
+        assert!(results[0].learned_models.is_empty());
+        assert_eq!(results[1].learned_models, ["mdl_1", "mdl_req_2"]);
+
+        // Three more steps reach the goal, planned once and then followed while the predictions hold
+        for result in &results[5..8] {
+            assert!(!result.babbled);
+            assert_eq!(result.executed_command.as_ref(), Some(&step));
+            assert_eq!(result.goal.as_deref(), Some("goal_0"));
+            assert_eq!(result.predictions.len(), 1);
+        }
+        assert_eq!(results[5..8].iter().map(|r| r.replanned).collect::<Vec<_>>(), [true, false, false]);
+
+        assert_eq!(results[8].goal_events, [GoalEvent { goal: "goal_0".to_string(), kind: GoalEventKind::Achieved }]);
+        assert_eq!(results[8].executed_command, None);
+        assert_eq!(agent.system().models["mdl_1"].success_count, 8);
+        assert_eq!(agent.environment().variables[&EntityVariableKey::new("r", "pos")], Value::Number(8.0));
+    }
+
+    /// A restart in the middle of a plan drops the plan, and the command before the restart is not learned from
+    #[test]
+    fn restart_drops_the_plan() {
+        let mut environment = ScriptedEnvironment::new(&[("r", "pos", 0.0)], step_forward);
+        environment.restart_at = Some(7);
+        let mut agent = Agent::new(|system| parse_into_system(ROBOT, system).unwrap(), environment, options()).unwrap();
+        let results = (0..6).map(|_| agent.step().unwrap()).collect::<Vec<_>>();
+        assert!(results[5].replanned);
+        assert!(agent.committed_plan.is_some());
+
+        let restarted = agent.step().unwrap();
+        assert!(!restarted.observed);
+        assert_eq!(restarted.executed_command, None);
+        assert!(agent.committed_plan.is_none());
+        assert!(agent.searches.is_empty());
+
+        // Back at the start, the step executed before the restart is not taken to have failed
+        let result = agent.step().unwrap();
+        assert!(result.replanned);
+        assert!(result.learned_models.is_empty());
+        assert_eq!(agent.system().models["mdl_1"].failure_count, 0);
+    }
+
+    /// Agent that has planned for the goal with a planning budget, and executed the first two commands of the plan
+    fn agent_following_a_plan() -> Agent<ScriptedEnvironment> {
+        let environment = ScriptedEnvironment::new(&[("r", "pos", 0.0)], step_forward);
+        let options = RunOptions { planning_budget: Some(Duration::from_secs(10)), ..options() };
+        let mut agent = Agent::new(|system| parse_into_system(ROBOT, system).unwrap(), environment, options).unwrap();
+        let results = (0..7).map(|_| agent.step().unwrap()).collect::<Vec<_>>();
+        assert!(results[5].replanned && !results[6].replanned);
+        agent
+    }
+
+    /// With a planning budget the search for the goal is kept across steps, as long as the observations are as predicted
+    #[test]
+    fn search_is_kept_while_the_observations_are_as_predicted() {
+        // The search made in the step that planned was moved on to the observed state in the next step
+        let agent = agent_following_a_plan();
+        assert!(agent.searches.contains_key("goal_0"));
+
+        let mut agent = agent_following_a_plan();
+        agent.system.current_state.variables.insert(EntityVariableKey::new("r", "pos"), Value::Number(20.0));
+        agent.update_searches(false);
+        assert!(agent.searches.is_empty());
+
+        let mut agent = agent_following_a_plan();
+        agent.update_searches(true);
+        assert!(agent.searches.is_empty());
+    }
+
+    fn state(pos: f64, dir: f64) -> SystemState {
+        let mut state = SystemState::new();
+        state.variables.insert(EntityVariableKey::new("r", "pos"), Value::Number(pos));
+        state.variables.insert(EntityVariableKey::new("r", "dir"), Value::Number(dir));
+        state
+    }
+
+    #[test]
+    fn observation_is_as_predicted_when_only_the_predicted_changes_happen() {
+        let predicted = [(EntityVariableKey::new("r", "pos"), Value::Number(1.0), IMdl::new("mdl_1".to_string(), Vec::new()))];
+        assert!(observation_as_predicted(&state(0.0, 0.0), &state(1.0, 0.0), &predicted));
+        // The predicted change didn't happen
+        assert!(!observation_as_predicted(&state(0.0, 0.0), &state(0.0, 0.0), &predicted));
+        assert!(!observation_as_predicted(&state(0.0, 0.0), &state(2.0, 0.0), &predicted));
+        // Something changed that wasn't predicted
+        assert!(!observation_as_predicted(&state(0.0, 0.0), &state(1.0, 1.0), &predicted));
+        assert!(!observation_as_predicted(&state(0.0, 0.0), &state(0.0, 1.0), &[]));
+    }
+
+    /// The plan is followed while the observations are as predicted, and made again when they are not
+    #[test]
+    fn plan_is_dropped_when_the_observation_is_not_as_predicted() {
+        // The plan from position 5 to the goal at 8 was followed for two steps
+        let mut agent = agent_following_a_plan();
+        assert_eq!(agent.committed_plan.as_ref().unwrap().commands.len(), 1);
+
+        // Something pushed the robot back a position, so one more step doesn't reach the goal anymore
+        agent.environment.variables.insert(EntityVariableKey::new("r", "pos"), Value::Number(6.0));
+        let result = agent.step().unwrap();
+        assert!(result.replanned);
+        assert_eq!(agent.committed_plan.as_ref().unwrap().commands.len(), 1);
+        assert_eq!(agent.environment.variables[&EntityVariableKey::new("r", "pos")], Value::Number(7.0));
+    }
+
+    #[test]
+    fn plan_is_not_wanted_when_a_more_important_goal_becomes_active() {
+        let mut agent = agent_following_a_plan();
+        assert!(is_plan_still_wanted(agent.committed_plan.as_ref().unwrap(), &agent.system));
+
+        let mut goal = agent.system.goals[0].clone();
+        goal.name = "goal_1".to_string();
+        goal.priority = 2;
+        agent.system.goals.push(goal);
+        assert!(!is_plan_still_wanted(agent.committed_plan.as_ref().unwrap(), &agent.system));
+
+        agent.system.goals.pop();
+        agent.system.goals[0].priority = 2;
+        assert!(!is_plan_still_wanted(agent.committed_plan.as_ref().unwrap(), &agent.system));
+    }
+}
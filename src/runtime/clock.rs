@@ -0,0 +1,77 @@
+use std::time::{Duration, Instant};
+use crate::interfaces::ObservationTime;
+use crate::types::runtime::{System, SystemTime};
+
+/// Keeps the system time in milliseconds. It follows the time reported by the environment when there is one,
+/// otherwise it advances by a fixed tick each step
+pub struct Clock {
+    tick: Duration,
+    /// Don't wait for the next tick, for simulated environments
+    fast: bool,
+    first_timestamp: Option<u64>,
+    /// System time of the previous observation
+    last_observation: Option<u64>,
+    /// The time of the last observation came from the environment, so it also decides the time of the next one
+    follows_environment: bool,
+    next_tick: Instant,
+}
+
+impl Clock {
+    pub fn new(tick: Duration, fast: bool) -> Clock {
+        Clock {
+            tick,
+            fast,
+            first_timestamp: None,
+            last_observation: None,
+            follows_environment: false,
+            next_tick: Instant::now() + tick,
+        }
+    }
+
+    /// Sleep until the next tick is due, does nothing in fast mode
+    pub fn wait_for_tick(&mut self) {
+        if self.fast {
+            return;
+        }
+        let now = Instant::now();
+        if self.next_tick > now {
+            std::thread::sleep(self.next_tick - now);
+        }
+        // Don't try to catch up when a step took longer than a tick
+        self.next_tick = self.next_tick.max(now) + self.tick;
+    }
+
+    /// Set the system time to the time of the observation that was just made.
+    /// Timestamps are counted from the first one, so the system time starts at 0 like the time in seeds
+    pub fn observed(&mut self, time: Option<ObservationTime>, system: &mut System) {
+        let SystemTime::Exact(current_time) = system.current_state.time else {
+            panic!("System time should always be exact during runtime");
+        };
+        let observed_time = match time {
+            Some(ObservationTime::Timestamp(timestamp)) => {
+                let first_timestamp = *self.first_timestamp.get_or_insert(timestamp);
+                if timestamp < first_timestamp {
+                    log::warn!("Environment went back in time from {first_timestamp} to {timestamp}");
+                }
+                timestamp.saturating_sub(first_timestamp)
+            }
+            Some(ObservationTime::Span(span)) => self.last_observation.map_or(current_time, |last| last + span),
+            None => current_time,
+        };
+
+        self.follows_environment = time.is_some();
+        self.last_observation = Some(observed_time);
+        system.current_state.time = SystemTime::Exact(observed_time);
+    }
+
+    /// Move the system time to the end of the step, unless the next observation sets the time instead
+    pub fn advance(&mut self, system: &mut System) {
+        if self.follows_environment {
+            return;
+        }
+        let SystemTime::Exact(time) = system.current_state.time else {
+            panic!("System time should always be exact during runtime");
+        };
+        system.current_state.time = SystemTime::Exact(time + self.tick.as_millis() as u64);
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashSet;
+use itertools::Itertools;
+use crate::runtime::knowledge::{find_dangling_references, DanglingReference};
+use crate::types::models::{Mdl, MdlLeftValue};
+use crate::types::runtime::System;
+
+/// When models and composite states are forgotten
+#[derive(Clone, Debug)]
+pub struct ForgettingPolicy {
+    /// Models and csts with a confidence below this are forgotten, once they have failed `min_failures` times
+    pub confidence_floor: f64,
+    pub min_failures: usize,
+    /// Forget the least recently used learned casual models, with the models that depend on them, while there are more
+    /// models of any kind than this, or never if not set. Seeded models are never forgotten this way, so if there are more
+    /// of them than this the limit is not reached
+    pub max_models: Option<usize>,
+}
+
+impl Default for ForgettingPolicy {
+    fn default() -> Self {
+        Self {
+            confidence_floor: 0.3,
+            min_failures: 4,
+            max_models: None,
+        }
+    }
+}
+
+impl ForgettingPolicy {
+    fn is_failing(&self, failure_count: usize, confidence: f64) -> bool {
+        failure_count >= self.min_failures && confidence < self.confidence_floor
+    }
+}
+
+/// Forget the models and csts that keep failing, then the least recently used learned casual models while there are too many.
+/// Models that refer to a forgotten model or cst are forgotten with it, so no icst or imdl is left pointing to nothing.
+/// Returns the ids of the forgotten models
+pub fn forget(system: &mut System, policy: &ForgettingPolicy) -> Vec<String> {
+    // Models that have not been seen before, because they were just learned or seeded, count as used now
+    let now = system.current_state.time.end();
+    for model_id in system.models.keys() {
+        system.model_last_used.entry(model_id.clone()).or_insert(now);
+    }
+    system.model_last_used.retain(|model_id, _| system.models.contains_key(model_id));
+
+    let failing_models = system.models
+        .values()
+        .filter(|m| policy.is_failing(m.failure_count, m.confidence()))
+        .map(|m| m.model_id.clone())
+        .collect_vec();
+    let failing_csts = system.csts
+        .values()
+        .filter(|c| policy.is_failing(c.failure_count, c.confidence()))
+        .map(|c| c.cst_id.clone())
+        .collect_vec();
+    let mut forgotten = remove_knowledge(system, failing_models, failing_csts);
+
+    if let Some(max_models) = policy.max_models {
+        while system.models.len() > max_models {
+            // Of the models used equally long ago, the one with the lowest confidence is the least useful.
+            // Only learned models have a provenance, seeded ones are kept. Forgetting a casual model forgets the
+            // requirement models of it as well
+            let last_used = |m: &Mdl| system.model_last_used.get(&m.model_id).copied().unwrap_or(now);
+            let Some(least_recently_used) = system.models
+                .values()
+                .filter(|m| m.is_casual_model() && system.provenance.contains_key(&m.model_id))
+                .min_by(|m1, m2| last_used(m1).cmp(&last_used(m2)).then(m1.confidence().total_cmp(&m2.confidence())))
+                .map(|m| m.model_id.clone()) else {
+                break;
+            };
+            forgotten.extend(remove_knowledge(system, vec![least_recently_used], Vec::new()));
+        }
+    }
+
+    forgotten
+}
+
+/// Remove the models and csts along with every model that refers to a removed one. Csts that were used by
+/// removed models and are not used by any remaining model are removed too. Returns the ids of the removed models
+fn remove_knowledge(system: &mut System, model_ids: Vec<String>, cst_ids: Vec<String>) -> Vec<String> {
+    let mut removed_models = Vec::new();
+    let mut unused_csts: HashSet<String> = cst_ids.into_iter().collect();
+    for cst_id in &unused_csts {
+        system.csts.remove(cst_id);
+    }
+
+    let mut to_remove = model_ids;
+    while !to_remove.is_empty() {
+        for model_id in to_remove {
+            let Some(model) = system.models.remove(&model_id) else {
+                continue;
+            };
+            log::debug!("Forgot model {model_id} (confidence {:.2})", model.confidence());
+            if let MdlLeftValue::ICst(icst) = &model.left.pattern {
+                unused_csts.insert(icst.cst_id.clone());
+            }
+            system.model_last_used.remove(&model_id);
+            system.provenance.remove(&model_id);
+            removed_models.push(model_id);
+        }
+        to_remove = find_dangling_references(&system.models, &system.csts)
+            .into_iter()
+            .filter_map(|reference| match reference {
+                DanglingReference::Cst { model_id, .. } | DanglingReference::Model { model_id, .. } => Some(model_id),
+                DanglingReference::Guard { .. } | DanglingReference::Class { .. } => None,
+            })
+            .unique()
+            .collect();
+    }
+
+    unused_csts.retain(|cst_id| !system.models.values().any(|m| matches!(&m.left.pattern, MdlLeftValue::ICst(icst) if &icst.cst_id == cst_id)));
+    for cst_id in unused_csts {
+        if system.csts.remove(&cst_id).is_some() {
+            log::debug!("Forgot cst {cst_id}");
+        }
+        system.provenance.remove(&cst_id);
+        system.current_state.instansiated_csts.remove(&cst_id);
+    }
+
+    removed_models
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use crate::replicode::parse_into_system;
+    use crate::runtime::forgetting::{forget, ForgettingPolicy};
+    use crate::types::runtime::{Provenance, RuntimeCommand, System, SystemTime};
+
+    /// A seeded model and its requirement, and the same for a learned model
+    fn switch_models(name: &str, success_count: usize, failure_count: usize) -> String {
+        format!("
+{name}:(mdl [] []
+  (fact (cmd {name} [l: ]) : :)
+  (fact (mk.val l: on 1) : :)
+|[]
+|[]); Success count: {success_count}, Failure count: {failure_count}
+
+{name}_req:(mdl [] []
+  (fact (icst S_lamp [l: p:]) : :)
+  (fact (imdl {name} [l:] | ) : :)
+|[]
+|[]); Success count: {success_count}, Failure count: {failure_count}
+")
+    }
+
+    /// A seeded model and three learned ones, the learned ones last used at times 10, 20 and 20
+    fn lamp() -> System {
+        let mut source = "
+(mk.val l essence lamp)
+(mk.val l on 0)
+
+S_lamp:(cst [] []
+  (fact (mk.val l: essence lamp) : :)
+  (fact (mk.val l: on p:) : :)
+|[]
+|[]); Success count: 20, Failure count: 0
+".to_string();
+        source += &switch_models("M_seeded", 20, 0);
+        source += &switch_models("M_old", 20, 0);
+        source += &switch_models("M_reliable", 20, 0);
+        source += &switch_models("M_unreliable", 5, 2);
+        let mut system = System::new();
+        parse_into_system(&source, &mut system).unwrap();
+
+        let command = RuntimeCommand { name: "switch".to_string(), entity_id: "l".to_string(), params: Vec::new() };
+        for (model_id, last_used) in [("M_old", 10), ("M_reliable", 20), ("M_unreliable", 20)] {
+            system.model_last_used.insert(model_id.to_string(), last_used);
+            for id in [model_id.to_string(), format!("{model_id}_req")] {
+                system.provenance.insert(id, Provenance::new(1, &command));
+            }
+        }
+        system.current_state.time = SystemTime::Exact(30);
+        system
+    }
+
+    fn forget_beyond(system: &mut System, max_models: usize) -> Vec<String> {
+        forget(system, &ForgettingPolicy { max_models: Some(max_models), ..ForgettingPolicy::default() })
+    }
+
+    fn model_ids(system: &System) -> Vec<&str> {
+        system.models.keys().map(|id| id.as_str()).sorted().collect()
+    }
+
+    #[test]
+    fn least_recently_used_model_is_forgotten_with_its_requirement() {
+        let mut system = lamp();
+        assert!(forget_beyond(&mut system, 8).is_empty());
+
+        assert_eq!(forget_beyond(&mut system, 7).into_iter().sorted().collect_vec(), ["M_old", "M_old_req"]);
+        assert_eq!(system.models.len(), 6);
+        assert!(!system.model_last_used.contains_key("M_old"));
+        assert!(!system.provenance.contains_key("M_old_req"));
+        // The cst is still used by the other requirement models
+        assert!(system.csts.contains_key("S_lamp"));
+    }
+
+    #[test]
+    fn less_confident_model_is_forgotten_first_when_used_equally_long_ago() {
+        let mut system = lamp();
+        forget_beyond(&mut system, 4);
+        assert_eq!(model_ids(&system), ["M_reliable", "M_reliable_req", "M_seeded", "M_seeded_req"]);
+    }
+
+    #[test]
+    fn seeded_models_are_kept_beyond_the_limit() {
+        let mut system = lamp();
+        forget_beyond(&mut system, 1);
+        assert_eq!(model_ids(&system), ["M_seeded", "M_seeded_req"]);
+        assert!(system.csts.contains_key("S_lamp"));
+    }
+
+    #[test]
+    fn recently_used_model_is_kept() {
+        let mut system = lamp();
+        system.model_last_used.insert("M_old".to_string(), 25);
+        forget_beyond(&mut system, 6);
+        assert_eq!(model_ids(&system), ["M_old", "M_old_req", "M_reliable", "M_reliable_req", "M_seeded", "M_seeded_req"]);
+    }
+}
@@ -0,0 +1,325 @@
+use itertools::Itertools;
+use crate::runtime::pattern_matching::state_matches_facts;
+use crate::types::runtime::{RuntimeCommand, System};
+use crate::types::{GoalPolicy, GoalStatus};
+
+/// Change in the state of a goal, reported to the environment
+#[derive(Clone, Debug, PartialEq)]
+pub struct GoalEvent {
+    pub goal: String,
+    pub kind: GoalEventKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GoalEventKind {
+    Achieved,
+    Missed,
+    Abandoned,
+    /// Planning failed for the first time since the goal became active
+    NoPlan,
+}
+
+impl GoalEvent {
+    fn new(goal: &str, kind: GoalEventKind) -> GoalEvent {
+        GoalEvent { goal: goal.to_string(), kind }
+    }
+}
+
+/// Update the status of all goals after an observation and return the goals that were achieved or missed.
+/// Goals are only counted as achieved when `can_achieve` is set, so babbling doesn't achieve goals by accident.
+/// Drives become active again as soon as they no longer hold, other goals are missed once their deadline passes.
+/// Waiting goals become active once the goal they come after is achieved, so they are never achieved out of order
+pub fn update_goals(system: &mut System, can_achieve: bool) -> Vec<GoalEvent> {
+    let now = system.current_state.time.end();
+    let mut events = Vec::new();
+    for goal in &mut system.goals {
+        let holds = state_matches_facts(&system.current_state, &goal.facts);
+        match goal.status {
+            GoalStatus::Active if holds && can_achieve => {
+                log::info!("Goal {} achieved", goal.name);
+                goal.status = GoalStatus::Achieved;
+                events.push(GoalEvent::new(&goal.name, GoalEventKind::Achieved));
+            }
+            GoalStatus::Active | GoalStatus::Waiting => {
+                if let Some(deadline) = goal.deadline.filter(|deadline| *deadline < now) {
+                    log::warn!("Deadline {deadline} of goal {} has passed", goal.name);
+                    goal.status = GoalStatus::Missed;
+                    events.push(GoalEvent::new(&goal.name, GoalEventKind::Missed));
+                }
+            }
+            GoalStatus::Achieved if goal.policy == GoalPolicy::Drive && !holds => {
+                log::info!("Drive {} no longer holds, activating it again", goal.name);
+                goal.status = GoalStatus::Active;
+                goal.failed_attempts = 0;
+            }
+            _ => {}
+        }
+    }
+    events.extend(activate_waiting_goals(system));
+
+    events
+}
+
+/// Activate the waiting goals whose preceding goal has been achieved or no longer exists.
+/// If the preceding goal was missed or abandoned the waiting goal can never be reached and is abandoned as well
+fn activate_waiting_goals(system: &mut System) -> Vec<GoalEvent> {
+    let mut events = Vec::new();
+    for i in 0..system.goals.len() {
+        if system.goals[i].status != GoalStatus::Waiting {
+            continue;
+        }
+        let preceding_status = system.goals[i].after.as_ref()
+            .and_then(|after| system.goals.iter().find(|goal| &goal.name == after))
+            .map(|goal| goal.status);
+        let goal = &mut system.goals[i];
+        match preceding_status {
+            Some(GoalStatus::Waiting | GoalStatus::Active) => {}
+            Some(GoalStatus::Missed | GoalStatus::Abandoned) => {
+                log::warn!("Abandoning goal {}, the goal it comes after can't be achieved anymore", goal.name);
+                goal.status = GoalStatus::Abandoned;
+                events.push(GoalEvent::new(&goal.name, GoalEventKind::Abandoned));
+            }
+            Some(GoalStatus::Achieved) | None => {
+                log::info!("Goal {} is now active", goal.name);
+                goal.status = GoalStatus::Active;
+            }
+        }
+    }
+
+    events
+}
+
+/// True if no goal can become active anymore. Drives are never done
+pub fn all_goals_done(system: &System) -> bool {
+    system.goals.iter().all(|goal| goal.is_done() && goal.policy != GoalPolicy::Drive)
+}
+
+/// Index of the active goal with the highest priority, the first one wins ties
+pub fn highest_priority_goal(system: &System) -> Option<usize> {
+    system.goals.iter()
+        .enumerate()
+        .filter(|(_, goal)| goal.is_active())
+        .rev()
+        .max_by_key(|(_, goal)| goal.priority)
+        .map(|(i, _)| i)
+}
+
+/// Pick the active goal to pursue and the plan for it. Goals are tried from the highest priority down,
+/// within the same priority the goal with the cheapest plan, i.e. the fewest commands, is picked.
+/// Goals without a plan count as a failed attempt and are abandoned according to their policy.
+/// `plan` returns None if planning for the goal has not finished yet, which doesn't count as a failed attempt
+pub fn select_goal(
+    system: &mut System,
+    events: &mut Vec<GoalEvent>,
+    mut plan: impl FnMut(usize, &System) -> Option<Vec<RuntimeCommand>>,
+) -> Option<(usize, Vec<RuntimeCommand>)> {
+    let priorities = system.goals.iter()
+        .filter(|goal| goal.is_active())
+        .map(|goal| std::cmp::Reverse(goal.priority))
+        .sorted()
+        .dedup()
+        .map(|priority| priority.0)
+        .collect_vec();
+
+    for priority in priorities {
+        let candidates = system.goals.iter()
+            .enumerate()
+            .filter(|(_, goal)| goal.is_active() && goal.priority == priority)
+            .map(|(i, _)| i)
+            .collect_vec();
+
+        let mut best: Option<(usize, Vec<RuntimeCommand>)> = None;
+        for i in candidates {
+            let Some(path) = plan(i, system) else {
+                log::debug!("Still planning for goal {}", system.goals[i].name);
+                continue;
+            };
+            if path.is_empty() {
+                log::debug!("Goal {} not reachable", system.goals[i].name);
+                events.extend(record_failed_attempt(system, i));
+                continue;
+            }
+            log::debug!("Goal {} reachable with cost {}", system.goals[i].name, path.len());
+            if best.as_ref().is_none_or(|(_, best_path)| path.len() < best_path.len()) {
+                best = Some((i, path));
+            }
+        }
+        if best.is_some() {
+            return best;
+        }
+    }
+
+    None
+}
+
+fn record_failed_attempt(system: &mut System, goal_index: usize) -> Vec<GoalEvent> {
+    let goal = &mut system.goals[goal_index];
+    goal.failed_attempts += 1;
+    let mut events = Vec::new();
+    if goal.failed_attempts == 1 {
+        events.push(GoalEvent::new(&goal.name, GoalEventKind::NoPlan));
+    }
+    if let GoalPolicy::AbandonAfter(attempts) = goal.policy {
+        if goal.failed_attempts >= attempts {
+            log::warn!("Abandoning goal {} after {} failed attempts", goal.name, goal.failed_attempts);
+            goal.status = GoalStatus::Abandoned;
+            events.push(GoalEvent::new(&goal.name, GoalEventKind::Abandoned));
+        }
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::goals::{all_goals_done, select_goal, update_goals, GoalEvent, GoalEventKind};
+    use crate::types::pattern::PatternItem;
+    use crate::types::runtime::{RuntimeCommand, System, SystemTime};
+    use crate::types::value::Value;
+    use crate::types::{EntityPatternValue, EntityVariableKey, Fact, Goal, GoalPolicy, GoalStatus, MkVal, TimePatternRange};
+
+    fn pos_is(pos: f64) -> Vec<Fact<MkVal>> {
+        vec![Fact::new(MkVal {
+            entity_id: EntityPatternValue::EntityId("r".to_string()),
+            var_name: "pos".to_string(),
+            value: PatternItem::Value(Value::Number(pos)),
+            assumption: false,
+        }, TimePatternRange::wildcard())]
+    }
+
+    fn system(goals: Vec<Goal>, pos: f64) -> System {
+        let mut system = System::new();
+        system.goals = goals;
+        observe(&mut system, pos, 0);
+        system
+    }
+
+    fn observe(system: &mut System, pos: f64, time: u64) {
+        system.current_state.variables.insert(EntityVariableKey::new("r", "pos"), Value::Number(pos));
+        system.current_state.time = SystemTime::Exact(time);
+    }
+
+    fn statuses(system: &System) -> Vec<GoalStatus> {
+        system.goals.iter().map(|goal| goal.status).collect()
+    }
+
+    fn event(goal: &str, kind: GoalEventKind) -> GoalEvent {
+        GoalEvent { goal: goal.to_string(), kind }
+    }
+
+    fn step() -> RuntimeCommand {
+        RuntimeCommand { name: "step".to_string(), entity_id: "r".to_string(), params: Vec::new() }
+    }
+
+    #[test]
+    fn sequence_goals_wait_for_the_goal_before_them() {
+        let goals = Goal::sequence(vec![pos_is(1.0), pos_is(2.0), pos_is(3.0)]);
+        assert_eq!(goals.iter().map(|goal| goal.priority).collect::<Vec<_>>(), vec![3, 2, 1]);
+        assert_eq!(goals.iter().map(|goal| goal.after.as_deref()).collect::<Vec<_>>(), vec![None, Some("goal_0"), Some("goal_1")]);
+
+        let mut system = system(goals, 0.0);
+        assert_eq!(statuses(&system), vec![GoalStatus::Active, GoalStatus::Waiting, GoalStatus::Waiting]);
+
+        // The last goal holds first, but it can't be achieved before the goals it comes after
+        observe(&mut system, 3.0, 1);
+        assert!(update_goals(&mut system, true).is_empty());
+        assert_eq!(statuses(&system), vec![GoalStatus::Active, GoalStatus::Waiting, GoalStatus::Waiting]);
+
+        observe(&mut system, 1.0, 2);
+        assert_eq!(update_goals(&mut system, true), vec![event("goal_0", GoalEventKind::Achieved)]);
+        assert_eq!(statuses(&system), vec![GoalStatus::Achieved, GoalStatus::Active, GoalStatus::Waiting]);
+
+        observe(&mut system, 2.0, 3);
+        update_goals(&mut system, true);
+        observe(&mut system, 3.0, 4);
+        update_goals(&mut system, true);
+        assert_eq!(statuses(&system), vec![GoalStatus::Achieved; 3]);
+        assert!(all_goals_done(&system));
+    }
+
+    #[test]
+    fn goals_are_not_achieved_while_babbling() {
+        let mut system = system(vec![Goal::new("goal_0", pos_is(1.0), 1)], 1.0);
+        assert!(update_goals(&mut system, false).is_empty());
+        assert_eq!(statuses(&system), vec![GoalStatus::Active]);
+
+        assert_eq!(update_goals(&mut system, true), vec![event("goal_0", GoalEventKind::Achieved)]);
+    }
+
+    #[test]
+    fn goal_is_missed_after_its_deadline() {
+        let mut goals = Goal::sequence(vec![pos_is(1.0), pos_is(2.0)]);
+        goals[0].deadline = Some(10);
+        let mut system = system(goals, 0.0);
+
+        observe(&mut system, 0.0, 10);
+        assert!(update_goals(&mut system, true).is_empty());
+
+        // The goal after it can never become active, so it is abandoned
+        observe(&mut system, 0.0, 11);
+        assert_eq!(update_goals(&mut system, true), vec![
+            event("goal_0", GoalEventKind::Missed),
+            event("goal_1", GoalEventKind::Abandoned),
+        ]);
+        assert_eq!(statuses(&system), vec![GoalStatus::Missed, GoalStatus::Abandoned]);
+        assert!(all_goals_done(&system));
+    }
+
+    #[test]
+    fn goal_is_abandoned_after_failed_attempts() {
+        let mut goal = Goal::new("goal_0", pos_is(1.0), 1);
+        goal.policy = GoalPolicy::AbandonAfter(2);
+        let mut system = system(vec![goal], 0.0);
+
+        let mut events = Vec::new();
+        assert_eq!(select_goal(&mut system, &mut events, |_, _| Some(Vec::new())), None);
+        assert_eq!(events, vec![event("goal_0", GoalEventKind::NoPlan)]);
+        assert_eq!(statuses(&system), vec![GoalStatus::Active]);
+
+        // Planning that has not finished yet is not a failed attempt
+        events.clear();
+        assert_eq!(select_goal(&mut system, &mut events, |_, _| None), None);
+        assert!(events.is_empty());
+
+        assert_eq!(select_goal(&mut system, &mut events, |_, _| Some(Vec::new())), None);
+        assert_eq!(events, vec![event("goal_0", GoalEventKind::Abandoned)]);
+        assert_eq!(statuses(&system), vec![GoalStatus::Abandoned]);
+    }
+
+    #[test]
+    fn goal_with_the_cheapest_plan_is_picked_within_a_priority() {
+        let mut system = system(vec![
+            Goal::new("far", pos_is(3.0), 1),
+            Goal::new("near", pos_is(1.0), 1),
+            Goal::new("unimportant", pos_is(-1.0), 0),
+        ], 0.0);
+
+        let mut events = Vec::new();
+        let selected = select_goal(&mut system, &mut events, |i, _| Some(vec![step(); [3, 1, 1][i]]));
+        assert_eq!(selected, Some((1, vec![step()])));
+
+        // Lower priorities are only tried when no goal with a higher priority has a plan
+        let selected = select_goal(&mut system, &mut events, |i, _| Some(vec![step(); [0, 0, 1][i]]));
+        assert_eq!(selected, Some((2, vec![step()])));
+    }
+
+    #[test]
+    fn drive_becomes_active_again_when_it_no_longer_holds() {
+        let mut drive = Goal::new("goal_0", pos_is(0.0), 1);
+        drive.policy = GoalPolicy::Drive;
+        drive.failed_attempts = 3;
+        let mut system = system(vec![drive], 0.0);
+
+        update_goals(&mut system, true);
+        assert_eq!(statuses(&system), vec![GoalStatus::Achieved]);
+        assert!(!all_goals_done(&system));
+
+        observe(&mut system, 1.0, 1);
+        assert!(update_goals(&mut system, true).is_empty());
+        assert_eq!(statuses(&system), vec![GoalStatus::Active]);
+        assert_eq!(system.goals[0].failed_attempts, 0);
+
+        observe(&mut system, 0.0, 2);
+        assert_eq!(update_goals(&mut system, true), vec![event("goal_0", GoalEventKind::Achieved)]);
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::Path;
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use crate::types::cst::Cst;
+use crate::types::models::{Mdl, MdlLeftValue, MdlRightValue};
+use crate::types::runtime::{Provenance, System};
+use crate::types::{Goal, Time};
+
+/// Increase when the layout of the knowledge file changes
+pub const KNOWLEDGE_FORMAT_VERSION: u32 = 5;
+
+/// Everything the system has learned or was seeded with, in the form it is written to disk
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KnowledgeStore {
+    pub version: u32,
+    pub models: HashMap<String, Mdl>,
+    pub csts: HashMap<String, Cst>,
+    pub entities_in_classes: HashMap<String, Vec<String>>,
+    pub goals: Vec<Goal>,
+    pub provenance: HashMap<String, Provenance>,
+    /// Time each model was last used, so the least recently used models are still known after loading
+    pub model_last_used: HashMap<String, Time>,
+    /// Next number for generated ids, so ids of removed models and csts are not reused after loading
+    pub next_id: usize,
+}
+
+impl KnowledgeStore {
+    pub fn from_system(system: &System) -> KnowledgeStore {
+        KnowledgeStore {
+            version: KNOWLEDGE_FORMAT_VERSION,
+            models: system.models.clone(),
+            csts: system.csts.clone(),
+            entities_in_classes: system.entities_in_classes.clone(),
+            goals: system.goals.clone(),
+            provenance: system.provenance.clone(),
+            model_last_used: system.model_last_used.clone(),
+            next_id: system.next_id,
+        }
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> anyhow::Result<KnowledgeStore> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read knowledge file {}", path.display()))?;
+        let knowledge: KnowledgeStore = serde_json::from_str(&json)
+            .with_context(|| format!("Invalid knowledge file {}", path.display()))?;
+        if knowledge.version != KNOWLEDGE_FORMAT_VERSION {
+            bail!(
+                "Unsupported knowledge file version {} (expected {KNOWLEDGE_FORMAT_VERSION})",
+                knowledge.version
+            );
+        }
+
+        Ok(knowledge)
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json)
+            .with_context(|| format!("Failed to write knowledge file {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// Fails with a list of every reference to a cst, model, forward guard or entity class that is not in the store
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut dangling = find_dangling_references(&self.models, &self.csts);
+        dangling.extend(find_dangling_class_references(&self.csts, &self.entities_in_classes));
+        if !dangling.is_empty() {
+            bail!(
+                "Knowledge contains {} dangling reference(s):\n{}",
+                dangling.len(),
+                dangling.iter().map(|r| format!("  {r}")).join("\n")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DanglingReference {
+    Cst { model_id: String, cst_id: String },
+    Model { model_id: String, referenced_model_id: String },
+    /// An imdl gives a value to a forward guard binding that the model it refers to doesn't compute
+    Guard { model_id: String, referenced_model_id: String, binding: String },
+    /// An entity of the cst is declared with a class that no entity belongs to
+    Class { cst_id: String, class: String },
+}
+
+impl Display for DanglingReference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DanglingReference::Cst { model_id, cst_id } => {
+                write!(f, "{model_id} references missing cst {cst_id}")
+            }
+            DanglingReference::Model { model_id, referenced_model_id } => {
+                write!(f, "{model_id} references missing model {referenced_model_id}")
+            }
+            DanglingReference::Guard { model_id, referenced_model_id, binding } => {
+                write!(f, "{model_id} references missing forward guard {binding} of model {referenced_model_id}")
+            }
+            DanglingReference::Class { cst_id, class } => {
+                write!(f, "{cst_id} references missing entity class {class}")
+            }
+        }
+    }
+}
+
+/// Every icst and imdl of the models that points to a cst or model that doesn't exist, and every forward guard
+/// value of an imdl that the model it points to doesn't compute
+pub fn find_dangling_references(models: &HashMap<String, Mdl>, csts: &HashMap<String, Cst>) -> Vec<DanglingReference> {
+    let mut dangling = Vec::new();
+    for (model_id, model) in models.iter().sorted_by_key(|(id, _)| *id) {
+        let imdl = match &model.left.pattern {
+            MdlLeftValue::ICst(icst) if !csts.contains_key(&icst.cst_id) => {
+                dangling.push(DanglingReference::Cst { model_id: model_id.clone(), cst_id: icst.cst_id.clone() });
+                None
+            }
+            MdlLeftValue::IMdl(imdl) => Some(imdl),
+            _ => None,
+        };
+        let imdls = imdl.into_iter().chain(match &model.right.pattern {
+            MdlRightValue::IMdl(imdl) => Some(imdl),
+            _ => None,
+        });
+        for imdl in imdls {
+            let Some(referenced_model) = models.get(&imdl.model_id) else {
+                dangling.push(DanglingReference::Model { model_id: model_id.clone(), referenced_model_id: imdl.model_id.clone() });
+                continue;
+            };
+            for binding in imdl.fwd_guard_bindings.keys().sorted() {
+                if !referenced_model.forward_computed.iter().any(|(b, _)| b == binding) {
+                    dangling.push(DanglingReference::Guard {
+                        model_id: model_id.clone(),
+                        referenced_model_id: imdl.model_id.clone(),
+                        binding: binding.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    dangling
+}
+
+/// Every class that an entity of a cst is declared with, but that no known entity belongs to
+pub fn find_dangling_class_references(csts: &HashMap<String, Cst>, entities_in_classes: &HashMap<String, Vec<String>>) -> Vec<DanglingReference> {
+    csts.iter()
+        .sorted_by_key(|(id, _)| *id)
+        .flat_map(|(cst_id, cst)| cst.entities.iter().map(move |entity| (cst_id, &entity.class)))
+        .filter(|(_, class)| !entities_in_classes.contains_key(*class))
+        .map(|(cst_id, class)| DanglingReference::Class { cst_id: cst_id.clone(), class: class.clone() })
+        .dedup()
+        .collect()
+}
+
+impl System {
+    pub fn save_knowledge(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        KnowledgeStore::from_system(self).write(path)
+    }
+
+    /// Replace models, composite states, entities and goals with those stored in the file.
+    /// Nothing is changed if the file is invalid
+    pub fn load_knowledge(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let knowledge = KnowledgeStore::read(path)?;
+        knowledge.validate()?;
+        self.apply_knowledge(knowledge);
+
+        Ok(())
+    }
+
+    pub fn apply_knowledge(&mut self, knowledge: KnowledgeStore) {
+        self.models = knowledge.models;
+        self.csts = knowledge.csts;
+        self.entities_in_classes = knowledge.entities_in_classes;
+        self.goals = knowledge.goals;
+        self.provenance = knowledge.provenance;
+        self.model_last_used = knowledge.model_last_used;
+        self.next_id = knowledge.next_id;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::runtime::knowledge::{find_dangling_class_references, find_dangling_references, DanglingReference, KnowledgeStore};
+    use crate::runtime::seeds::hand_grab_sphere::setup_hand_grab_sphere_seed;
+    use crate::runtime::seeds::BUILTIN_SEEDS;
+    use crate::types::models::MdlRightValue;
+    use crate::types::runtime::{Provenance, RuntimeCommand, System};
+    use crate::types::value::Value;
+    use crate::types::EntityDeclaration;
+
+    fn seeded_system() -> System {
+        let mut system = System::new();
+        setup_hand_grab_sphere_seed(&mut system);
+        system
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("aera-knowledge-{}-{name}.json", std::process::id()))
+    }
+
+    fn as_json(system: &System) -> serde_json::Value {
+        serde_json::to_value(KnowledgeStore::from_system(system)).unwrap()
+    }
+
+    #[test]
+    fn saved_knowledge_loads_back_the_same() {
+        let mut system = seeded_system();
+        let command = RuntimeCommand { name: "move".to_string(), entity_id: "h".to_string(), params: vec![Value::Number(0.25)] };
+        system.provenance.insert("mdl_move".to_string(), Provenance::new(3, &command));
+        system.model_last_used.insert("mdl_move".to_string(), 1200);
+        system.model_last_used.insert("M_grab".to_string(), 400);
+        system.next_id = 17;
+        let path = temp_path("round-trip");
+        system.save_knowledge(&path).unwrap();
+
+        let mut loaded = System::new();
+        let result = loaded.load_knowledge(&path);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        assert_eq!(as_json(&loaded), as_json(&system));
+        assert_eq!(loaded.model_last_used["mdl_move"], 1200);
+        assert_eq!(loaded.next_id, 17);
+    }
+
+    #[test]
+    fn knowledge_with_dangling_references_is_not_loaded() {
+        let mut knowledge = KnowledgeStore::from_system(&seeded_system());
+        knowledge.csts.remove("S0");
+        let path = temp_path("dangling");
+        knowledge.write(&path).unwrap();
+
+        let mut system = seeded_system();
+        let before = as_json(&system);
+        let result = system.load_knowledge(&path);
+        std::fs::remove_file(&path).unwrap();
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("mdl_move_req references missing cst S0"), "{error}");
+        assert_eq!(as_json(&system), before);
+    }
+
+    #[test]
+    fn guard_and_class_references_are_checked() {
+        let mut system = seeded_system();
+        let MdlRightValue::IMdl(imdl) = &mut system.models.get_mut("mdl_move_req").unwrap().right.pattern else {
+            panic!("mdl_move_req should be a requirement model");
+        };
+        // mdl_move computes np with a forward guard, but not dp
+        imdl.fwd_guard_bindings.insert("np".to_string(), Value::Number(1.0));
+        imdl.fwd_guard_bindings.insert("dp".to_string(), Value::Number(1.0));
+        system.csts.get_mut("S0").unwrap().entities.push(EntityDeclaration::new("g", "ghost"));
+
+        assert_eq!(find_dangling_references(&system.models, &system.csts), vec![DanglingReference::Guard {
+            model_id: "mdl_move_req".to_string(),
+            referenced_model_id: "mdl_move".to_string(),
+            binding: "dp".to_string(),
+        }]);
+        assert_eq!(find_dangling_class_references(&system.csts, &system.entities_in_classes), vec![DanglingReference::Class {
+            cst_id: "S0".to_string(),
+            class: "ghost".to_string(),
+        }]);
+        assert!(KnowledgeStore::from_system(&system).validate().is_err());
+    }
+
+    #[test]
+    fn builtin_seeds_have_no_dangling_references() {
+        for (name, seed) in BUILTIN_SEEDS {
+            let mut system = System::new();
+            seed(&mut system);
+            KnowledgeStore::from_system(&system).validate().unwrap_or_else(|e| panic!("Seed {name} is invalid: {e:#}"));
+        }
+    }
+}
@@ -3,7 +3,7 @@ use crate::types::cst::{Cst, ICst};
 use crate::types::functions::Function;
 use crate::types::models::{BoundModel, IMdl, Mdl, MdlLeftValue, MdlRightValue};
 use crate::types::pattern::PatternItem;
-use crate::types::runtime::{RuntimeCommand, System, SystemState};
+use crate::types::runtime::{RuntimeCommand, System};
 use crate::types::value::Value;
 use crate::types::{
     Command, EntityDeclaration, EntityPatternValue, EntityVariableKey, Fact, MkVal,
@@ -13,18 +13,22 @@ use itertools::Itertools;
 use std::collections::{HashMap, HashSet};
 use std::vec;
 use crate::runtime::learning::cst::form_new_cst_for_state;
+use crate::runtime::learning::LearningContext;
 use crate::runtime::learning::model_comparison::compare_model_effects;
 use crate::runtime::utils::all_req_models;
 
+/// Largest relative error between the value computed by a guard and the observed value for the guard to be learned
+const GUARD_FIT_RELATIVE_TOLERANCE: f64 = 0.01;
+
 pub fn extract_patterns(
     changed_var: &EntityVariableKey,
     before: Option<&Value>,
     after: &Value,
-    executed_command: &RuntimeCommand,
+    context: &LearningContext,
     system: &mut System,
-    state_before: &SystemState,
 ) {
     // Before here is like target in AERA, and after is like consequent
+    let LearningContext { executed_command, state_before, .. } = context;
 
     let change = EntityVarChange {
         entity: changed_var.clone(),
@@ -35,11 +39,18 @@ pub fn extract_patterns(
     let cst = form_new_cst_for_state(&change, system, state_before, &mut pattern_value_map);
     let cmd_model =
         form_new_command_model(executed_command, &change, &mut pattern_value_map, system);
+    // The change was seen in the first observation after the command, which is the only delay we know so far
+    let delay = system.current_state.time.end().saturating_sub(state_before.time.end());
+    system.models.get_mut(&cmd_model).unwrap().observe_delay(delay);
     let req_model = form_new_req_model(
         &system.csts[&cst].clone(),
         &system.models[&cmd_model].clone(),
         system,
     );
+    let provenance = context.provenance();
+    for id in [&cst, &cmd_model, &req_model] {
+        system.provenance.insert(id.clone(), provenance.clone());
+    }
     println!("{}", system.csts[&cst]);
     println!("{}", system.models[&cmd_model]);
     println!("{}", system.models[&req_model]);
@@ -153,7 +164,6 @@ fn create_delta_guards(pattern_value_map: &PatternValueMap, change: &EntityVarCh
         return (Vec::new(), Vec::new());
     }
     // TODO: Get real bindings for premise and cmd, P{X} and CMD{X} are only used if the values are unique
-    // TODO: Could also combine them and look through any combination?
     let premise_values: HashMap<_, _> = pattern_value_map
         .iter()
         .filter(|(v, b)| {
@@ -182,6 +192,9 @@ fn create_delta_guards(pattern_value_map: &PatternValueMap, change: &EntityVarCh
     (fwd_guards, bwd_guards)
 }
 
+/// Find the simplest function of premise and command values that computes the consequent value, and the backward
+/// function that computes one of its inputs from the consequent. Values are bound per component, so guards on
+/// vectors are found component by component
 fn create_delta_guard(
     value: &Value,
     binding: &str,
@@ -189,68 +202,134 @@ fn create_delta_guard(
     premise_values: &HashMap<&ValueKey, &String>,
     cmd_values: &HashMap<&ValueKey, &String>,
 ) -> Option<((String, Function), (String, Function))> {
-    let addition = premise_values
+    let premise_bindings = premise_values.values().map(|b| b.to_string()).sorted().collect_vec();
+    let cmd_bindings = cmd_values.values().map(|b| b.to_string()).sorted().collect_vec();
+    let input_values: HashMap<String, Value> = premise_values
         .iter()
-        .filter_map(|(ValueKey(pv), pb)| {
-            cmd_values
-                .iter()
-                .filter_map(|(ValueKey(cv), cb)| {
-                    if (*pv).clone() + (*cv).clone() == *value {
-                        Some((pb, cb))
-                    } else {
-                        None
-                    }
-                })
-                .next()
+        .chain(cmd_values.iter())
+        .map(|(ValueKey(v), b)| (b.to_string(), v.clone()))
+        .collect();
+
+    // Functions with a constant always fit the observed change, so they are only used when no other function does
+    let fwd_function = guard_function_candidates(&premise_bindings, &cmd_bindings)
+        .into_iter()
+        .filter(|f| f.evaluate(&input_values).is_some_and(|res| fits_observed_value(&res, value)))
+        .chain(constant_guard_function_candidates(value, premise_equivalent, &cmd_bindings, &input_values))
+        .find(|f| invert_guard_function(f, binding, premise_equivalent, &cmd_bindings).is_some())?;
+    let (input_binding, bwd_function) = invert_guard_function(&fwd_function, binding, premise_equivalent, &cmd_bindings)?;
+    log::debug!("Created guard {binding} = {fwd_function}, {input_binding} = {bwd_function}");
+
+    Some(((binding.to_string(), fwd_function), (input_binding, bwd_function)))
+}
+
+/// Functions without constants that can compute a consequent value, ordered from simplest to most complex.
+/// Every function uses the command, since the change happened because of it. Functions of the same size are
+/// ordered by operator, so the common P + CMD comes first
+fn guard_function_candidates(premise_bindings: &[String], cmd_bindings: &[String]) -> Vec<Function> {
+    let binding = |b: &String| Function::Value(PatternItem::Binding(b.clone()));
+    let operands = premise_bindings.iter().chain(cmd_bindings).collect_vec();
+
+    // Add and Mul are commutative, so they are only created for one order of each pair of operands
+    let commutative_pairs = |function: fn(Box<Function>, Box<Function>) -> Function| operands.iter()
+        .tuple_combinations()
+        .map(move |(a, b)| function(Box::new(binding(a)), Box::new(binding(b))))
+        .collect_vec();
+    let ordered_pairs = |function: fn(Box<Function>, Box<Function>) -> Function| operands.iter()
+        .permutations(2)
+        .map(move |p| function(Box::new(binding(p[0])), Box::new(binding(p[1]))))
+        .collect_vec();
+    let pairs = [commutative_pairs(Function::Add), ordered_pairs(Function::Sub), commutative_pairs(Function::Mul), ordered_pairs(Function::Div)]
+        .concat();
+    // Affine combinations of a premise value and a scaled value, i.e. (P + (CMD1 * CMD2)) for velocity times duration
+    let affine = premise_bindings.iter()
+        .flat_map(|p| {
+            pairs.iter()
+                .filter(|f| matches!(f, Function::Mul(_, _) | Function::Div(_, _)) && !f.binding_params().contains(p))
+                .flat_map(move |scaled| [
+                    Function::Add(Box::new(binding(p)), Box::new(scaled.clone())),
+                    Function::Sub(Box::new(binding(p)), Box::new(scaled.clone())),
+                ])
         })
-        .next();
-    // Case 1: P{X} + CMD{X}
-    if let Some((premise_binding, cmd_binding)) = addition {
-        let fwd_function = Function::Add(
-            Box::new(Function::Value(PatternItem::Binding(
-                premise_binding.to_string(),
-            ))),
-            Box::new(Function::Value(PatternItem::Binding(
-                cmd_binding.to_string(),
-            ))),
-        );
-        let bwd_function = Function::Sub(
-            Box::new(Function::Value(PatternItem::Binding(
-                binding.to_string(),
-            ))),
-            Box::new(Function::Value(PatternItem::Binding(
-                premise_binding.to_string(),
-            ))),
-        );
+        .collect_vec();
 
-        Some(((binding.to_string(), fwd_function), (cmd_binding.to_string(), bwd_function.clone())))
-    } else if let Some((pb, pv)) = premise_equivalent {
-        if value.can_do_numeric_op(pv) {
-            let diff = value.clone() - pv.clone();
-            let fwd_function = Function::Add(
-                Box::new(Function::Value(PatternItem::Binding(
-                    pb.to_string(),
-                ))),
-                Box::new(Function::Value(PatternItem::Value(
-                    diff.clone()
-                ))),
-            );
-            let bwd_function = Function::Sub(
-                Box::new(Function::Value(PatternItem::Binding(
-                    binding.to_string(),
-                ))),
-                Box::new(Function::Value(PatternItem::Value(
-                    diff
-                ))),
-            );
-
-            Some(((binding.to_string(), fwd_function), (pb.to_string(), bwd_function.clone())))
+    pairs.into_iter()
+        .chain(affine)
+        .filter(|f| f.binding_params().iter().any(|b| cmd_bindings.contains(b)))
+        .sorted_by_key(function_size)
+        .collect()
+}
+
+/// True if the value computed by a guard matches the observed value up to a small relative error. This is much
+/// stricter than comparing the values, so a single observation doesn't fit functions that are only roughly right
+fn fits_observed_value(computed: &Value, observed: &Value) -> bool {
+    match (computed, observed) {
+        (Value::Vec(v1), Value::Vec(v2)) => {
+            v1.len() == v2.len() && v1.iter().zip(v2).all(|(c, o)| fits_observed_value(c, o))
         }
-        else {
-            None
+        _ => match (computed.mean_and_std(), observed.mean_and_std()) {
+            (Some((c, _)), Some((o, _))) => (c - o).abs() <= GUARD_FIT_RELATIVE_TOLERANCE * c.abs().max(o.abs()) + f64::EPSILON,
+            _ => computed == observed,
+        },
+    }
+}
+
+/// Functions with a constant fitted to the observed value, the offset from the premise (P + constant) or, for values
+/// that did not exist before, a scaled command value (CMD * constant)
+fn constant_guard_function_candidates(
+    value: &Value,
+    premise_equivalent: &Option<(String, Value)>,
+    cmd_bindings: &[String],
+    input_values: &HashMap<String, Value>,
+) -> Vec<Function> {
+    let constant_function = |b: &String, constant: Value, function: fn(Box<Function>, Box<Function>) -> Function| {
+        function(
+            Box::new(Function::Value(PatternItem::Binding(b.clone()))),
+            Box::new(Function::Value(PatternItem::Value(constant))),
+        )
+    };
+
+    match premise_equivalent {
+        Some((pb, pv)) if value.can_do_numeric_op(pv) => {
+            vec![constant_function(pb, value.clone() - pv.clone(), Function::Add)]
         }
-    } else {
-        None
+        Some(_) => Vec::new(),
+        None => cmd_bindings
+            .iter()
+            .filter_map(|cb| {
+                let cv = &input_values[cb];
+                let is_zero = matches!(cv, Value::Number(n) | Value::UncertainNumber(n, _) if *n == 0.0);
+                (value.can_do_numeric_op(cv) && !is_zero).then(|| constant_function(cb, value.clone() / cv.clone(), Function::Mul))
+            })
+            .collect(),
+    }
+}
+
+/// Create the backward guard for a forward guard function, by solving it for the command binding it uses or
+/// otherwise for the premise. Returns None if the binding appears more than once, since it cannot be solved for then
+fn invert_guard_function(
+    function: &Function,
+    binding: &str,
+    premise_equivalent: &Option<(String, Value)>,
+    cmd_bindings: &[String],
+) -> Option<(String, Function)> {
+    let function_bindings = function.binding_params();
+    let input_binding = function_bindings.iter()
+        .find(|b| cmd_bindings.contains(b))
+        .or_else(|| premise_equivalent.as_ref().map(|(pb, _)| pb).filter(|pb| function_bindings.contains(pb)))
+        .or(function_bindings.first())?;
+    let result = Function::Value(PatternItem::Binding(binding.to_string()));
+
+    Some((input_binding.clone(), function.solve_for(input_binding, result)?))
+}
+
+fn function_size(function: &Function) -> usize {
+    match function {
+        Function::Value(_) => 1,
+        Function::Add(f1, f2) | Function::Sub(f1, f2) | Function::Mul(f1, f2) | Function::Div(f1, f2) => {
+            1 + function_size(f1) + function_size(f2)
+        }
+        Function::List(l) => 1 + l.iter().map(function_size).sum::<usize>(),
+        Function::ConvertToEntityId(f) | Function::ConvertToNumber(f) => 1 + function_size(f),
     }
 }
 
@@ -310,7 +389,7 @@ fn check_and_merge_with_existing_model(cst: &Cst, req_model: &Mdl, casual_model:
     let Some((new_cst, new_req_model, new_casual_model)) = req_models.into_iter()
         .filter_map(|req_model2| {
             match &req_model2.right.pattern {
-                MdlRightValue::IMdl(imdl) if req_model2.model_id != req_model.model_id => {
+                MdlRightValue::IMdl(imdl) if req_model2.model_id != req_model.model_id && !req_model2.right.anti => {
                     let casual_model = system.models.get(&imdl.model_id)?.clone();
                     Some((req_model2, casual_model))
                 }
@@ -335,7 +414,14 @@ fn check_and_merge_with_existing_model(cst: &Cst, req_model: &Mdl, casual_model:
     system.csts.insert(new_cst_id.clone(), new_cst);
     
     // Since the model would have succeeded if it had already been merged, promote it
-    system.models.get_mut(&new_casual_model.model_id).unwrap().promote();
+    let existing_casual_model = system.models.get_mut(&new_casual_model.model_id).unwrap();
+    existing_casual_model.merge_guard_constants(casual_model);
+    existing_casual_model.promote();
+    system.model_last_used.insert(new_casual_model.model_id.clone(), system.current_state.time.end());
+    if let Some((earliest, latest)) = casual_model.delay_window() {
+        existing_casual_model.observe_delay(earliest);
+        existing_casual_model.observe_delay(latest);
+    }
 
     // Update confidence to same as the causal model (to keep cst and model in sync)
     if let Some(cst_ref) = system.csts.get_mut(&new_cst_id) {
@@ -359,6 +445,15 @@ fn check_and_merge_with_existing_model(cst: &Cst, req_model: &Mdl, casual_model:
     println!("{new_cst}");
     println!("{new_req_model_ref}");
 
+    // The existing models and cst keep track of what was merged into them, the merged ones are removed
+    for (existing_id, merged_id) in [
+        (&new_casual_model.model_id, &casual_model.model_id),
+        (&new_req_model.model_id, &req_model.model_id),
+        (&new_cst_id, &cst.cst_id),
+    ] {
+        system.record_merge(existing_id, merged_id);
+    }
+
     system.csts.remove(&cst.cst_id);
     system.models.remove(&req_model.model_id);
     system.models.remove(&casual_model.model_id);
@@ -387,3 +482,68 @@ fn find_equivalent_binding_in_other(value_key: &ValueKey, value_of_key: &Value,
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::runtime::learning::ctpx::{create_delta_guard, invert_guard_function};
+    use crate::runtime::learning::utils::ValueKey;
+    use crate::types::functions::Function;
+    use crate::types::pattern::PatternItem;
+    use crate::types::value::Value;
+
+    /// The forward guard for C and the backward guard, as the binding it computes and its function
+    fn guard(value: f64, premise: &[(&str, f64)], cmd: &[(&str, f64)], premise_equivalent: Option<(&str, f64)>) -> Option<(String, String, String)> {
+        let keys = |values: &[(&str, f64)]| values.iter().map(|(b, v)| (ValueKey(Value::Number(*v)), b.to_string())).collect::<Vec<_>>();
+        let (premise, cmd) = (keys(premise), keys(cmd));
+        let premise_values = premise.iter().map(|(v, b)| (v, b)).collect::<HashMap<_, _>>();
+        let cmd_values = cmd.iter().map(|(v, b)| (v, b)).collect::<HashMap<_, _>>();
+        let premise_equivalent = premise_equivalent.map(|(b, v)| (b.to_string(), Value::Number(v)));
+
+        let ((fwd_binding, fwd), (bwd_binding, bwd)) = create_delta_guard(&Value::Number(value), "C", &premise_equivalent, &premise_values, &cmd_values)?;
+        assert_eq!(fwd_binding, "C");
+        Some((fwd.to_string(), bwd_binding, bwd.to_string()))
+    }
+
+    fn expected(fwd: &str, bwd_binding: &str, bwd: &str) -> Option<(String, String, String)> {
+        Some((fwd.to_string(), bwd_binding.to_string(), bwd.to_string()))
+    }
+
+    #[test]
+    fn guard_for_each_operator() {
+        let premise = [("P0", 3.0)];
+        let cmd = [("CMD0", 2.0)];
+        assert_eq!(guard(5.0, &premise, &cmd, None), expected("(+ P0: CMD0:)", "CMD0", "(- C: P0:)"));
+        // The command is on the right of the operator
+        assert_eq!(guard(1.0, &premise, &cmd, None), expected("(- P0: CMD0:)", "CMD0", "(- P0: C:)"));
+        assert_eq!(guard(6.0, &premise, &cmd, None), expected("(* P0: CMD0:)", "CMD0", "(/ C: P0:)"));
+        assert_eq!(guard(1.5, &premise, &cmd, None), expected("(/ P0: CMD0:)", "CMD0", "(/ P0: C:)"));
+        // The command is on the left of the operator
+        assert_eq!(guard(-1.0, &premise, &cmd, None), expected("(- CMD0: P0:)", "CMD0", "(+ C: P0:)"));
+        assert_eq!(guard(2.0 / 3.0, &premise, &cmd, None), expected("(/ CMD0: P0:)", "CMD0", "(* C: P0:)"));
+    }
+
+    #[test]
+    fn affine_guard_is_solved_for_the_command() {
+        // Position plus velocity times duration
+        let guard = guard(7.0, &[("P0", 1.0)], &[("CMD0", 2.0), ("CMD1", 3.0)], None);
+        assert_eq!(guard, expected("(+ P0: (* CMD0: CMD1:))", "CMD0", "(/ (- C: P0:) CMD1:)"));
+    }
+
+    #[test]
+    fn constant_guards_are_used_when_nothing_else_fits() {
+        // Offset from the value before the change, solved for the premise since the command is not in the guard
+        assert_eq!(guard(10.0, &[("P0", 3.0)], &[("CMD0", 2.0)], Some(("P0", 3.0))), expected("(+ P0: 7)", "P0", "(- C: 7)"));
+        // New values are scaled from the command
+        assert_eq!(guard(8.0, &[], &[("CMD0", 2.0)], None), expected("(* CMD0: 4)", "CMD0", "(/ C: 4)"));
+        // A zero command can't be scaled to the value
+        assert_eq!(guard(8.0, &[], &[("CMD0", 0.0)], None), None);
+    }
+
+    #[test]
+    fn guard_with_the_binding_twice_is_not_inverted() {
+        let cmd = Function::Value(PatternItem::Binding("CMD0".to_string()));
+        let twice = Function::Add(Box::new(cmd.clone()), Box::new(cmd));
+        assert_eq!(invert_guard_function(&twice, "C", &None, &["CMD0".to_string()]), None);
+    }
+}
@@ -5,27 +5,50 @@ mod ptpx;
 mod cst;
 mod full_causal_model_comparison;
 
+use std::collections::{HashMap, HashSet};
+use crate::runtime::pattern_matching::{compare_imdls, pattern_item_matches_value_with_bindings, PatternMatchResult};
+use crate::runtime::utils::all_req_models;
 use crate::types::EntityVariableKey;
 use crate::types::models::{IMdl, MdlLeftValue, MdlRightValue};
-use crate::types::runtime::{RuntimeCommand, System, SystemState};
+use crate::types::runtime::{Provenance, RuntimeCommand, System, SystemState};
 use crate::types::value::Value;
 
-pub fn extract_patterns(executed_command: &RuntimeCommand, system: &mut System, state_before: &SystemState, predicted_changes: &Vec<(EntityVariableKey, Value, IMdl)>) {
+/// The step that is learned from
+struct LearningContext<'a> {
+    executed_command: &'a RuntimeCommand,
+    /// Number of the step the command was executed in
+    step: usize,
+    state_before: &'a SystemState,
+}
+
+impl LearningContext<'_> {
+    /// Where knowledge learned in this step came from
+    fn provenance(&self) -> Provenance {
+        Provenance::new(self.step, self.executed_command)
+    }
+}
+
+pub fn extract_patterns(executed_command: &RuntimeCommand, step: usize, system: &mut System, state_before: &SystemState, predicted_changes: &Vec<(EntityVariableKey, Value, IMdl)>) {
     log::debug!("Checking for patterns");
+    let context = LearningContext { executed_command, step, state_before };
     for (key, value) in &system.current_state.variables.clone() {
         let old_value = state_before.variables.get(key);
         // Fact changed after executing command, and we have no model that predicted it
         if Some(value) != old_value && !predicted_changes.iter().any(|(k, v, _)| key == k && value == v) {
             log::debug!("Found change on {key:?}");
-            ctpx::extract_patterns(key, old_value, value, executed_command, system, state_before);
+            ctpx::extract_patterns(key, old_value, value, &context, system);
         }
     }
 
+    // Models that already got an anti-requirement model this step, so a model failing to change multiple variables
+    // does not create an anti-requirement model for each of them
+    let mut models_with_new_anti_req = HashSet::new();
     for (key, predicted_value, model) in predicted_changes {
         let Some(current_value) = system.current_state.variables.get(key).cloned() else {
             continue
         };
         log::debug!("Expected change {predicted_value} on {key:?} using model {}", model.model_id);
+        system.model_last_used.insert(model.model_id.clone(), system.current_state.time.end());
         let cst_id_of_model = system.models.values().find_map(|m| match (&m.left.pattern, &m.right.pattern) {
             (MdlLeftValue::ICst(icst), MdlRightValue::IMdl(imdl)) if imdl.model_id == model.model_id => Some(icst.cst_id.clone()),
             _ => None
@@ -42,18 +65,185 @@ pub fn extract_patterns(executed_command: &RuntimeCommand, system: &mut System,
                 let cst_ref = system.csts.get_mut(cst_id).unwrap();
                 cst_ref.demote();
             }
-            // ptpx::extract_patterns(key, old_value, &current_value, predicted_value, model, executed_command, system, state_before);
+
+            // Learn in which context the model fails, so it is not used there again
+            if let Some(old_value) = state_before.variables.get(key) {
+                if models_with_new_anti_req.insert(model.model_id.clone()) {
+                    ptpx::extract_patterns(key, old_value, &current_value, predicted_value, model, &context, system);
+                }
+            }
         }
         else {
             log::debug!("Expected change did happen, model {} promoted", model.model_id);
+            let delay = system.current_state.time.end().saturating_sub(state_before.time.end());
+            let observed_bindings = observed_guard_bindings(model, &current_value, system);
             let model_ref = system.models.get_mut(&model.model_id).unwrap();
+            model_ref.observe_guard_constants(&observed_bindings);
             model_ref.promote();
+            model_ref.observe_delay(delay);
 
             if let Some(cst_id) = &cst_id_of_model {
                 log::debug!("Cst {} also promoted", cst_id);
                 let cst_ref = system.csts.get_mut(cst_id).unwrap();
                 cst_ref.promote();
             }
+
+            // The model worked in a context where an anti-requirement model says it fails
+            for anti_req_model_id in matching_anti_req_models(model, state_before, system) {
+                log::debug!("Anti-requirement model {anti_req_model_id} demoted");
+                system.models.get_mut(&anti_req_model_id).unwrap().demote();
+            }
+        }
+    }
+}
+
+/// Anti-requirement models of the model that were instantiated in the state
+fn matching_anti_req_models(model: &IMdl, state: &SystemState, system: &System) -> Vec<String> {
+    all_req_models(system)
+        .into_iter()
+        .filter(|m| m.right.anti && m.right.pattern.as_imdl().model_id == model.model_id)
+        .filter(|m| {
+            m.try_instantiate_with_icst(state)
+                .iter()
+                .any(|bm| compare_imdls(&m.right.pattern.as_filled_in_imdl(&bm.bindings), model, true, true))
+        })
+        .map(|m| m.model_id)
+        .collect()
+}
+
+/// Bindings of the model that made a prediction, with the results of its guards replaced by the observed values
+fn observed_guard_bindings(model: &IMdl, observed_value: &Value, system: &System) -> HashMap<String, Value> {
+    let bound_model = model.instantiate(&HashMap::new(), system);
+    let mut bindings = bound_model.bindings;
+    if let MdlRightValue::MkVal(mk_val) = &bound_model.model.right.pattern {
+        if let PatternMatchResult::True(observed) = pattern_item_matches_value_with_bindings(&mk_val.value, observed_value, HashMap::new()) {
+            let guard_params = bound_model.model.fwd_guard_params();
+            bindings.extend(observed.into_iter().filter(|(b, _)| guard_params.contains(&b.as_str())));
+        }
+    }
+    bindings
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replicode::parse_into_system;
+    use crate::runtime::learning::extract_patterns;
+    use crate::runtime::simulation::forward::predict_all_changes_of_command;
+    use crate::runtime::utils::{compute_instantiated_states, is_established_anti_req_model, ANTI_REQUIREMENT_MIN_FAILURES};
+    use crate::types::models::Mdl;
+    use crate::types::runtime::{RuntimeCommand, System, SystemTime};
+    use crate::types::value::Value;
+    use crate::types::EntityVariableKey;
+
+    /// A lamp that is switched on by a reliable model, except that it has no power
+    const LAMP: &str = "
+(mk.val l essence lamp)
+(mk.val l on 0)
+(mk.val l power 0)
+
+S_lamp:(cst [] []
+  (fact (mk.val l: essence lamp) : :)
+  (fact (mk.val l: on p:) : :)
+|[]
+|[]); Success count: 20, Failure count: 0
+
+M_switch:(mdl [] []
+  (fact (cmd switch [l: ]) : :)
+  (fact (mk.val l: on 1) : :)
+|[]
+|[]); Success count: 20, Failure count: 0
+
+M_switch_req:(mdl [] []
+  (fact (icst S_lamp [l: p:]) : :)
+  (fact (imdl M_switch [l:] | ) : :)
+|[]
+|[]); Success count: 20, Failure count: 0
+";
+
+    fn lamp() -> System {
+        let mut system = System::new();
+        parse_into_system(LAMP, &mut system).unwrap();
+        system.current_state.instansiated_csts = compute_instantiated_states(&system, &system.current_state);
+        system
+    }
+
+    fn switch() -> RuntimeCommand {
+        RuntimeCommand { name: "switch".to_string(), entity_id: "l".to_string(), params: Vec::new() }
+    }
+
+    fn switch_is_predicted(system: &System) -> bool {
+        predict_all_changes_of_command(&switch(), false, system)
+            .iter()
+            .any(|(key, value, _)| key == &EntityVariableKey::new("l", "on") && value == &Value::Number(1.0))
+    }
+
+    /// Switch the lamp and observe that it stays off
+    fn fail_to_switch(system: &mut System, step: usize) {
+        let predicted_changes = predict_all_changes_of_command(&switch(), false, system);
+        let state_before = system.current_state.clone();
+        system.current_state.time = SystemTime::Exact(system.current_state.time.end() + 100);
+        extract_patterns(&switch(), step, system, &state_before, &predicted_changes);
+        system.current_state.instansiated_csts = compute_instantiated_states(system, &system.current_state);
+    }
+
+    fn all_anti_req_models(system: &System) -> Vec<&Mdl> {
+        system.models.values().filter(|m| m.right.anti).collect()
+    }
+
+    #[test]
+    fn anti_requirement_model_blocks_the_model_after_repeated_failures() {
+        let mut system = lamp();
+        assert!(switch_is_predicted(&system));
+
+        fail_to_switch(&mut system, 1);
+        let anti_req_models = all_anti_req_models(&system);
+        assert_eq!(anti_req_models.len(), 1);
+        assert_eq!(anti_req_models[0].right.pattern.as_imdl().model_id, "M_switch");
+        assert!(!is_established_anti_req_model(anti_req_models[0]));
+        // One failure could be noise, the model is still used
+        assert!(switch_is_predicted(&system));
+
+        for step in 2..=ANTI_REQUIREMENT_MIN_FAILURES {
+            fail_to_switch(&mut system, step);
         }
+        // Failures in the same context add up in the same anti-requirement model
+        let anti_req_models = all_anti_req_models(&system);
+        assert_eq!(anti_req_models.len(), 1);
+        assert_eq!(anti_req_models[0].success_count, ANTI_REQUIREMENT_MIN_FAILURES);
+        assert!(is_established_anti_req_model(anti_req_models[0]));
+        assert!(!switch_is_predicted(&system));
+    }
+
+    #[test]
+    fn prediction_marks_the_model_as_used() {
+        let mut system = lamp();
+        fail_to_switch(&mut system, 1);
+        assert_eq!(system.model_last_used["M_switch"], 100);
+        fail_to_switch(&mut system, 2);
+        assert_eq!(system.model_last_used["M_switch"], 200);
+    }
+
+    /// Switch a lamp that has no models yet on at the given time, without predicting anything
+    fn learn_to_switch(system: &mut System, time: u64) {
+        system.current_state.variables.insert(EntityVariableKey::new("l", "on"), Value::Number(0.0));
+        system.current_state.time = SystemTime::Exact(time);
+        let state_before = system.current_state.clone();
+        system.current_state.variables.insert(EntityVariableKey::new("l", "on"), Value::Number(1.0));
+        extract_patterns(&switch(), 1, system, &state_before, &Vec::new());
+    }
+
+    #[test]
+    fn merging_a_learned_model_marks_the_existing_model_as_used() {
+        let mut system = System::new();
+        parse_into_system("(mk.val l essence lamp)\n(mk.val l on 0)\n", &mut system).unwrap();
+        learn_to_switch(&mut system, 100);
+        let casual_models = system.models.values().filter(|m| m.is_casual_model()).map(|m| m.model_id.clone()).collect::<Vec<_>>();
+        assert_eq!(casual_models.len(), 1);
+        let model_count = system.models.len();
+
+        // The same change learned again is merged into the existing model
+        learn_to_switch(&mut system, 200);
+        assert_eq!(system.models.len(), model_count);
+        assert_eq!(system.model_last_used[&casual_models[0]], 200);
     }
-}
\ No newline at end of file
+}
@@ -10,6 +10,9 @@ use std::collections::{HashMap, HashSet};
 use crate::runtime::learning::full_causal_model_comparison::compare_casual_models_with_bindings;
 use crate::types::value::Value;
 
+/// Largest difference between guard constants that are the same, when their observations have no spread yet
+const GUARD_CONSTANT_MIN_TOLERANCE: f64 = 0.1;
+
 pub fn compare_model_effects(
     cst: &Cst,
     req_model: &Mdl,
@@ -247,25 +250,31 @@ fn compare_casual_models(model1: &Mdl, model2: &Mdl) -> bool {
         }
         _ => false,
     };
-    // Assumes same order of functions
-    let forward_equal = model1.forward_computed.len() == model2.forward_computed.len()
-        && model1
-            .forward_computed
+    // Assumes same order of functions. Guards on time bindings are left out, models with different delays are still the same model
+    let value_guards = |model: &'_ Mdl, guards: &'_ Vec<(String, Function)>| guards
+        .iter()
+        .filter(|(b, _)| !model.is_time_binding(b))
+        .map(|(_, f)| f.clone())
+        .collect_vec();
+    let (forward1, forward2) = (value_guards(model1, &model1.forward_computed), value_guards(model2, &model2.forward_computed));
+    let forward_equal = forward1.len() == forward2.len()
+        && forward1
             .iter()
-            .zip(&model2.forward_computed)
-            .all(|((_, f1), (_, f2))| compare_functions(f1, f2));
-    let backward_equal = model1.backward_computed.len() == model2.backward_computed.len()
-        && model2
-            .backward_computed
+            .zip(&forward2)
+            .all(|(f1, f2)| compare_functions(f1, f2));
+    let (backward1, backward2) = (value_guards(model1, &model1.backward_computed), value_guards(model2, &model2.backward_computed));
+    let backward_equal = backward1.len() == backward2.len()
+        && backward1
             .iter()
-            .zip(&model2.backward_computed)
-            .all(|((_, f1), (_, f2))| compare_functions(f1, f2));
+            .zip(&backward2)
+            .all(|(f1, f2)| compare_functions(f1, f2));
 
     lhs_equal && rhs_equal && forward_equal && backward_equal
 }
 
 fn compare_functions(function1: &Function, function2: &Function) -> bool {
     match (function1, function2) {
+        (Function::Value(PatternItem::Value(v1)), Function::Value(PatternItem::Value(v2))) => compare_guard_constants(v1, v2),
         (Function::Value(p1), Function::Value(p2)) => compare_pattern_items(p1, p2, true),
         (Function::Add(v1, v2), Function::Add(v21, v22)) => {
             compare_functions(v1, v21) && compare_functions(v2, v22)
@@ -290,6 +299,22 @@ fn compare_functions(function1: &Function, function2: &Function) -> bool {
     }
 }
 
+/// Constants fitted to observations are the same if they are within the spread of the observations,
+/// so noisy observations refine the constant of the model instead of creating a new one.
+/// There is no tolerance relative to their size, that would make e.g. the constants of different gear ratios the same
+fn compare_guard_constants(value1: &Value, value2: &Value) -> bool {
+    match (value1, value2) {
+        (Value::Number(_) | Value::UncertainNumber(_, _), Value::Number(_) | Value::UncertainNumber(_, _)) => {
+            let (Some((mean1, std1)), Some((mean2, std2))) = (value1.mean_and_std(), value2.mean_and_std()) else {
+                return false;
+            };
+            let tolerance = (3.0 * std1.max(std2)).max(GUARD_CONSTANT_MIN_TOLERANCE);
+            (mean1 - mean2).abs() <= tolerance
+        }
+        _ => value1 == value2,
+    }
+}
+
 
 pub fn compare_cst_fact_patterns(
     pattern1: &Pattern,
@@ -432,3 +457,39 @@ fn get_var_prefix(var: &str) -> String {
         "v".to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::replicode::parse_into_system;
+    use crate::runtime::learning::model_comparison::compare_casual_models;
+    use crate::types::models::Mdl;
+    use crate::types::runtime::System;
+
+    /// Model of a wheel that turns the given multiple of what its motor turns
+    fn gear(ratio: &str) -> Mdl {
+        let mut system = System::new();
+        parse_into_system(&format!("
+mdl_gear:(mdl [] []
+  (fact (cmd turn [w: d:]) : :)
+  (fact (mk.val w: angle na:) : :)
+[]
+  na:(+ a: (* d: {ratio}))
+[]
+  d:(/ (- na: a:) {ratio})
+); Success count: 5, Failure count: 0
+"), &mut system).unwrap();
+        system.models.remove("mdl_gear").unwrap()
+    }
+
+    #[test]
+    fn distinct_guard_constants_stay_separate() {
+        // Gear ratios 2 and 2.3 are different gears, even if they are within 20% of each other
+        assert!(!compare_casual_models(&gear("2"), &gear("2.3")));
+        assert!(!compare_casual_models(&gear("10"), &gear("11")));
+        // Noise in the observations of the same gear
+        assert!(compare_casual_models(&gear("2"), &gear("2.05")));
+        // The spread of the observations so far decides what is noise
+        assert!(compare_casual_models(&gear("(uncertain 2 0.2)"), &gear("2.5")));
+        assert!(!compare_casual_models(&gear("(uncertain 2 0.05)"), &gear("2.3")));
+    }
+}
@@ -2,12 +2,14 @@ use crate::runtime::learning::utils::{change_intersects_entity_var, create_bindi
 use crate::runtime::utils::all_req_models;
 use crate::types::cst::{Cst, ICst};
 use crate::types::models::{IMdl, Mdl, MdlLeftValue, MdlRightValue};
-use crate::types::runtime::{RuntimeCommand, System, SystemState};
+use crate::types::runtime::{RuntimeCommand, System};
 use crate::types::value::Value;
 use crate::types::{EntityVariableKey, Fact, TimePatternRange};
 use itertools::Itertools;
 use std::collections::HashMap;
 use crate::runtime::learning::cst::form_new_cst_for_state;
+use crate::runtime::learning::LearningContext;
+use crate::runtime::learning::model_comparison::compare_model_effects;
 use crate::types::pattern::PatternItem;
 
 pub fn extract_patterns(
@@ -16,10 +18,10 @@ pub fn extract_patterns(
     after: &Value,
     expected_change: &Value,
     model_at_fault: &IMdl,
-    executed_command: &RuntimeCommand,
+    context: &LearningContext,
     system: &mut System,
-    state_before: &SystemState,
 ) {
+    let LearningContext { executed_command, state_before, .. } = context;
     log::debug!("Expected {entity_var:?} to become {expected_change}, but it did not change");
 
     let change = EntityVarChange {
@@ -31,8 +33,62 @@ pub fn extract_patterns(
     let mut pattern_map = create_initial_pattern_value_map(entity_var, before, executed_command);
     let new_cst_id = form_new_cst_for_state(&change, system, state_before, &mut pattern_map);
     let new_cst = system.csts[&new_cst_id].clone();
-    form_new_anti_req_model(&new_cst, model_at_fault, &mut pattern_map, system);
+    let anti_req_model = form_new_anti_req_model(&new_cst, model_at_fault, &mut pattern_map, system);
+    let provenance = context.provenance();
+    for id in [new_cst_id, anti_req_model.clone()] {
+        system.provenance.insert(id, provenance.clone());
+    }
+
+    check_and_merge_with_existing_anti_req_model(&new_cst, &anti_req_model, system);
+}
+
+/// Merge a new anti-requirement model into an existing one for the same model with an equivalent cst,
+/// so repeated failures in the same context add up in one anti-requirement model instead of creating new ones
+fn check_and_merge_with_existing_anti_req_model(cst: &Cst, anti_req_model_id: &str, system: &mut System) {
+    let anti_req_model = system.models[anti_req_model_id].clone();
+    let failed_model_id = &anti_req_model.right.pattern.as_imdl().model_id;
+    let Some(failed_model) = system.models.get(failed_model_id).cloned() else {
+        return;
+    };
+
+    let Some((new_cst, existing_anti_req_model)) = all_req_models(system).into_iter()
+        .filter(|m| m.right.anti && m.model_id != anti_req_model.model_id)
+        .filter(|m| &m.right.pattern.as_imdl().model_id == failed_model_id)
+        .find_map(|existing| {
+            let existing_cst = system.csts.get(&existing.left.pattern.as_icst().cst_id)?;
+            let new_cst = compare_model_effects(existing_cst, &existing, &failed_model, cst, &anti_req_model, &failed_model, system)?;
+            Some((new_cst, existing))
+        }) else {
+        return;
+    };
+
+    let new_cst_id = new_cst.cst_id.clone();
+    let icst = ICst {
+        cst_id: new_cst_id.clone(),
+        params: new_cst.binding_params()
+            .iter()
+            .map(|b| PatternItem::Binding(b.clone()))
+            .collect(),
+    };
+    system.csts.insert(new_cst_id.clone(), new_cst);
+    system.csts.get_mut(&new_cst_id).unwrap().promote();
+
+    // The model failed again in the context of the existing anti-requirement model
+    let existing_ref = system.models.get_mut(&existing_anti_req_model.model_id).unwrap();
+    existing_ref.left = existing_ref.left.with_pattern(MdlLeftValue::ICst(icst));
+    existing_ref.promote();
+
+    log::debug!("Merged into existing anti-requirement model\n{}\n{existing_ref}", system.csts[&new_cst_id]);
+
+    for (existing_id, merged_id) in [
+        (&existing_anti_req_model.model_id, &anti_req_model.model_id),
+        (&new_cst_id, &cst.cst_id),
+    ] {
+        system.record_merge(existing_id, merged_id);
+    }
 
+    system.csts.remove(&cst.cst_id);
+    system.models.remove(&anti_req_model.model_id);
 }
 
 fn form_new_anti_req_model(cst: &Cst, failed_command_model: &IMdl, pattern_map: &mut PatternValueMap, system: &mut System) -> String {
@@ -79,20 +79,20 @@ fn extract_values_from_value(value: &Value) -> HashSet<Value> {
     }
 }
 
-pub fn generate_casual_model_name(system: &System) -> String {
-    format!("mdl_{}", system.models.len())
+pub fn generate_casual_model_name(system: &mut System) -> String {
+    system.generate_id("mdl")
 }
 
-pub fn generate_req_model_name(system: &System) -> String {
-    format!("mdl_req_{}", system.models.len())
+pub fn generate_req_model_name(system: &mut System) -> String {
+    system.generate_id("mdl_req")
 }
 
-pub fn generate_anti_req_model_name(system: &System) -> String {
-    format!("mdl_anti_req_{}", system.models.len())
+pub fn generate_anti_req_model_name(system: &mut System) -> String {
+    system.generate_id("mdl_anti_req")
 }
 
-pub fn generate_cst_name(system: &System) -> String {
-    format!("cst_{}", system.csts.len())
+pub fn generate_cst_name(system: &mut System) -> String {
+    system.generate_id("cst")
 }
 
 pub fn compute_vec_norm(values: &Vec<Value>) -> f64 {
@@ -35,6 +35,10 @@ pub fn state_matches_facts(state: &SystemState, facts: &Vec<Fact<MkVal>>) -> boo
 }
 
 pub fn state_matches_fact(state: &SystemState, fact: &Fact<MkVal>) -> bool {
+    // Facts with a time range, like goals with a deadline, only match the state within that range
+    if !state.time.matches_pattern(&fact.time_range, &HashMap::new()) {
+        return false;
+    }
     let Some(entity_key) = fact.pattern.entity_key(&HashMap::new()) else {
         let matches_any_value = state
             .variables
@@ -1,5 +1,5 @@
 use crate::types::cst::{Cst, ICst};
-use crate::types::{Command, EntityDeclaration, EntityPatternValue, Fact, MkVal, TimePatternRange, TimePatternValue};
+use crate::types::{Command, EntityDeclaration, EntityPatternValue, Fact, Goal, MkVal, TimePatternRange, TimePatternValue};
 use crate::types::functions::Function;
 use crate::types::models::{IMdl, Mdl, MdlLeftValue, MdlRightValue};
 use crate::types::pattern::{PatternItem};
@@ -368,7 +368,7 @@ pub fn setup_hand_grab_sphere_seed(system: &mut System) {
         },
     );
 
-    system.goals = vec![
+    system.goals = Goal::sequence(vec![
         vec![
             Fact::new(
                 MkVal {
@@ -420,5 +420,5 @@ pub fn setup_hand_grab_sphere_seed(system: &mut System) {
                 TimePatternRange::new(TimePatternValue::Any, TimePatternValue::Any)
             )
         ]
-    ];
+    ]);
 }
\ No newline at end of file
@@ -1,4 +1,4 @@
-use crate::types::{EntityPatternValue, EntityVariableKey, Fact, MkVal, TimePatternRange};
+use crate::types::{EntityPatternValue, EntityVariableKey, Fact, Goal, MkVal, TimePatternRange};
 use crate::types::pattern::PatternItem;
 use crate::types::runtime::{RuntimeCommand, System};
 use crate::types::value::Value;
@@ -34,7 +34,7 @@ pub fn setup_hand_grab_sphere_learn_seed(system: &mut System) {
     system.babble_command.push(RuntimeCommand::new("grab".to_string(), "h".to_string(), vec![]));
     system.babble_command.push(RuntimeCommand::new("move".to_string(), "h".to_string(), vec![Value::Number(-5.0)]));
 
-    system.goals = vec![
+    system.goals = Goal::sequence(vec![
         vec![
             Fact::new(MkVal {
                 entity_id: EntityPatternValue::EntityId("s".to_string()),
@@ -67,5 +67,5 @@ pub fn setup_hand_grab_sphere_learn_seed(system: &mut System) {
                 assumption: false,
             }, TimePatternRange::wildcard())
         ],
-    ]
+    ]);
 }
\ No newline at end of file
@@ -7,12 +7,42 @@ pub mod robot_sift_learn_3;
 pub mod scenario_2;
 
 use crate::types::cst::{Cst, ICst};
-use crate::types::{Command, EntityDeclaration, EntityPatternValue, EntityVariableKey, Fact, MkVal, TimePatternRange, TimePatternValue};
+use crate::types::{Command, EntityDeclaration, EntityPatternValue, EntityVariableKey, Fact, Goal, MkVal, TimePatternRange, TimePatternValue};
 use crate::types::functions::Function;
 use crate::types::models::{IMdl, Mdl, MdlLeftValue, MdlRightValue};
 use crate::types::pattern::{PatternItem};
 use crate::types::runtime::System;
 use crate::types::value::Value;
+use serde::de::DeserializeOwned;
+
+pub type Seed = fn(&mut System);
+
+/// Models or csts that an earlier run saved to the file as JSON, or none if the file doesn't exist
+fn load_saved<T: DeserializeOwned + Default>(path: &str) -> T {
+    match std::fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| panic!("Invalid {path}: {e}")),
+        Err(e) => {
+            log::warn!("Not loading {path}: {e}");
+            T::default()
+        }
+    }
+}
+
+/// Seeds that can be selected by name when starting the runtime
+pub const BUILTIN_SEEDS: &[(&str, Seed)] = &[
+    ("bindings", setup_bindings_seed),
+    ("hand-grab-sphere", hand_grab_sphere::setup_hand_grab_sphere_seed),
+    ("hand-grab-sphere-learn", hand_grab_sphere_learn::setup_hand_grab_sphere_learn_seed),
+    ("robot-advanced-move", robot_advanced_move::setup_robot_advanced_seed),
+    ("robot-sift-learn", robot_sift_learn::setup_robot_sift_learn_seed),
+    ("robot-sift-learn-2", robot_sift_learn_2::setup_robot_sift_learn_seed),
+    ("robot-sift-learn-3", robot_sift_learn_3::setup_robot_sift_learn_seed),
+    ("scenario-2", scenario_2::setup_scenario_2),
+];
+
+pub fn builtin_seed(name: &str) -> Option<Seed> {
+    BUILTIN_SEEDS.iter().find(|(seed_name, _)| *seed_name == name).map(|(_, seed)| *seed)
+}
 
 pub fn setup_bindings_seed(system: &mut System) {
     system.create_entity("h", "hand");
@@ -274,7 +304,7 @@ pub fn setup_bindings_seed(system: &mut System) {
         Value::Vec(vec![Value::Number(5.0), Value::Number(5.0)]),
     );
 
-    system.goals = vec![
+    system.goals = Goal::sequence(vec![
         vec![
             Fact::new(
                 MkVal {
@@ -286,5 +316,5 @@ pub fn setup_bindings_seed(system: &mut System) {
                 TimePatternRange::new(TimePatternValue::Any, TimePatternValue::Any)
             ),
         ]
-    ]
+    ]);
 }
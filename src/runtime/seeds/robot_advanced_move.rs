@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::vec;
 use crate::types::cst::{Cst, ICst};
-use crate::types::{Command, EntityDeclaration, EntityPatternValue, EntityVariableKey, Fact, MkVal, TimePatternRange, TimePatternValue};
+use crate::types::{Command, EntityDeclaration, EntityPatternValue, EntityVariableKey, Fact, Goal, MkVal, TimePatternRange, TimePatternValue};
 use crate::types::functions::Function;
 use crate::types::models::{IMdl, Mdl, MdlLeftValue, MdlRightValue};
 use crate::types::pattern::PatternItem;
@@ -755,7 +755,7 @@ pub fn setup_robot_advanced_seed(system: &mut System) {
 
     system.current_state.variables.insert(EntityVariableKey::new("h", "position"), Value::Vec(vec![Value::Number(0.0), Value::Number(0.0), Value::Number(0.0), Value::Number(0.0)]));
 
-    system.goals = vec![
+    system.goals = Goal::sequence(vec![
         vec![
             Fact::new(
                 MkVal {
@@ -800,5 +800,5 @@ pub fn setup_robot_advanced_seed(system: &mut System) {
                 TimePatternRange::new(TimePatternValue::Any, TimePatternValue::Any)
             ),
         ],
-    ];
+    ]);
 }
\ No newline at end of file
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::vec;
 use crate::types::cst::{Cst, ICst};
-use crate::types::{Command, EntityDeclaration, EntityPatternValue, EntityVariableKey, Fact, MkVal, TimePatternRange, TimePatternValue};
+use crate::types::{Command, EntityDeclaration, EntityPatternValue, EntityVariableKey, Fact, Goal, MkVal, TimePatternRange, TimePatternValue};
 use crate::types::functions::Function;
 use crate::types::models::{IMdl, Mdl, MdlLeftValue, MdlRightValue};
 use crate::types::pattern::PatternItem;
@@ -716,7 +716,7 @@ pub fn setup_robot_sift_learn_seed(system: &mut System) {
     });
 
 
-    system.goals = vec![
+    system.goals = Goal::sequence(vec![
         /*vec![
             Fact::new(MkVal {
                 entity_id: EntityPatternValue::EntityId("h".to_string()),
@@ -763,5 +763,5 @@ pub fn setup_robot_sift_learn_seed(system: &mut System) {
                 assumption: false,
             }, TimePatternRange::wildcard()),*/
         ],
-    ];
+    ]);
 }
\ No newline at end of file
@@ -1,12 +1,13 @@
 use std::collections::HashMap;
 use std::vec;
 use crate::types::cst::{Cst, ICst};
-use crate::types::{Command, EntityDeclaration, EntityPatternValue, EntityVariableKey, Fact, MkVal, TimePatternRange, TimePatternValue};
+use crate::types::{Command, EntityDeclaration, EntityPatternValue, EntityVariableKey, Fact, Goal, MkVal, TimePatternRange, TimePatternValue};
 use crate::types::functions::Function;
 use crate::types::models::{IMdl, Mdl, MdlLeftValue, MdlRightValue};
 use crate::types::pattern::PatternItem;
 use crate::types::runtime::{RuntimeCommand, System};
 use crate::types::value::Value;
+use super::load_saved;
 
 pub fn setup_robot_sift_learn_seed(system: &mut System) {
     system.create_entity("h", "hand");
@@ -453,8 +454,8 @@ pub fn setup_robot_sift_learn_seed(system: &mut System) {
         println!("{model}");
     }
 
-    let loaded_models: HashMap<String, Mdl> = serde_json::from_str(&std::fs::read_to_string("models.json").unwrap()).unwrap();
-    let loaded_csts: HashMap<String, Cst> = serde_json::from_str(&std::fs::read_to_string("csts.json").unwrap()).unwrap();
+    let loaded_models: HashMap<String, Mdl> = load_saved("models.json");
+    let loaded_csts: HashMap<String, Cst> = load_saved("csts.json");
 
     let mut added_models = Vec::new();
     for (mdl_id, model) in loaded_models {
@@ -524,7 +525,7 @@ pub fn setup_robot_sift_learn_seed(system: &mut System) {
 
 
 
-    system.goals = vec![
+    system.goals = Goal::sequence(vec![
         /*vec![
             Fact::new(MkVal {
                 entity_id: EntityPatternValue::EntityId("h".to_string()),
@@ -560,5 +561,5 @@ pub fn setup_robot_sift_learn_seed(system: &mut System) {
                 assumption: false,
             }, TimePatternRange::wildcard()),*/
         ],
-    ];
+    ]);
 }
\ No newline at end of file
@@ -1,12 +1,13 @@
 use std::collections::HashMap;
 use std::vec;
 use crate::types::cst::{Cst, ICst};
-use crate::types::{Command, EntityDeclaration, EntityPatternValue, EntityVariableKey, Fact, MkVal, TimePatternRange, TimePatternValue};
+use crate::types::{Command, EntityDeclaration, EntityPatternValue, EntityVariableKey, Fact, Goal, MkVal, TimePatternRange, TimePatternValue};
 use crate::types::functions::Function;
 use crate::types::models::{IMdl, Mdl, MdlLeftValue, MdlRightValue};
 use crate::types::pattern::PatternItem;
 use crate::types::runtime::{RuntimeCommand, System};
 use crate::types::value::Value;
+use super::load_saved;
 
 pub fn setup_robot_sift_learn_seed(system: &mut System) {
     system.create_entity("h", "hand");
@@ -418,8 +419,8 @@ pub fn setup_robot_sift_learn_seed(system: &mut System) {
         },
     );
 
-    let loaded_models: HashMap<String, Mdl> = serde_json::from_str(&std::fs::read_to_string("models.json").unwrap()).unwrap();
-    let loaded_csts: HashMap<String, Cst> = serde_json::from_str(&std::fs::read_to_string("csts.json").unwrap()).unwrap();
+    let loaded_models: HashMap<String, Mdl> = load_saved("models.json");
+    let loaded_csts: HashMap<String, Cst> = load_saved("csts.json");
 
     for (mdl_id, model) in loaded_models {
         if !system.models.contains_key(&mdl_id) {
@@ -467,7 +468,7 @@ pub fn setup_robot_sift_learn_seed(system: &mut System) {
     insert_sift_features(&[1, 2, 3], "co2", system);
 
 
-    system.goals = vec![
+    system.goals = Goal::sequence(vec![
         /*vec![
             Fact::new(MkVal {
                 entity_id: EntityPatternValue::EntityId("h".to_string()),
@@ -503,5 +504,5 @@ pub fn setup_robot_sift_learn_seed(system: &mut System) {
                 assumption: false,
             }, TimePatternRange::wildcard()),*/
         ],
-    ];
+    ]);
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 use crate::runtime::pattern_matching::{are_goals_equal, compare_imdls, compare_pattern_items, compare_patterns, extract_bindings_from_pattern, extract_bindings_from_patterns, extract_duplicate_bindings_from_pattern, extract_duplicate_bindings_from_pattern_and_values};
-use crate::runtime::utils::{all_assumption_models, all_causal_models, all_req_models, all_state_prediction_models, MODEL_CONFIDENCE_THRESHOLD};
+use crate::runtime::utils::{all_assumption_models, all_causal_models, all_req_models, all_state_prediction_models, is_established_anti_req_model, MODEL_CONFIDENCE_THRESHOLD};
 use crate::types::cst::{Cst, ICst};
 use crate::types::models::{AbductionResult, IMdl, Mdl, MdlLeftValue, MdlRightValue};
 use crate::types::pattern::PatternItem;
@@ -12,16 +12,29 @@ use std::hash::Hash;
 
 const MAX_DEPTH: usize = 7;
 
-pub fn backward_chain(goal: &Fact<MkVal>, data: &System) -> Vec<(IMdl, usize)> {
+/// Get the casual models that can lead to the goal and how far they are from it. The requirements of all facts of the goal
+/// are merged, keeping the lowest depth of each model. A fact that already holds needs no models, unless the models for
+/// another fact change it, then the models that make it hold again are also needed
+pub fn backward_chain(goal: &[Fact<MkVal>], data: &System) -> Vec<(IMdl, usize)> {
     let mut instantiable_cas_mdl = Vec::new();
+    let mut anti_requirements = Vec::new();
 
     let req_models = all_req_models(data);
     for m_req in &req_models {
         for bound_m_req in m_req.try_instantiate_with_icst(&data.current_state) {
             let imdl = m_req.right.pattern.as_filled_in_imdl(&bound_m_req.bindings);
-            instantiable_cas_mdl.push(imdl.clone());
+            if m_req.right.anti {
+                if is_established_anti_req_model(m_req) {
+                    anti_requirements.push(imdl);
+                }
+            }
+            else {
+                instantiable_cas_mdl.push(imdl);
+            }
         }
     }
+    // Casual models that are known to fail in the current state cannot be reached from it
+    instantiable_cas_mdl.retain(|imdl| !anti_requirements.iter().any(|anti| compare_imdls(anti, imdl, true, true)));
 
     let mut casual_models = all_causal_models(data);
     casual_models.retain(|m| m.confidence() > MODEL_CONFIDENCE_THRESHOLD && m.success_count > 1);
@@ -29,31 +42,79 @@ pub fn backward_chain(goal: &Fact<MkVal>, data: &System) -> Vec<(IMdl, usize)> {
     
     let mut state_prediction_models = all_assumption_models(data);
     state_prediction_models.extend(all_state_prediction_models(data));
-    
-    let mut observed_goals = HashSet::new();
-    let mut observed_csts = HashMap::new();
-    let goal_req_model_results = run_get_goal_requirements_for_goal(
-        &goal,
-        &instantiable_cas_mdl,
-        &casual_models,
-        &state_prediction_models,
-        data,
-        &mut observed_goals,
-        &mut observed_csts,
-    );
-    
-    goal_req_model_results
+
+    let models = BackwardChainModels {
+        instantiable_cas_mdl,
+        casual_models,
+        assumption_models: state_prediction_models,
+    };
+    let requirements_for_fact = |fact: &Fact<MkVal>, keep_satisfied_goal: bool| {
+        run_get_goal_requirements_for_goal(
+            fact,
+            keep_satisfied_goal,
+            &models,
+            data,
+            &mut HashSet::new(),
+            &mut HashMap::new(),
+        )
+    };
+    let mut fact_requirements = goal.iter()
+        .map(|fact| requirements_for_fact(fact, false))
+        .collect_vec();
+
+    // Backward chain again from facts that are undone by the requirements of other facts,
+    // until no more facts are undone. Each fact is only backward chained again once
+    let mut rechained = vec![false; goal.len()];
+    while let Some(i) = (0..goal.len()).find(|i| {
+        !rechained[*i] && fact_requirements.iter()
+            .enumerate()
+            .any(|(j, requirements)| j != *i && undoes_fact(requirements, &goal[*i], data))
+    }) {
+        log::debug!("Goal fact {} is undone by the requirements of another fact", goal[i]);
+        rechained[i] = true;
+        fact_requirements[i] = requirements_for_fact(&goal[i], true);
+    }
+
+    fact_requirements
         .into_iter()
-        // Remove duplicate results
-        .unique()
+        .flatten()
+        .into_group_map()
+        .into_iter()
+        .map(|(imdl, depths)| (imdl, depths.into_iter().min().unwrap_or(0)))
         .collect()
 }
 
+/// True if one of the requirements changes the variable of the fact, to a value that doesn't match the fact
+/// or to a value that is only known during forward chaining
+fn undoes_fact(requirements: &[(IMdl, usize)], fact: &Fact<MkVal>, data: &System) -> bool {
+    let Some(fact_key) = fact.pattern.entity_key(&HashMap::new()) else {
+        return false;
+    };
+
+    requirements.iter().any(|(imdl, _)| {
+        let bm = imdl.instantiate(&HashMap::new(), data);
+        let MdlRightValue::MkVal(rhs) = &bm.model.right.pattern else {
+            return false;
+        };
+        rhs.entity_key(&bm.bindings).as_ref() == Some(&fact_key)
+            && rhs.value.get_value_with_bindings(&bm.bindings).is_none_or(|value| value != fact.pattern.value)
+    })
+}
+
+/// The models backward chaining goes through, collected once for all facts of the goal
+pub struct BackwardChainModels {
+    /// Casual models whose requirements hold in the current state
+    pub instantiable_cas_mdl: Vec<IMdl>,
+    pub casual_models: Vec<Mdl>,
+    pub assumption_models: Vec<Mdl>,
+}
+
+/// Backward chain from a single fact. If `keep_satisfied_goal` is set, models that lead to the fact are kept
+/// even if it already holds in the current state
 pub fn run_get_goal_requirements_for_goal(
     goal: &Fact<MkVal>,
-    instantiable_cas_mdl: &Vec<IMdl>,
-    casual_models: &Vec<Mdl>,
-    assumption_models: &Vec<Mdl>,
+    keep_satisfied_goal: bool,
+    models: &BackwardChainModels,
     data: &System,
     observed_goals: &mut HashSet<ObservedGoal>,
     observed_csts: &mut HashMap<ObservedCst, usize>,
@@ -66,9 +127,8 @@ pub fn run_get_goal_requirements_for_goal(
     while let Some((current_goal, depth)) = queue.pop_front() {
         let (mut goal_requirements, sub_goals, _) = get_goal_requirements_for_goal(
             &current_goal,
-            instantiable_cas_mdl,
-            casual_models,
-            assumption_models,
+            keep_satisfied_goal && depth == 0,
+            models,
             data,
             observed_goals,
             observed_csts,
@@ -92,9 +152,8 @@ pub fn run_get_goal_requirements_for_goal(
 /// TODO: then use the depth in forward chaining to prioritize the shortest path
 fn get_goal_requirements_for_goal(
     goal: &Fact<MkVal>,
-    instantiable_cas_mdl: &Vec<IMdl>,
-    casual_models: &Vec<Mdl>,
-    assumption_models: &Vec<Mdl>,
+    keep_satisfied_goal: bool,
+    models: &BackwardChainModels,
     data: &System,
     observed_goals: &mut HashSet<ObservedGoal>,
     observed_csts: &mut HashMap<ObservedCst, usize>,
@@ -108,9 +167,9 @@ fn get_goal_requirements_for_goal(
     let mut goal_requirements: Vec<(IMdl, usize)> = Vec::new();
     let mut subgoals = Vec::new();
 
-    let abduction_results = casual_models
+    let abduction_results = models.casual_models
         .iter()
-        .chain(assumption_models.iter())
+        .chain(models.assumption_models.iter())
         // Find and backward chain from all casual models where rhs matches a fact from the goal
         .filter_map(|m| {
             let bm = m.as_bound_model();
@@ -123,7 +182,7 @@ fn get_goal_requirements_for_goal(
         };
 
         // If the causal model can be reached directly from the current state, then we don't have to look further back
-        if instantiable_cas_mdl.iter().any(|imdl_val| {
+        if models.instantiable_cas_mdl.iter().any(|imdl_val| {
             compare_imdls(imdl_val, &goal_model_imdl, true, true)
         }) {
             reached_current_state = true;
@@ -139,7 +198,7 @@ fn get_goal_requirements_for_goal(
         let rhs_mk_val_value = rhs_mk_val
             .value
             .get_value_with_bindings(&goal_model_bm.bindings);
-        if let Some(mk_val_entity_key) = rhs_mk_val.entity_key(&goal_model_bm.bindings) {
+        if let Some(mk_val_entity_key) = rhs_mk_val.entity_key(&goal_model_bm.bindings).filter(|_| !keep_satisfied_goal) {
             if matches!(
             &rhs_mk_val_value,
             Some(v) if data.current_state.variables
@@ -1,63 +1,49 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
 use std::hash::{Hash, Hasher};
-use std::rc::Rc;
-use std::string::ToString;
-use std::time::Instant;
-use crate::runtime::pattern_matching::{compare_imdls, state_matches_facts};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::runtime::pattern_matching::{compare_imdls, state_matches_fact, state_matches_facts};
 use crate::types::models::{BoundModel, IMdl, MdlLeftValue, MdlRightValue};
 use crate::types::runtime::{RuntimeCommand, System, SystemState};
-use crate::types::{Command, EntityPatternValue, EntityVariableKey, Fact, MkVal, TimePatternRange};
+use crate::types::{EntityVariableKey, Fact, MkVal, TimePatternRange};
 use itertools::Itertools;
-use crate::runtime::utils::{all_req_models, MODEL_CONFIDENCE_THRESHOLD};
+use rayon::prelude::*;
+use crate::runtime::utils::{all_req_models, is_established_anti_req_model, MODEL_CONFIDENCE_THRESHOLD};
 use crate::types::cst::BoundCst;
 use crate::types::pattern::PatternItem;
 use crate::types::value::Value;
-use crate::visualize::visualize_forward_chaining;
+use crate::utils::math::probability_in_range;
 
-const MAX_FWD_CHAIN_DEPTH: u64 = 6;
+/// Longest plan the search looks for
+const MAX_PLAN_LENGTH: usize = 12;
+/// Number of states the search may expand before giving up
+const MAX_EXPANSIONS: usize = 5000;
 const TIME_LIMIT_SECS: u64 = 60*10;
+/// Numbers closer to the goal value than this are in the goal, the same as when numbers are compared
+const GOAL_TOLERANCE: f64 = 0.1;
 
 #[derive(Debug, Clone)]
 #[allow(unused)]
 pub struct ForwardChainNode {
     pub command: RuntimeCommand,
-    pub children: Vec<Rc<ForwardChainNode>>,
+    pub children: Vec<Arc<ForwardChainNode>>,
     pub is_in_goal_path: bool,
     pub min_goal_depth: u64,
     pub depth: u64,
 }
 
-#[derive(Debug, Clone)]
-pub struct ForwardChainState {
-    pub observed_states: HashSet<ObservedState>,
-    pub min_solution_depth: u64,
-    pub solution_found: bool,
-    pub start_time: Instant,
-}
-
-impl ForwardChainState {
-    pub fn new() -> ForwardChainState {
-        ForwardChainState {
-            observed_states: HashSet::new(),
-            min_solution_depth: MAX_FWD_CHAIN_DEPTH,
-            solution_found: false,
-            start_time: Instant::now(),
-        }
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct ObservedState {
     pub state: SystemState,
-    pub node: Option<Rc<ForwardChainNode>>,
     pub reachable_from_depth: u64,
 }
 
 impl ObservedState {
-    pub fn new(state: SystemState, node: Option<Rc<ForwardChainNode>>, reachable_from_depth: u64, is_goal_reachable: bool) -> Self {
+    pub fn new(state: SystemState, reachable_from_depth: u64) -> Self {
         Self {
             state,
-            node,
             reachable_from_depth,
         }
     }
@@ -79,122 +65,419 @@ impl PartialEq for ObservedState {
 
 impl Eq for ObservedState {}
 
-pub fn forward_chain(goal: &Vec<Fact<MkVal>>, goal_requirements: &Vec<(IMdl, usize)>, system: &System,) -> Vec<RuntimeCommand> {
-    let (forward_chain_graph, _, _) = forward_chain_rec(goal, goal_requirements, &system.current_state, system, &mut ForwardChainState::new(), 0);
-    let path = commit_to_path(&forward_chain_graph);
-    path
+/// State reached during the search, with the command that led to it from its parent
+struct SearchNode {
+    state: SystemState,
+    parent: Option<usize>,
+    command: Option<RuntimeCommand>,
+    steps: usize,
+    goal_distance: f64,
+    /// Product of the confidences of the models that predicted the states on the path to this state
+    probability: f64,
 }
 
-fn forward_chain_rec(
-    goal: &Vec<Fact<MkVal>>,
-    goal_requirements: &Vec<(IMdl, usize)>,
-    state: &SystemState,
-    system: &System,
-    forward_chain_state: &mut ForwardChainState,
-    depth: u64,
-) -> (Vec<Rc<ForwardChainNode>>, bool, u64) {
-    let state_str = state.variables.iter()
-        .map(|(k, v)| {
-            let entity = &k.entity_id;
-            let variable = &k.var_name;
-            format!("(mk.val {entity} {variable} {v})")
-        })
-        .collect_vec();
-    if state_matches_facts(state, goal) {
-        forward_chain_state.min_solution_depth = forward_chain_state.min_solution_depth.min(depth);
-        forward_chain_state.solution_found = true;
-        log::debug!("Found goal at depth {depth}");
-        return (Vec::new(), true, 0);
+/// Entry in the open list, the node with the lowest estimated cost is expanded first.
+/// Ties are broken by the probability of reaching the state, then by the order the nodes were found in
+struct OpenNode {
+    estimated_cost: f64,
+    probability: f64,
+    index: usize,
+}
+
+impl Ord for OpenNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimated_cost.total_cmp(&self.estimated_cost)
+            .then(self.probability.total_cmp(&other.probability))
+            .then(other.index.cmp(&self.index))
+    }
+}
+
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OpenNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OpenNode {}
+
+/// Best path a search has found so far
+#[derive(Debug, Clone)]
+pub struct PartialPlan {
+    pub commands: Vec<RuntimeCommand>,
+    /// Estimated distance from the state at the end of the path to the goal, 0 if the path reaches the goal
+    pub goal_distance: f64,
+    /// Probability that executing the path reaches the goal, or the state at the end of the path if it doesn't reach the goal
+    pub success_probability: f64,
+    /// True if the path reaches the goal
+    pub complete: bool,
+    /// True if the search has nothing left to look at, so the path won't get any better
+    pub exhausted: bool,
+}
+
+/// Best-first search over predicted states for the sequence of commands that is most likely to reach the goal.
+/// Every command costs one step. States are expanded in the order of their steps plus an estimate of the steps that
+/// are left: the backward chaining depth of the model that predicted the state, i.e. how many more models are needed
+/// to reach the goal from it, plus how far the values of the state are from the goal. The estimate can be more than
+/// the steps that are actually left, so the search is greedy and the first path it finds to the goal may not be the shortest.
+/// The probability that a path succeeds is the product of the confidences of the models along it and the probability
+/// that the predicted values are within the goal. Once a path to the goal is found, the search continues with the states
+/// that are more likely to be reached than the goal at the end of it, so a longer path can replace it if it is more reliable.
+/// The search can be run a little at a time and keeps its tree between runs.
+/// The most promising states are expanded in parallel on the rayon thread pool, the states they lead to are added
+/// to the tree in the order the expanded states were found in, so the search finds the same plan every time
+pub struct ForwardChainSearch {
+    goal: Vec<Fact<MkVal>>,
+    goal_requirements: Vec<(IMdl, usize)>,
+    nodes: Vec<SearchNode>,
+    open: BinaryHeap<OpenNode>,
+    // States that have been reached, with the number of steps and probability of each path to them that no other path
+    // is both shorter and more likely than
+    observed_states: HashMap<ObservedState, Vec<(usize, f64)>>,
+    expansions: usize,
+    solution: Option<usize>,
+    solution_probability: f64,
+    threads: usize,
+}
+
+impl ForwardChainSearch {
+    pub fn new(goal: &[Fact<MkVal>], goal_requirements: &[(IMdl, usize)], state: &SystemState) -> ForwardChainSearch {
+        let goal_distance = goal_distance(state, goal);
+        ForwardChainSearch {
+            goal: goal.to_vec(),
+            goal_requirements: goal_requirements.to_vec(),
+            nodes: vec![SearchNode { state: state.clone(), parent: None, command: None, steps: 0, goal_distance, probability: 1.0 }],
+            open: BinaryHeap::from([OpenNode { estimated_cost: goal_distance, probability: 1.0, index: 0 }]),
+            observed_states: HashMap::from([(ObservedState::new(state.clone(), 0), vec![(0, 1.0)])]),
+            expansions: 0,
+            solution: None,
+            solution_probability: 0.0,
+            threads: available_threads(),
+        }
+    }
+
+    /// Number of states that are expanded at the same time
+    pub fn with_threads(mut self, threads: usize) -> ForwardChainSearch {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn goal(&self) -> &Vec<Fact<MkVal>> {
+        &self.goal
+    }
+
+    /// Search until no state that is left can lead to a more likely path to the goal, or the deadline has passed
+    pub fn run(&mut self, system: &System, deadline: Instant) -> PartialPlan {
+        let was_exhausted = self.is_exhausted();
+        while !self.is_exhausted() && Instant::now() < deadline {
+            let mut batch = Vec::new();
+            while batch.len() < self.threads && self.expansions + batch.len() < MAX_EXPANSIONS {
+                let Some(open_node) = self.open.pop() else {
+                    break;
+                };
+                let node = &self.nodes[open_node.index];
+                // The probability of a path only gets lower as it gets longer
+                if !self.can_improve_solution(node) {
+                    continue;
+                }
+                if state_matches_facts(&node.state, &self.goal) {
+                    let probability = node.probability * goal_probability(&node.state, &self.goal);
+                    log::debug!("Found goal at depth {} with probability {probability:.3} after expanding {} states", node.steps, self.expansions);
+                    if self.solution.is_none() || probability > self.solution_probability {
+                        self.solution = Some(open_node.index);
+                        self.solution_probability = probability;
+                    }
+                    continue;
+                }
+                if node.steps < MAX_PLAN_LENGTH {
+                    batch.push(open_node);
+                }
+            }
+            self.expand(batch.into_iter().map(|open_node| open_node.index).collect(), system);
+        }
+        if !was_exhausted && self.is_exhausted() {
+            match self.solution {
+                Some(_) => log::debug!("Best path to the goal succeeds with probability {:.3}, expanded {} states", self.solution_probability, self.expansions),
+                None => log::debug!("Stopped searching for the goal after expanding {} states", self.expansions),
+            }
+        }
+
+        self.best_plan()
+    }
+
+    /// True if a path through the node may be more likely to succeed than the best path to the goal so far,
+    /// or as likely and shorter
+    fn can_improve_solution(&self, node: &SearchNode) -> bool {
+        let Some(solution) = self.solution else {
+            return true;
+        };
+
+        node.probability > self.solution_probability
+            || (node.probability == self.solution_probability && node.steps < self.nodes[solution].steps)
+    }
+
+    /// Move the start of the search to the state the command led to, keeping the part of the tree below it.
+    /// Returns false if the command was not expanded from the start or the observed state is not the predicted one,
+    /// or if the command leads away from the path to the goal, then the search has to start over
+    pub fn advance(&mut self, command: &RuntimeCommand, state: &SystemState) -> bool {
+        let Some(new_root) = (1..self.nodes.len()).find(|i| {
+            let node = &self.nodes[*i];
+            node.parent == Some(0) && node.command.as_ref() == Some(command) && node.state == *state
+        }) else {
+            return false;
+        };
+
+        // Nodes are always added after their parent, so a node is below the new start if its parent is
+        let root_probability = self.nodes[new_root].probability;
+        let mut new_indices = vec![None; self.nodes.len()];
+        let mut nodes = Vec::new();
+        for (i, node) in std::mem::take(&mut self.nodes).into_iter().enumerate() {
+            let parent = if i == new_root {
+                None
+            } else {
+                match node.parent.and_then(|parent| new_indices[parent]) {
+                    Some(parent) => Some(parent),
+                    None => continue,
+                }
+            };
+            new_indices[i] = Some(nodes.len());
+            nodes.push(SearchNode {
+                parent,
+                command: if i == new_root { None } else { node.command },
+                steps: node.steps - 1,
+                probability: node.probability / root_probability,
+                ..node
+            });
+        }
+        nodes[0].state = state.clone();
+        self.nodes = nodes;
+
+        self.open = std::mem::take(&mut self.open)
+            .into_iter()
+            .filter_map(|open_node| Some(OpenNode {
+                index: new_indices[open_node.index]?,
+                estimated_cost: open_node.estimated_cost - 1.0,
+                probability: open_node.probability / root_probability,
+            }))
+            .collect();
+        // States that were less likely than the path to the goal have been dropped, so the search can't continue without it
+        if self.solution.is_some_and(|solution| new_indices[solution].is_none()) {
+            return false;
+        }
+        self.solution = self.solution.and_then(|solution| new_indices[solution]);
+        self.solution_probability /= root_probability;
+        self.observed_states.clear();
+        for i in 0..self.nodes.len() {
+            self.observe(self.nodes[i].state.clone(), self.nodes[i].steps, self.nodes[i].probability);
+        }
+        // The expansion limit applies to each start state
+        self.expansions = 0;
+
+        true
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.open.is_empty() || self.expansions >= MAX_EXPANSIONS
+    }
+
+    /// Expand the states in parallel, and add the new states they lead to to the tree.
+    /// The states are added in the order of the nodes they were expanded from, so which of two paths to the same state
+    /// is kept doesn't depend on which thread finishes first
+    fn expand(&mut self, mut batch: Vec<usize>, system: &System) {
+        self.expansions += batch.len();
+        batch.sort_unstable();
+        let successors = if batch.len() == 1 {
+            vec![successors(&self.nodes[batch[0]].state, &self.goal_requirements, system)]
+        } else {
+            batch.par_iter()
+                .map(|index| successors(&self.nodes[*index].state, &self.goal_requirements, system))
+                .collect()
+        };
+
+        for (index, successors) in batch.into_iter().zip(successors) {
+            let steps = self.nodes[index].steps + 1;
+            for (command, next_state, bwd_depth, confidence) in successors {
+                let probability = self.nodes[index].probability * confidence;
+                // States that have already been reached in as few steps and as likely don't need to be looked at again
+                if !self.observe(next_state.clone(), steps, probability) {
+                    continue;
+                }
+                let goal_distance = goal_distance(&next_state, &self.goal);
+                let estimated_cost = steps as f64 + bwd_depth as f64 + goal_distance;
+                self.open.push(OpenNode { estimated_cost, probability, index: self.nodes.len() });
+                self.nodes.push(SearchNode { state: next_state, parent: Some(index), command: Some(command), steps, goal_distance, probability });
+            }
+        }
+    }
+
+    /// Remember that the state can be reached in this many steps with this probability.
+    /// Returns false if it has already been reached in as few steps and at least as likely, then it doesn't need
+    /// to be looked at again. A longer path is kept if it is more likely, it may lead to a more reliable plan
+    fn observe(&mut self, state: SystemState, steps: usize, probability: f64) -> bool {
+        let paths = self.observed_states.entry(ObservedState::new(state, steps as u64)).or_default();
+        if paths.iter().any(|(s, p)| *s <= steps && *p >= probability) {
+            return false;
+        }
+        paths.retain(|(s, p)| *s < steps || *p > probability);
+        paths.push((steps, probability));
+
+        true
     }
-    if depth >= forward_chain_state.min_solution_depth {
-        return (Vec::new(), false, u64::MAX);
+
+    /// The path to the goal if it has been found, otherwise the path to the state that is closest to the goal.
+    /// The path is empty if no state is closer to the goal than the start
+    fn best_plan(&self) -> PartialPlan {
+        let exhausted = self.is_exhausted();
+        if let Some(solution) = self.solution {
+            return PartialPlan {
+                commands: path_to(&self.nodes, solution),
+                goal_distance: 0.0,
+                success_probability: self.solution_probability,
+                complete: true,
+                exhausted,
+            };
+        }
+        let closest = (0..self.nodes.len())
+            .min_by(|a, b| {
+                self.nodes[*a].goal_distance.total_cmp(&self.nodes[*b].goal_distance)
+                    .then(self.nodes[*a].steps.cmp(&self.nodes[*b].steps))
+            })
+            .unwrap_or(0);
+
+        PartialPlan {
+            commands: path_to(&self.nodes, closest),
+            goal_distance: self.nodes[closest].goal_distance,
+            success_probability: self.nodes[closest].probability,
+            complete: false,
+            exhausted,
+        }
     }
-    if forward_chain_state.solution_found && forward_chain_state.start_time.elapsed().as_secs() > TIME_LIMIT_SECS {
-        return (Vec::new(), false, u64::MAX);
+}
+
+/// Find the sequence of commands that is most likely to reach the goal, or an empty path if it can't be found in time.
+/// It is not necessarily the shortest one, see [`ForwardChainSearch`]
+pub fn forward_chain(goal: &Vec<Fact<MkVal>>, goal_requirements: &Vec<(IMdl, usize)>, system: &System, threads: usize) -> Vec<RuntimeCommand> {
+    let deadline = Instant::now() + Duration::from_secs(TIME_LIMIT_SECS);
+    let plan = ForwardChainSearch::new(goal, goal_requirements, &system.current_state)
+        .with_threads(threads)
+        .run(system, deadline);
+    if plan.complete {
+        plan.commands
+    } else {
+        Vec::new()
     }
+}
 
-    let mut results = Vec::new();
-    let mut is_in_goal_path = false;
-    let mut node_min_goal_depth = u64::MAX;
+/// Number of threads to plan with when nothing else is given, one for each core
+pub fn available_threads() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
+}
 
+/// Commands of the casual models that can be used in the state and lead towards the goal, with the states they
+/// are predicted to lead to, the backward chaining depth of the model and its confidence
+fn successors(
+    state: &SystemState,
+    goal_requirements: &Vec<(IMdl, usize)>,
+    system: &System,
+) -> Vec<(RuntimeCommand, SystemState, usize, f64)> {
     // Get all casual models that can be instantiated with forward chaining
     let fwd_chained_casual_models = compute_instantiate_casual_models(state, true, system);
+    let anti_requirements = fwd_chained_casual_models.iter().filter(|(_, anti)| *anti).map(|(imdl, _)| imdl).collect_vec();
 
     let (insatiable_casual_models, final_casual_models)
         = compute_merged_forward_backward_models(&fwd_chained_casual_models, goal_requirements, system);
 
-    for (casual_model, _) in final_casual_models.iter().sorted_by_key(|(m, d)| (d, -(m.model.confidence() * 100.0) as i32)) {
-        if let Some(command) = casual_model
-            .get_casual_model_command(&insatiable_casual_models, &system)
-            .map(|c| c.to_runtime_command(&casual_model.bindings).ok())
-            .flatten()
-        {
-            let Some(next_state) = casual_model.predict_state_change(
-                &state,
-                &fwd_chained_casual_models.iter().filter(|(_, anti)| *anti).map(|(imdl, _)| imdl).collect(),
-                &insatiable_casual_models,
-                system,
-            ) else {
-              continue;
-            };
+    let mut successors = Vec::new();
+    for (casual_model, depth) in final_casual_models {
+        let Some(command) = casual_model
+            .get_casual_model_command(&insatiable_casual_models, system)
+            .and_then(|c| c.to_runtime_command(&casual_model.bindings).ok())
+        else {
+            continue;
+        };
+        let Some(next_state) = casual_model.predict_state_change(state, &anti_requirements, &insatiable_casual_models, system) else {
+            continue;
+        };
+        // Commands that are predicted to change nothing can't bring us closer to the goal
+        if state == &next_state {
+            continue;
+        }
+        successors.push((command, next_state, depth, casual_model.model.confidence()));
+    }
 
-            // Don't look at next state if prediction changes nothing or if we have already seen this state
-            let observed_state = ObservedState::new(next_state.clone(), None, depth, false);
-            if state == &next_state {
-                continue;
-            }
-            if let Some(existing_observed_state) = forward_chain_state.observed_states.get(&observed_state) {
-                // Re-evaluate this state if we reached it at a lower depth, since goal paths could have been skipped due to depth limit
-                if existing_observed_state.reachable_from_depth <= depth || existing_observed_state.node.as_ref().map(|n| n.is_in_goal_path).unwrap_or(false) {
-                    // If the node for this state has been computed, then add it since we may have found an alternative (potentially better) path to it
-                    // If it has not been computed, then we have most likely found a cycle in the graph
-                    if let Some(node) = existing_observed_state.node.as_ref() {
-                        results.push(Rc::new(ForwardChainNode {
-                            command,
-                            children: node.children.clone(),
-                            is_in_goal_path: node.is_in_goal_path,
-                            min_goal_depth: node.min_goal_depth,
-                            depth
-                        }));
-                        if node.is_in_goal_path {
-                            node_min_goal_depth = node_min_goal_depth.min(node.min_goal_depth);
-                            is_in_goal_path = true;
-                            forward_chain_state.min_solution_depth = forward_chain_state.min_solution_depth.min(depth+node.min_goal_depth);
-                        }
-                    }
+    successors
+}
 
-                    continue;
+/// How far the values of the state are from the goal. Each fact adds between 0 when it holds and 1,
+/// numbers that are close to the goal value add less than numbers that are far from it
+fn goal_distance(state: &SystemState, goal: &[Fact<MkVal>]) -> f64 {
+    goal.iter()
+        .map(|fact| {
+            if state_matches_fact(state, fact) {
+                return 0.0;
+            }
+            let value = fact.pattern.entity_key(&HashMap::new()).and_then(|key| state.variables.get(&key));
+            match (value, &fact.pattern.value) {
+                (Some(value), PatternItem::Value(goal_value)) => {
+                    let distance = value_distance(value, goal_value);
+                    if distance.is_finite() { distance / (distance + 1.0) } else { 1.0 }
                 }
-
-                // Remove the existing observed state to insert a new one with a new depth limit
-                forward_chain_state.observed_states.remove(&observed_state);
+                _ => 1.0,
             }
-            forward_chain_state.observed_states.insert(observed_state);
-
-            let command_str = command.to_string();
-            let (children, is_goal_path, min_goal_depth) =
-                forward_chain_rec(goal, goal_requirements, &next_state, system, forward_chain_state, depth + 1);
-            if is_goal_path {
-                node_min_goal_depth = node_min_goal_depth.min(min_goal_depth.saturating_add(1));
-                is_in_goal_path = true;
+        })
+        .sum()
+}
+
+/// Probability that the values of the state are within the goal, where numbers are within the goal
+/// if they are closer to it than the tolerance used when comparing numbers
+fn goal_probability(state: &SystemState, goal: &[Fact<MkVal>]) -> f64 {
+    goal.iter()
+        .map(|fact| {
+            let value = fact.pattern.entity_key(&HashMap::new()).and_then(|key| state.variables.get(&key));
+            match (value, &fact.pattern.value) {
+                (Some(value), PatternItem::Value(goal_value)) => value_probability(value, goal_value),
+                _ if state_matches_fact(state, fact) => 1.0,
+                _ => 0.0,
             }
+        })
+        .product()
+}
 
-            let node = Rc::new(ForwardChainNode {
-                command,
-                children,
-                is_in_goal_path: is_goal_path,
-                min_goal_depth: min_goal_depth.saturating_add(1),
-                depth
-            });
+fn value_probability(value: &Value, goal_value: &Value) -> f64 {
+    match (value, goal_value) {
+        (Value::UncertainNumber(mean, std), Value::Number(b) | Value::ConstantNumber(b) | Value::UncertainNumber(b, _)) if *std > 0.0 => {
+            probability_in_range(*mean, *std, b - GOAL_TOLERANCE, b + GOAL_TOLERANCE)
+        }
+        (Value::Vec(a), Value::Vec(b)) if a.len() == b.len() => a.iter().zip(b).map(|(a, b)| value_probability(a, b)).product(),
+        (a, b) if a == b => 1.0,
+        _ => 0.0,
+    }
+}
 
-            let new_observed_state = ObservedState::new(next_state.clone(), Some(node.clone()), depth, is_goal_path);
-            forward_chain_state.observed_states.remove(&new_observed_state);
-            forward_chain_state.observed_states.insert(new_observed_state);
-            results.push(node);
-        };
+fn value_distance(value: &Value, goal_value: &Value) -> f64 {
+    match (value, goal_value) {
+        (Value::Number(a) | Value::ConstantNumber(a) | Value::UncertainNumber(a, _), Value::Number(b) | Value::ConstantNumber(b) | Value::UncertainNumber(b, _)) => (a - b).abs(),
+        (Value::Vec(a), Value::Vec(b)) if a.len() == b.len() => a.iter().zip(b).map(|(a, b)| value_distance(a, b)).sum(),
+        (a, b) if a == b => 0.0,
+        _ => f64::INFINITY,
+    }
+}
+
+/// Follow the parents of the node back to the start of the search
+fn path_to(nodes: &[SearchNode], mut index: usize) -> Vec<RuntimeCommand> {
+    let mut path = Vec::new();
+    while let Some(command) = &nodes[index].command {
+        path.push(command.clone());
+        index = nodes[index].parent.expect("Only the start node has no parent");
     }
+    path.reverse();
 
-    (results, is_in_goal_path, node_min_goal_depth)
+    path
 }
 
 pub(super) fn compute_merged_forward_backward_models(fwd_chained_casual_models: &Vec<(IMdl, bool)>, goal_requirements: &Vec<(IMdl, usize)>, system: &System) -> (Vec<BoundModel>, Vec<(BoundModel, usize)>) {
@@ -241,12 +524,13 @@ pub(super) fn compute_instantiate_casual_models(state: &SystemState, use_confide
 
     all_req_models(system)
         .into_iter()
+        .filter(|m| !m.right.anti || is_established_anti_req_model(m))
         .flat_map(|m| {
             let bm = m.as_bound_model();
             instantiated_composite_states
                 .iter()
                 .filter_map(|icst| {
-                    bm.deduce(&Fact::new(MdlLeftValue::ICst(icst.clone()), TimePatternRange::wildcard()), &Vec::new())
+                    bm.deduce(&Fact::new(MdlLeftValue::ICst(icst.clone()), TimePatternRange::from_system_time(&state.time)), &Vec::new())
                 })
                 .collect_vec()
         })
@@ -264,26 +548,10 @@ pub(super) fn compute_instantiate_casual_models(state: &SystemState, use_confide
         .collect_vec()
 }
 
-fn commit_to_path(forward_chain_result: &Vec<Rc<ForwardChainNode>>) -> Vec<RuntimeCommand> {
-    let Some(best_path_node) = forward_chain_result.iter()
-        .sorted_by_key(|n| n.min_goal_depth)
-        .filter(|n| n.is_in_goal_path)
-        .next() else {
-        return Vec::new();
-    };
-
-    if best_path_node.children.is_empty() {
-        vec![best_path_node.command.clone()]
-    } else {
-        let mut path = commit_to_path(&best_path_node.children);
-        path.insert(0, best_path_node.command.clone());
-        path
-    }
-
-}
-
 pub fn predict_all_changes_of_command(command: &RuntimeCommand, use_confidence_threshold: bool, system: &System) -> Vec<(EntityVariableKey, Value, IMdl)> {
-    let lhs_cmd = Fact::new(MdlLeftValue::Command(command.to_command()), TimePatternRange::wildcard());
+    // The command is executed now, so predictions of models with timing are relative to the current time
+    let now = TimePatternRange::from_system_time(&system.current_state.time);
+    let lhs_cmd = Fact::new(MdlLeftValue::Command(command.to_command()), now.clone());
     let fwd_chained_casual_models = compute_instantiate_casual_models(&system.current_state, use_confidence_threshold, system);
 
     let anti_requirements = fwd_chained_casual_models
@@ -305,7 +573,7 @@ pub fn predict_all_changes_of_command(command: &RuntimeCommand, use_confidence_t
                     .filter(|(_, anti)| !*anti)
                     .filter_map(|(mdl2, _)| {
                         let mdl2 = mdl2.instantiate(&HashMap::new(), &system).extend_bindings_with_lhs_input(&lhs_cmd)?.imdl_for_model();
-                        bound_mdl.deduce(&Fact::new(MdlLeftValue::IMdl(mdl2), TimePatternRange::wildcard()), &anti_requirements_ref)
+                        bound_mdl.deduce(&Fact::new(MdlLeftValue::IMdl(mdl2), now.clone()), &anti_requirements_ref)
                     })
                     .next()?;
                 Some((rhs, bound_mdl.imdl_for_model()))
@@ -328,4 +596,217 @@ pub fn predict_all_changes_of_command(command: &RuntimeCommand, use_confidence_t
             _ => None
         })
         .collect()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::replicode::parse_into_system;
+    use crate::runtime::utils::compute_instantiated_states;
+    use crate::runtime::simulation::backward::backward_chain;
+    use itertools::Itertools;
+    use crate::runtime::simulation::forward::{ForwardChainSearch, MAX_EXPANSIONS, MAX_PLAN_LENGTH};
+    use crate::types::models::IMdl;
+    use crate::types::pattern::PatternItem;
+    use crate::types::runtime::{RuntimeCommand, System, SystemState};
+    use crate::types::value::Value;
+    use crate::types::{EntityPatternValue, EntityVariableKey, Fact, Goal, MkVal, TimePatternRange};
+
+    /// A robot that moves one position at a time with a reliable model, or jumps from 0 to 2 with an unreliable one
+    const STEP_OR_JUMP: &str = "
+(mk.val r essence robot)
+(mk.val r pos 0)
+
+S_at:(cst [] []
+  (fact (mk.val r: essence robot) : :)
+  (fact (mk.val r: pos p:) : :)
+|[]
+|[]); Success count: 5, Failure count: 0
+
+S_start:(cst [] []
+  (fact (mk.val r: essence robot) : :)
+  (fact (mk.val r: pos 0) : :)
+|[]
+|[]); Success count: 5, Failure count: 0
+
+mdl_step:(mdl [] []
+  (fact (cmd step [r: ]) : :)
+  (fact (mk.val r: pos np:) : :)
+[]
+  np:(+ p: 1)
+[]
+  p:(- np: 1)
+); Success count: 37, Failure count: 0
+
+mdl_step_req:(mdl [] []
+  (fact (icst S_at [r: p:]) : :)
+  (fact (imdl mdl_step [r: p:] | ) : :)
+|[]
+|[]); Success count: 5, Failure count: 0
+
+mdl_jump:(mdl [] []
+  (fact (cmd jump [r: ]) : :)
+  (fact (mk.val r: pos 2) : :)
+|[]
+|[]); Success count: 2, Failure count: 1
+
+mdl_jump_req:(mdl [] []
+  (fact (icst S_start [r:]) : :)
+  (fact (imdl mdl_jump [r:] | ) : :)
+|[]
+|[]); Success count: 5, Failure count: 0
+
+goal_0:(goal (fact (mk.val r pos 3) : :) 1 : retry)
+";
+
+    fn system(source: &str) -> System {
+        let mut system = System::new();
+        parse_into_system(source, &mut system).unwrap();
+        system.current_state.instansiated_csts = compute_instantiated_states(&system, &system.current_state);
+        system
+    }
+
+    fn search(system: &System, threads: usize) -> ForwardChainSearch {
+        let goal = system.goals[0].facts.clone();
+        ForwardChainSearch::new(&goal, &backward_chain(&goal, system), &system.current_state).with_threads(threads)
+    }
+
+    /// Jumping and stepping once is shorter, but stepping three times is more likely to reach the goal.
+    /// The state after the jump is reached again by the longer path, which must not be pruned
+    #[test]
+    fn longer_path_is_kept_when_it_is_more_likely() {
+        let system = system(STEP_OR_JUMP);
+        let plan = search(&system, 1).run(&system, Instant::now() + Duration::from_secs(10));
+
+        assert!(plan.complete);
+        assert_eq!(plan.commands.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["step", "step", "step"]);
+        assert!(plan.success_probability > 0.9, "Plan succeeds with probability {}", plan.success_probability);
+    }
+
+    /// States are expanded in parallel, but the tree they are added to is the same as when they are expanded one at a time
+    #[test]
+    fn parallel_search_finds_the_same_plan() {
+        let system = system(STEP_OR_JUMP);
+        let sequential = search(&system, 1).run(&system, Instant::now() + Duration::from_secs(10));
+        for _ in 0..10 {
+            let parallel = search(&system, 4).run(&system, Instant::now() + Duration::from_secs(10));
+            assert_eq!(parallel.commands, sequential.commands);
+            assert_eq!(parallel.success_probability, sequential.success_probability);
+        }
+    }
+
+    /// Predicted state the command leads to from the start of the search
+    fn predicted_state(search: &ForwardChainSearch, command: &RuntimeCommand) -> SystemState {
+        search.nodes.iter().find(|node| node.parent == Some(0) && node.command.as_ref() == Some(command)).unwrap().state.clone()
+    }
+
+    /// When the first command of the plan leads to the predicted state, the search continues from there with what it has found
+    #[test]
+    fn search_is_kept_when_the_state_is_the_predicted_one() {
+        let system = system(STEP_OR_JUMP);
+        let mut search = search(&system, 1);
+        let plan = search.run(&system, Instant::now() + Duration::from_secs(10));
+        let step = plan.commands[0].clone();
+        let predicted = predicted_state(&search, &step);
+        let nodes = search.nodes.len();
+
+        assert!(search.advance(&step, &predicted));
+        assert_eq!(search.nodes[0].state, predicted);
+        assert!(search.nodes.len() > 1 && search.nodes.len() < nodes);
+        assert!(search.nodes.iter().all(|node| node.parent.is_none_or(|parent| parent < search.nodes.len())));
+        let plan = search.run(&system, Instant::now() + Duration::from_secs(10));
+        assert!(plan.complete);
+        assert_eq!(plan.commands.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["step", "step"]);
+    }
+
+    #[test]
+    fn search_starts_over_when_the_state_is_not_the_predicted_one() {
+        let system = system(STEP_OR_JUMP);
+        let mut search = search(&system, 1);
+        let plan = search.run(&system, Instant::now() + Duration::from_secs(10));
+        let step = plan.commands[0].clone();
+
+        let mut unexpected = predicted_state(&search, &step);
+        unexpected.variables.insert(EntityVariableKey::new("r", "pos"), Value::Number(5.0));
+        assert!(!search.advance(&step, &unexpected));
+
+        let wait = RuntimeCommand { name: "wait".to_string(), entity_id: "r".to_string(), params: Vec::new() };
+        assert!(!search.advance(&wait, &system.current_state));
+    }
+
+    fn step_only_search(system: &System) -> ForwardChainSearch {
+        let goal_requirements = [(IMdl::new("mdl_step".to_string(), vec![PatternItem::Any, PatternItem::Any]), 0)];
+        ForwardChainSearch::new(&system.goals[0].facts, &goal_requirements, &system.current_state).with_threads(1)
+    }
+
+    /// Backward chaining stops at a depth below the plan length, so the step model is given as the only requirement
+    #[test]
+    fn plans_are_no_longer_than_the_limit() {
+        let mut system = system(STEP_OR_JUMP);
+        system.goals[0] = Goal::new("goal_0", vec![goal_pos(MAX_PLAN_LENGTH)], 1);
+        let plan = step_only_search(&system).run(&system, Instant::now() + Duration::from_secs(10));
+        assert!(plan.complete);
+        assert_eq!(plan.commands.len(), MAX_PLAN_LENGTH);
+
+        system.goals[0] = Goal::new("goal_0", vec![goal_pos(MAX_PLAN_LENGTH + 1)], 1);
+        let plan = step_only_search(&system).run(&system, Instant::now() + Duration::from_secs(10));
+        assert!(!plan.complete);
+        assert!(plan.exhausted);
+        // The closest the search gets is one step short of the goal
+        assert_eq!(plan.commands.len(), MAX_PLAN_LENGTH);
+        assert_eq!(plan.goal_distance, 0.5);
+    }
+
+    fn goal_pos(pos: usize) -> Fact<MkVal> {
+        Fact::new(MkVal {
+            entity_id: EntityPatternValue::EntityId("r".to_string()),
+            var_name: "pos".to_string(),
+            value: PatternItem::Value(Value::Number(pos as f64)),
+            assumption: false,
+        }, TimePatternRange::wildcard())
+    }
+
+    /// Counters that each have a command to increment them, so there are many ways to reach many different states
+    fn counters(count: usize) -> String {
+        let names = (0..count).map(|i| format!("n{i}")).collect_vec();
+        let mut source = "(mk.val c essence counter)\n(mk.val c done 0)\n".to_string();
+        source += &names.iter().map(|n| format!("(mk.val c {n} 0)\n")).join("");
+        source += &format!(
+            "S_c:(cst [] []\n  (fact (mk.val c: essence counter) : :)\n{}|[]\n|[]); Success count: 5, Failure count: 0\n",
+            names.iter().map(|n| format!("  (fact (mk.val c: {n} {n}:) : :)\n")).join("")
+        );
+        for n in &names {
+            source += &format!("
+mdl_{n}:(mdl [] []
+  (fact (cmd inc_{n} [c: ]) : :)
+  (fact (mk.val c: {n} next:) : :)
+[]
+  next:(+ {n}: 1)
+[]
+  {n}:(- next: 1)
+); Success count: 37, Failure count: 0
+
+mdl_{n}_req:(mdl [] []
+  (fact (icst S_c [c: {params}]) : :)
+  (fact (imdl mdl_{n} [c: {n}:] | ) : :)
+|[]
+|[]); Success count: 5, Failure count: 0
+", params = names.iter().map(|n| format!("{n}:")).join(" "));
+        }
+        // Nothing changes done, so the goal can't be reached
+        source + "goal_0:(goal (fact (mk.val c done 1) : :) 1 : retry)\n"
+    }
+
+    #[test]
+    fn search_gives_up_after_the_expansion_limit() {
+        let system = system(&counters(6));
+        let goal_requirements = (0..6).map(|i| (IMdl::new(format!("mdl_n{i}"), vec![PatternItem::Any, PatternItem::Any]), 0)).collect_vec();
+        let mut search = ForwardChainSearch::new(&system.goals[0].facts, &goal_requirements, &system.current_state).with_threads(4);
+        let plan = search.run(&system, Instant::now() + Duration::from_secs(600));
+        assert!(!plan.complete);
+        assert!(plan.exhausted);
+        assert_eq!(search.expansions, MAX_EXPANSIONS);
+        // Further runs don't look at any more states
+        search.run(&system, Instant::now() + Duration::from_secs(600));
+        assert_eq!(search.expansions, MAX_EXPANSIONS);
+    }
+}
@@ -60,7 +60,7 @@ pub fn try_to_find_expected_path(goal: &Fact<MkVal>, system: &System) {
     //    can_find_all_needed_models_in_backward_chaining(0, g, &expected_path, system);
     //}
     // Create backwards chaining results
-    let bwd_results = backward_chain(goal, system);
+    let bwd_results = backward_chain(std::slice::from_ref(goal), system);
     // Validate the backwards chaining results contain expected commands
     let bwd_associated_models = validate_backwards_chaining_result(&bwd_results, &expected_path, &expected_mk_vals, &expected_command_names, system);
     // Make sure we can go though all expected commands with forward chaining, using the backwards chaining results
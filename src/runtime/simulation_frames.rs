@@ -1,6 +1,7 @@
 use std::process::exit;
 use crate::types::EntityVariableKey;
-use crate::types::runtime::System;
+use crate::interfaces::{Environment, InputEvent};
+use crate::types::runtime::{RuntimeCommand, System};
 use crate::types::value::Value;
 
 pub fn set_simulation_frame(frame: u64, system: &mut System) {
@@ -492,4 +493,62 @@ pub fn set_simulation_frame(frame: u64, system: &mut System) {
         insert_sift_features(&[9, 7, 30, 11, 26, 27, 29, 24, 25, 31, 44, 23], "co3", system);
         insert_sift_features(&[34, 11, 33, 37, 38, 35, 36], "co1", system);
     }
-}
\ No newline at end of file
+}
+/// Steps through the frames recorded from the robot, advancing one frame for every move, grab or release
+pub struct SimulatedRobotFrames {
+    /// Frames were recorded one step apart, so the next frame follows from the number of executed steps
+    steps: u64,
+    done: bool,
+}
+
+impl SimulatedRobotFrames {
+    /// The recording ends before frame 9
+    const LAST_FRAME: u64 = 8;
+
+    pub fn new() -> Self {
+        Self { steps: 0, done: false }
+    }
+}
+
+impl Default for SimulatedRobotFrames {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Environment for SimulatedRobotFrames {
+    fn observe(&mut self, system: &mut System) -> anyhow::Result<InputEvent> {
+        if system.current_state.variables.is_empty() && !system.babble_command.is_empty() {
+            set_simulation_frame(0, system);
+        }
+        Ok(InputEvent::Observed)
+    }
+
+    fn execute(&mut self, command: &RuntimeCommand, system: &mut System) -> anyhow::Result<()> {
+        let frame = self.steps;
+        self.steps += 1;
+        match &command.name[..] {
+            "move" | "grab" | "release" if frame < Self::LAST_FRAME => {
+                system.current_state.variables.clear();
+                log::debug!("Got to frame {}", frame + 1);
+                set_simulation_frame(frame + 1, system);
+            }
+            "move" | "grab" | "release" => self.done = true,
+            "no_action" if frame >= Self::LAST_FRAME => self.done = true,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self, system: &mut System) -> anyhow::Result<()> {
+        self.steps = 0;
+        self.done = false;
+        system.current_state.variables.clear();
+        Ok(())
+    }
+
+    fn is_episode_done(&self, _system: &System) -> bool {
+        self.done
+    }
+}
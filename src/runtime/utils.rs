@@ -7,6 +7,8 @@ use crate::types::runtime::{System, SystemState};
 use crate::types::value::Value;
 
 pub const MODEL_CONFIDENCE_THRESHOLD: f64 = 0.59;
+/// Number of times a model has to fail in the context of an anti-requirement model before it is excluded there
+pub const ANTI_REQUIREMENT_MIN_FAILURES: usize = 2;
 
 pub fn compute_instantiated_states(
     system: &System,
@@ -84,6 +86,13 @@ pub fn all_req_models(data: &System) -> Vec<Mdl> {
         .collect()
 }
 
+/// True if the model it excludes has failed often enough in its context, and rarely succeeded there since.
+/// An anti-requirement model is promoted each time the model fails in its context and demoted each time it succeeds,
+/// so its success count is the number of failures of the model it excludes
+pub fn is_established_anti_req_model(model: &Mdl) -> bool {
+    model.success_count >= ANTI_REQUIREMENT_MIN_FAILURES && model.confidence() > MODEL_CONFIDENCE_THRESHOLD
+}
+
 pub fn all_assumption_models(data: &System) -> Vec<Mdl> {
     data.models
         .iter()
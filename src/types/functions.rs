@@ -74,6 +74,65 @@ impl Function {
         }
     }
 
+    /// Rearrange `result = self` so the binding is alone on one side, and return the other side.
+    /// Returns None if the binding does not appear exactly once
+    pub fn solve_for(&self, binding: &str, result: Function) -> Option<Function> {
+        let contains_binding = |f: &Function| f.binding_params().iter().any(|b| b == binding);
+        let (f1, f2) = match self {
+            Function::Value(PatternItem::Binding(b)) if b == binding => return Some(result),
+            Function::Add(f1, f2) | Function::Sub(f1, f2) | Function::Mul(f1, f2) | Function::Div(f1, f2) => (f1, f2),
+            _ => return None,
+        };
+        let (result, f) = match (self, contains_binding(f1), contains_binding(f2)) {
+            (Function::Add(_, _), true, false) => (Function::Sub(Box::new(result), f2.clone()), f1),
+            (Function::Add(_, _), false, true) => (Function::Sub(Box::new(result), f1.clone()), f2),
+            (Function::Sub(_, _), true, false) => (Function::Add(Box::new(result), f2.clone()), f1),
+            (Function::Sub(_, _), false, true) => (Function::Sub(f1.clone(), Box::new(result)), f2),
+            (Function::Mul(_, _), true, false) => (Function::Div(Box::new(result), f2.clone()), f1),
+            (Function::Mul(_, _), false, true) => (Function::Div(Box::new(result), f1.clone()), f2),
+            (Function::Div(_, _), true, false) => (Function::Mul(Box::new(result), f2.clone()), f1),
+            (Function::Div(_, _), false, true) => (Function::Div(f1.clone(), Box::new(result)), f2),
+            _ => return None,
+        };
+
+        f.solve_for(binding, result)
+    }
+
+    /// The constant that was fitted to observations when this function was learned, if there is exactly one
+    pub fn fitted_constant(&self) -> Option<&Value> {
+        self.fitted_constants().into_iter().exactly_one().ok()
+    }
+
+    fn fitted_constants(&self) -> Vec<&Value> {
+        match self {
+            Function::Value(PatternItem::Value(v @ (Value::Number(_) | Value::UncertainNumber(_, _)))) => vec![v],
+            Function::Value(_) => Vec::new(),
+            Function::Add(f1, f2)
+            | Function::Sub(f1, f2)
+            | Function::Mul(f1, f2)
+            | Function::Div(f1, f2) => f1.fitted_constants().into_iter().chain(f2.fitted_constants()).collect(),
+            Function::List(l) => l.iter().flat_map(|f| f.fitted_constants()).collect(),
+            Function::ConvertToEntityId(f) => f.fitted_constants(),
+            Function::ConvertToNumber(f) => f.fitted_constants(),
+        }
+    }
+
+    /// Replace the fitted constants of this function
+    pub fn with_fitted_constant(&self, constant: &PatternItem) -> Function {
+        let replace = |f: &Function| Box::new(f.with_fitted_constant(constant));
+        match self {
+            Function::Value(PatternItem::Value(Value::Number(_) | Value::UncertainNumber(_, _))) => Function::Value(constant.clone()),
+            Function::Value(_) => self.clone(),
+            Function::Add(f1, f2) => Function::Add(replace(f1), replace(f2)),
+            Function::Sub(f1, f2) => Function::Sub(replace(f1), replace(f2)),
+            Function::Mul(f1, f2) => Function::Mul(replace(f1), replace(f2)),
+            Function::Div(f1, f2) => Function::Div(replace(f1), replace(f2)),
+            Function::List(l) => Function::List(l.iter().map(|f| f.with_fitted_constant(constant)).collect()),
+            Function::ConvertToEntityId(f) => Function::ConvertToEntityId(replace(f)),
+            Function::ConvertToNumber(f) => Function::ConvertToNumber(replace(f)),
+        }
+    }
+
     pub fn binding_params(&self) -> Vec<String> {
         match self {
             Function::Value(p) => p.get_bindings(),
@@ -108,4 +167,77 @@ impl Display for Function {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::types::functions::Function;
+    use crate::types::pattern::PatternItem;
+    use crate::types::value::Value;
+
+    fn b(binding: &str) -> Function {
+        Function::Value(PatternItem::Binding(binding.to_string()))
+    }
+
+    fn n(number: f64) -> Function {
+        Function::Value(PatternItem::Value(Value::Number(number)))
+    }
+
+    fn op(function: fn(Box<Function>, Box<Function>) -> Function, f1: Function, f2: Function) -> Function {
+        function(Box::new(f1), Box::new(f2))
+    }
+
+    fn solve(function: &Function, binding: &str) -> Option<String> {
+        function.solve_for(binding, b("r")).map(|f| f.to_string())
+    }
+
+    #[test]
+    fn solve_for_binding_on_the_left() {
+        assert_eq!(solve(&op(Function::Add, b("x"), b("y")), "x").as_deref(), Some("(- r: y:)"));
+        assert_eq!(solve(&op(Function::Sub, b("x"), b("y")), "x").as_deref(), Some("(+ r: y:)"));
+        assert_eq!(solve(&op(Function::Mul, b("x"), b("y")), "x").as_deref(), Some("(/ r: y:)"));
+        assert_eq!(solve(&op(Function::Div, b("x"), b("y")), "x").as_deref(), Some("(* r: y:)"));
+    }
+
+    #[test]
+    fn solve_for_binding_on_the_right() {
+        assert_eq!(solve(&op(Function::Add, b("y"), b("x")), "x").as_deref(), Some("(- r: y:)"));
+        assert_eq!(solve(&op(Function::Sub, b("y"), b("x")), "x").as_deref(), Some("(- y: r:)"));
+        assert_eq!(solve(&op(Function::Mul, b("y"), b("x")), "x").as_deref(), Some("(/ r: y:)"));
+        assert_eq!(solve(&op(Function::Div, b("y"), b("x")), "x").as_deref(), Some("(/ y: r:)"));
+    }
+
+    #[test]
+    fn solve_for_nested_binding() {
+        // r = p + d * 3, so d = (r - p) / 3
+        let function = op(Function::Add, b("p"), op(Function::Mul, b("d"), n(3.0)));
+        let solved = function.solve_for("d", b("r")).unwrap();
+        assert_eq!(solved.to_string(), "(/ (- r: p:) 3)");
+
+        let bindings = HashMap::from([("p".to_string(), Value::Number(1.0)), ("d".to_string(), Value::Number(2.0))]);
+        let result = function.evaluate(&bindings).unwrap();
+        assert_eq!(solved.evaluate(&HashMap::from([("r".to_string(), result), ("p".to_string(), Value::Number(1.0))])), Some(Value::Number(2.0)));
+    }
+
+    #[test]
+    fn solve_for_fails_unless_the_binding_appears_once() {
+        assert_eq!(solve(&b("x"), "x").as_deref(), Some("r:"));
+        assert_eq!(solve(&op(Function::Add, b("x"), b("x")), "x"), None);
+        assert_eq!(solve(&op(Function::Sub, op(Function::Mul, b("x"), b("y")), b("x")), "x"), None);
+        assert_eq!(solve(&op(Function::Mul, op(Function::Div, b("x"), b("x")), b("y")), "x"), None);
+        assert_eq!(solve(&op(Function::Add, b("y"), n(1.0)), "x"), None);
+    }
+
+    #[test]
+    fn fitted_constant_is_replaced() {
+        let function = op(Function::Add, b("p"), op(Function::Mul, b("d"), n(3.0)));
+        assert_eq!(function.fitted_constant(), Some(&Value::Number(3.0)));
+        let replaced = function.with_fitted_constant(&PatternItem::Value(Value::UncertainNumber(3.1, 0.1)));
+        assert_eq!(replaced.to_string(), "(+ p: (* d: (uncertain 3.1 0.1)))");
+        assert_eq!(replaced.with_fitted_constant(&PatternItem::Binding("k".to_string())).to_string(), "(+ p: (* d: k:))");
+
+        // With no or several constants there is no single fitted constant
+        assert_eq!(op(Function::Add, b("p"), b("d")).fitted_constant(), None);
+        assert_eq!(op(Function::Add, n(1.0), n(2.0)).fitted_constant(), None);
+    }
+}
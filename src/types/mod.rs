@@ -5,7 +5,7 @@ use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use crate::runtime::pattern_matching::{bind_values_to_pattern, compare_pattern_items, compare_patterns, extract_bindings_from_patterns, fill_in_pattern_with_bindings, PatternMatchResult};
 use crate::types::pattern::{bindings_in_pattern, Pattern, PatternItem};
-use crate::types::runtime::RuntimeCommand;
+use crate::types::runtime::{RuntimeCommand, SystemTime};
 use crate::types::value::Value;
 
 pub mod runtime;
@@ -16,7 +16,7 @@ pub mod functions;
 pub mod value;
 
 // Time is stored in milliseconds
-type Time = u64;
+pub type Time = u64;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Command {
@@ -127,10 +127,10 @@ impl MatchesFact<MkVal> for Fact<MkVal> {
 impl<T> Display for Fact<T> where T: Clone + Display {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if !self.anti {
-            write!(f, "(fact {} : :)", self.pattern)
+            write!(f, "(fact {} {} {})", self.pattern, self.time_range.from, self.time_range.to)
         }
         else {
-            write!(f, "(|fact {} : :)", self.pattern)
+            write!(f, "(|fact {} {} {})", self.pattern, self.time_range.from, self.time_range.to)
         }
     }
 }
@@ -201,9 +201,11 @@ impl MkVal {
 
 impl Display for MkVal {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Assumptions are not part of Replicode, but need their own name so they can be parsed back
         write!(
             f,
-            "(mk.val {} {} {})",
+            "({} {} {} {})",
+            if self.assumption { "mk.assumption" } else { "mk.val" },
             &self.entity_id,
             &self.var_name,
             &self.value,
@@ -227,6 +229,52 @@ impl TimePatternRange {
     pub fn wildcard() -> TimePatternRange {
         TimePatternRange::new(TimePatternValue::Any, TimePatternValue::Any)
     }
+
+    pub fn from_system_time(time: &SystemTime) -> TimePatternRange {
+        let (from, to) = match time {
+            SystemTime::Exact(t) => (*t, *t),
+            SystemTime::Range(from, to) => (*from, *to),
+        };
+        TimePatternRange::new(TimePatternValue::Time(from), TimePatternValue::Time(to))
+    }
+
+    pub fn is_wildcard(&self) -> bool {
+        self.from == TimePatternValue::Any && self.to == TimePatternValue::Any
+    }
+
+    pub fn bindings(&self) -> Vec<String> {
+        [&self.from, &self.to]
+            .into_iter()
+            .filter_map(|t| match t {
+                TimePatternValue::Binding(b) => Some(b.clone()),
+                _ => None,
+            })
+            .unique()
+            .collect()
+    }
+
+    /// Replace bound time bindings with their times
+    pub fn filled_in(&self, bindings: &HashMap<String, Value>) -> TimePatternRange {
+        TimePatternRange::new(self.from.filled_in(bindings), self.to.filled_in(bindings))
+    }
+
+    /// Bind the time bindings of this range to the times in `other`, usually the range of an input fact or a goal
+    pub fn extract_bindings(&self, other: &TimePatternRange) -> HashMap<String, Value> {
+        [(&self.from, &other.from), (&self.to, &other.to)]
+            .into_iter()
+            .filter_map(|(pattern, time)| match (pattern, time) {
+                (TimePatternValue::Binding(b), TimePatternValue::Time(t)) => Some((b.clone(), time_to_value(*t))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Check if the two ranges overlap, unbound ends are open
+    pub fn overlaps(&self, other: &TimePatternRange, bindings: &HashMap<String, Value>) -> bool {
+        let start = self.from.resolve(bindings).unwrap_or(0).max(other.from.resolve(bindings).unwrap_or(0));
+        let end = self.to.resolve(bindings).unwrap_or(Time::MAX).min(other.to.resolve(bindings).unwrap_or(Time::MAX));
+        start <= end
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -236,6 +284,46 @@ pub enum TimePatternValue {
     Binding(String)
 }
 
+impl TimePatternValue {
+    /// The time this stands for, `None` for wildcards and unbound bindings
+    pub fn resolve(&self, bindings: &HashMap<String, Value>) -> Option<Time> {
+        match self {
+            TimePatternValue::Time(t) => Some(*t),
+            TimePatternValue::Any => None,
+            TimePatternValue::Binding(b) => bindings.get(b).and_then(value_to_time),
+        }
+    }
+
+    pub fn filled_in(&self, bindings: &HashMap<String, Value>) -> TimePatternValue {
+        match self.resolve(bindings) {
+            Some(t) => TimePatternValue::Time(t),
+            None => self.clone(),
+        }
+    }
+}
+
+/// Times are kept in bindings as numbers, so guards can compute with them
+pub fn time_to_value(time: Time) -> Value {
+    Value::Number(time as f64)
+}
+
+pub fn value_to_time(value: &Value) -> Option<Time> {
+    match value {
+        Value::Number(t) | Value::ConstantNumber(t) | Value::UncertainNumber(t, _) => Some(t.round().max(0.0) as Time),
+        _ => None,
+    }
+}
+
+impl Display for TimePatternValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimePatternValue::Time(t) => write!(f, "{t}"),
+            TimePatternValue::Any => write!(f, ":"),
+            TimePatternValue::Binding(b) => write!(f, "{b}:"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct EntityVariableKey {
     pub entity_id: String,
@@ -248,10 +336,103 @@ impl EntityVariableKey {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Goal {
-    name: String,
-    time_range: TimePatternRange,
+    pub name: String,
+    pub facts: Vec<Fact<MkVal>>,
+    /// Active goals with higher priority are planned for first
+    pub priority: i32,
+    /// The goal is missed if it has not been achieved by this time
+    pub deadline: Option<Time>,
+    pub policy: GoalPolicy,
+    pub status: GoalStatus,
+    /// Number of times planning found no way to achieve the goal since it became active
+    pub failed_attempts: usize,
+    /// The goal waits until the goal with this name is achieved before it becomes active
+    pub after: Option<String>,
+}
+
+impl Goal {
+    /// Active goal that is retried until it is achieved. The deadline is the earliest end of the time ranges of the facts
+    pub fn new(name: &str, facts: Vec<Fact<MkVal>>, priority: i32) -> Goal {
+        let deadline = facts.iter().filter_map(|f| f.time_range.to.resolve(&HashMap::new())).min();
+        Goal {
+            name: name.to_string(),
+            facts,
+            priority,
+            deadline,
+            policy: GoalPolicy::default(),
+            status: GoalStatus::Active,
+            failed_attempts: 0,
+            after: None,
+        }
+    }
+
+    /// Goals that are pursued one after the other, the first one has the highest priority.
+    /// Each goal waits until the one before it is achieved
+    pub fn sequence(goals: Vec<Vec<Fact<MkVal>>>) -> Vec<Goal> {
+        let count = goals.len() as i32;
+        goals
+            .into_iter()
+            .enumerate()
+            .map(|(i, facts)| {
+                let mut goal = Goal::new(&format!("goal_{i}"), facts, count - i as i32);
+                if i > 0 {
+                    goal.after = Some(format!("goal_{}", i - 1));
+                    goal.status = GoalStatus::Waiting;
+                }
+                goal
+            })
+            .collect()
+    }
+
+    /// True if the goal can't become active anymore
+    pub fn is_done(&self) -> bool {
+        matches!(self.status, GoalStatus::Achieved | GoalStatus::Missed | GoalStatus::Abandoned)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.status == GoalStatus::Active
+    }
+}
+
+impl Display for Goal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let deadline = self.deadline.map(|d| d.to_string()).unwrap_or(":".to_string());
+        write!(f, "{}:(goal {} {} {deadline} {})", self.name, self.facts.iter().join(" "), self.priority, self.policy)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum GoalPolicy {
+    /// Keep planning for the goal until it is achieved or its deadline passes
+    #[default]
+    Retry,
+    /// Abandon the goal when planning has failed this many times
+    AbandonAfter(usize),
+    /// Recurring goal that becomes active again whenever it stops holding, like keeping the hand empty
+    Drive,
+}
+
+impl Display for GoalPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GoalPolicy::Retry => write!(f, "retry"),
+            GoalPolicy::AbandonAfter(attempts) => write!(f, "abandon {attempts}"),
+            GoalPolicy::Drive => write!(f, "drive"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum GoalStatus {
+    /// Waiting for the goal it comes after to be achieved
+    Waiting,
+    Active,
+    /// Achieved goals are done, except for drives which only wait until they no longer hold
+    Achieved,
+    Missed,
+    Abandoned,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -333,4 +514,34 @@ impl Display for EntityDeclaration {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "(mk.val {}: essence {})", &self.binding, &self.class)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::types::value::Value;
+    use crate::types::{TimePatternRange, TimePatternValue};
+
+    fn range(from: TimePatternValue, to: TimePatternValue) -> TimePatternRange {
+        TimePatternRange::new(from, to)
+    }
+
+    #[test]
+    fn ranges_overlap_when_they_share_a_time() {
+        use TimePatternValue::{Any, Binding, Time};
+        let no_bindings = HashMap::new();
+        assert!(range(Time(100), Time(200)).overlaps(&range(Time(150), Time(300)), &no_bindings));
+        // Ends are included
+        assert!(range(Time(100), Time(200)).overlaps(&range(Time(200), Time(300)), &no_bindings));
+        assert!(!range(Time(100), Time(200)).overlaps(&range(Time(201), Time(300)), &no_bindings));
+        assert!(!range(Time(250), Time(300)).overlaps(&range(Time(100), Time(200)), &no_bindings));
+        // Wildcards and unbound bindings are open ends
+        assert!(TimePatternRange::wildcard().overlaps(&range(Time(100), Time(200)), &no_bindings));
+        assert!(range(Any, Time(100)).overlaps(&range(Time(50), Any), &no_bindings));
+        assert!(range(Binding("T0".to_string()), Binding("T1".to_string())).overlaps(&range(Time(100), Time(200)), &no_bindings));
+
+        let bindings = HashMap::from([("T0".to_string(), Value::Number(300.0)), ("T1".to_string(), Value::Number(400.0))]);
+        let bound = range(Binding("T0".to_string()), Binding("T1".to_string()));
+        assert!(!bound.overlaps(&range(Time(100), Time(200)), &bindings));
+        assert!(bound.overlaps(&range(Time(100), Time(300)), &bindings));
+    }
+}
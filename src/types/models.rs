@@ -4,9 +4,9 @@ use crate::types::functions::Function;
 use crate::types::pattern::{
     bindings_in_pattern, Pattern,
 };
-use crate::types::runtime::{System, SystemState};
+use crate::types::runtime::{System, SystemState, SystemTime};
 use crate::types::value::Value;
-use crate::types::{Command, EntityVariableKey, Fact, MkVal, PatternItem, TimePatternRange};
+use crate::types::{time_to_value, value_to_time, Command, EntityVariableKey, Fact, MkVal, PatternItem, Time, TimePatternRange, TimePatternValue};
 use itertools::Itertools;
 use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
@@ -14,6 +14,11 @@ use std::hash::{Hash, Hasher};
 use tap::Tap;
 use serde::{Deserialize, Serialize};
 use crate::runtime::utils::{compute_assumptions, compute_instantiated_states, compute_state_predictions};
+use crate::utils::math::running_mean_and_std;
+
+/// Time bindings of learned models, the lhs happens between `T0` and `T1` and the rhs between `T2` and `T3`
+pub const LHS_TIME_BINDINGS: [&str; 2] = ["T0", "T1"];
+pub const RHS_TIME_BINDINGS: [&str; 2] = ["T2", "T3"];
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Mdl {
@@ -49,12 +54,136 @@ impl Mdl {
             .chain(right_params)
             // Bindings assigned to function results cannot be passes as parameters
             .filter(|b| !self.forward_computed.iter().any(|(k, _)| k == b))
+            // Times depend on when the model is instantiated, so they are not part of the imdl
+            .filter(|b| !self.is_time_binding(b))
             .unique()
             .collect()
     }
 
     pub fn fwd_guard_params(&self) -> Vec<&str> {
-        self.forward_computed.iter().map(|(p, _)| p.as_str()).collect()
+        self.forward_computed
+            .iter()
+            .map(|(p, _)| p.as_str())
+            .filter(|p| !self.is_time_binding(p))
+            .collect()
+    }
+
+    /// Bindings in the time ranges of lhs and rhs
+    pub fn time_bindings(&self) -> Vec<String> {
+        self.left.time_range.bindings().into_iter().chain(self.right.time_range.bindings()).unique().collect()
+    }
+
+    pub fn is_time_binding(&self, binding: &str) -> bool {
+        self.time_bindings().iter().any(|b| b == binding)
+    }
+
+    /// Earliest and latest delay in milliseconds between the lhs and the rhs of the model,
+    /// read from the forward guards on the rhs time bindings. `None` if the model has no timing
+    pub fn delay_window(&self) -> Option<(Time, Time)> {
+        let TimePatternValue::Binding(lhs_from) = &self.left.time_range.from else {
+            return None;
+        };
+        let delay_to = |rhs_time: &TimePatternValue| {
+            let TimePatternValue::Binding(rhs_time) = rhs_time else {
+                return None;
+            };
+            match self.forward_computed.iter().find(|(b, _)| b == rhs_time)? {
+                (_, Function::Add(time, delay)) if **time == Function::Value(PatternItem::Binding(lhs_from.clone())) => {
+                    value_to_time(&delay.evaluate(&HashMap::new())?)
+                }
+                _ => None,
+            }
+        };
+
+        Some((delay_to(&self.right.time_range.from)?, delay_to(&self.right.time_range.to)?))
+    }
+
+    /// Make the rhs happen between `earliest` and `latest` milliseconds after the lhs.
+    /// The lhs gets the time bindings `T0` `T1` and the rhs `T2` `T3`, with guards between them in both directions
+    pub fn set_delay_window(&mut self, earliest: Time, latest: Time) {
+        let [t0, t1] = LHS_TIME_BINDINGS.map(String::from);
+        let [t2, t3] = RHS_TIME_BINDINGS.map(String::from);
+        let time_bindings = self.time_bindings();
+        self.forward_computed.retain(|(b, _)| !time_bindings.contains(b));
+        self.backward_computed.retain(|(b, _)| !time_bindings.contains(b));
+        self.left.time_range = TimePatternRange::new(TimePatternValue::Binding(t0.clone()), TimePatternValue::Binding(t1.clone()));
+        self.right.time_range = TimePatternRange::new(TimePatternValue::Binding(t2.clone()), TimePatternValue::Binding(t3.clone()));
+
+        let binding = |b: &str| Box::new(Function::Value(PatternItem::Binding(b.to_string())));
+        let delay = |d: Time| Box::new(Function::Value(PatternItem::Value(time_to_value(d))));
+        self.forward_computed.push((t2.clone(), Function::Add(binding(&t0), delay(earliest))));
+        self.forward_computed.push((t3.clone(), Function::Add(binding(&t0), delay(latest))));
+        // The lhs has to happen early enough for the rhs to be in the window and late enough for it to not happen before
+        self.backward_computed.push((t0, Function::Sub(binding(&t2), delay(latest))));
+        self.backward_computed.push((t1, Function::Sub(binding(&t3), delay(earliest))));
+    }
+
+    /// Widen the delay window to include an observed delay, or start one if the model has no timing
+    pub fn observe_delay(&mut self, delay: Time) {
+        let (earliest, latest) = self.delay_window().unwrap_or((delay, delay));
+        self.set_delay_window(earliest.min(delay), latest.max(delay));
+    }
+
+    /// Refit the constants of the value guards to an observation of the model, where `bindings` has the inputs
+    /// of the guards and the observed values of their results
+    pub fn observe_guard_constants(&mut self, bindings: &HashMap<String, Value>) {
+        let constant_binding = "FITTED_CONSTANT";
+        let samples = self.forward_computed
+            .iter()
+            .filter(|(b, f)| !self.is_time_binding(b) && f.fitted_constant().is_some())
+            .filter_map(|(b, f)| {
+                let constant = f
+                    .with_fitted_constant(&PatternItem::Binding(constant_binding.to_string()))
+                    .solve_for(constant_binding, Function::Value(PatternItem::Binding(b.clone())))?;
+                Some((b.clone(), constant.evaluate(bindings)?))
+            })
+            .collect_vec();
+        for (binding, sample) in samples {
+            self.fit_guard_constant(&binding, &sample);
+        }
+    }
+
+    /// Refit the constants of the value guards to those of an equivalent model learned from a new observation.
+    /// The value guards of both models have to be in the same order
+    pub fn merge_guard_constants(&mut self, other: &Mdl) {
+        let value_guards = |model: &Mdl| model.forward_computed
+            .iter()
+            .filter(|(b, _)| !model.is_time_binding(b))
+            .cloned()
+            .collect_vec();
+        for ((binding, _), (_, other_function)) in value_guards(self).iter().zip(value_guards(other)) {
+            if let Some(sample) = other_function.fitted_constant() {
+                self.fit_guard_constant(binding, sample);
+            }
+        }
+    }
+
+    /// Add a sample to the constant of the forward guard of the binding, and the backward guards using the same
+    /// constant. The constant is the mean and standard deviation of all samples, one for each success of the model
+    fn fit_guard_constant(&mut self, binding: &str, sample: &Value) {
+        let Some(constant) = self.forward_computed
+            .iter()
+            .find(|(b, _)| b == binding)
+            .and_then(|(_, f)| f.fitted_constant())
+            .cloned() else {
+            return;
+        };
+        let (Some((mean, std)), Some((sample, _))) = (constant.mean_and_std(), sample.mean_and_std()) else {
+            return;
+        };
+        let (mean, std) = running_mean_and_std(mean, std, self.success_count, sample);
+        let fitted_constant = PatternItem::Value(Value::from_mean_and_std(mean, std));
+
+        for (b, f) in &mut self.forward_computed {
+            if b == binding {
+                *f = f.with_fitted_constant(&fitted_constant);
+            }
+        }
+        for (_, f) in &mut self.backward_computed {
+            if f.binding_params().iter().any(|b| b == binding) && f.fitted_constant() == Some(&constant) {
+                *f = f.with_fitted_constant(&fitted_constant);
+            }
+        }
     }
 
     /// Attempt to instantiate this model using the lhs icst instruction
@@ -522,7 +651,7 @@ impl Display for IMdl {
             "(imdl {} [{}] | {})",
             self.model_id,
             self.params.iter().map(|p| p.to_string()).join(" "),
-            self.fwd_guard_bindings.iter().map(|(b, v)| format!("{b}: {v}")).join(", ")
+            self.fwd_guard_bindings.iter().sorted_by_key(|(b, _)| *b).map(|(b, v)| format!("{b}: {v}")).join(", ")
         )?;
 
         Ok(())
@@ -562,6 +691,7 @@ impl BoundModel {
             return None;
         };
         // Combine bindings from input facts and those that were already in the model
+        bindings.extend(self.model.left.time_range.extract_bindings(&input.time_range));
         bindings.extend(self.bindings.clone());
         let mut model = BoundModel {
             model: self.model.clone(),
@@ -575,7 +705,11 @@ impl BoundModel {
             return None;
         }
 
-        Some(self.model.right.with_pattern(model.filled_in_rhs()))
+        Some(Fact {
+            pattern: model.filled_in_rhs(),
+            time_range: self.model.right.time_range.filled_in(&model.bindings),
+            anti: self.model.right.anti,
+        })
     }
 
     pub fn abduce(&self, input: &Fact<MdlRightValue>, system: &System) -> Option<AbductionResult> {
@@ -587,25 +721,33 @@ impl BoundModel {
             return None;
         };
         // Combine bindings from input facts and those that were already in the model
+        bindings.extend(self.model.right.time_range.extract_bindings(&input.time_range));
         bindings.extend(self.bindings.clone());
         let mut model = BoundModel {
             model: self.model.clone(),
             bindings
         };
         model.compute_backward_bindings();
+        // When the lhs has to happen for the rhs to happen in the time of the input
+        let lhs_time_range = self.model.left.time_range.filled_in(&model.bindings);
 
         match &self.model.left.pattern {
             MdlLeftValue::ICst(icst) => {
                 let mut icst = icst.clone();
                 icst.params = fill_in_pattern_with_bindings(icst.params, &model.bindings);
                 let subgoal_cst = icst.expand_cst(&system);
-                Some(AbductionResult::SubGoal(subgoal_cst.facts, Some(icst.cst_id.clone()), model.imdl_for_model(), Some(icst)))
+                let subgoals = subgoal_cst.facts
+                    .into_iter()
+                    .map(|f| if f.time_range.is_wildcard() { Fact { time_range: lhs_time_range.clone(), ..f } } else { f })
+                    .collect();
+                Some(AbductionResult::SubGoal(subgoals, Some(icst.cst_id.clone()), model.imdl_for_model(), Some(icst)))
             }
             MdlLeftValue::MkVal(mk_val) => {
                 let mut mk_val = mk_val.clone();
                 mk_val.entity_id.insert_binding_value(&model.bindings);
                 mk_val.value.insert_binding_values(&model.bindings);
-                Some(AbductionResult::SubGoal(vec![self.model.left.with_pattern(mk_val)], None, model.imdl_for_model(), None))
+                let subgoal = Fact { pattern: mk_val, time_range: lhs_time_range, anti: self.model.left.anti };
+                Some(AbductionResult::SubGoal(vec![subgoal], None, model.imdl_for_model(), None))
             }
             _ => {
                 Some(AbductionResult::IMdl(model.imdl_for_model()))
@@ -709,6 +851,14 @@ impl BoundModel {
             ),
             predicted_value,
         );
+        // The lhs happens in the time of the state, the new state is in the time window of the rhs if the model has one
+        let mut timed_model = self.clone();
+        timed_model.bindings.extend(self.model.left.time_range.extract_bindings(&TimePatternRange::from_system_time(&state.time)));
+        timed_model.compute_forward_bindings();
+        let rhs_time_range = &self.model.right.time_range;
+        if let (Some(from), Some(to)) = (rhs_time_range.from.resolve(&timed_model.bindings), rhs_time_range.to.resolve(&timed_model.bindings)) {
+            new_state.time = if from == to { SystemTime::Exact(from) } else { SystemTime::Range(from, to) };
+        }
         new_state.instansiated_csts = compute_instantiated_states(system, &new_state);
         /*new_state
             .variables
@@ -792,3 +942,106 @@ impl BoundModel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::replicode::parse_into_system;
+    use crate::types::models::{AbductionResult, MdlRightValue};
+    use crate::types::runtime::{System, SystemTime};
+    use crate::types::value::Value;
+    use crate::types::{EntityPatternValue, EntityVariableKey, Fact, MkVal, PatternItem, TimePatternRange, TimePatternValue};
+
+    /// A robot that moves one position with each step, and a lamp that lights up some time after it is on
+    const MODELS: &str = "
+(mk.val r essence robot)
+(mk.val r pos 0)
+
+mdl_step:(mdl [] []
+  (fact (cmd step [r: ]) : :)
+  (fact (mk.val r: pos np:) : :)
+[]
+  np:(+ p: 1)
+[]
+  p:(- np: 1)
+); Success count: 5, Failure count: 0
+
+mdl_lit:(mdl [] []
+  (fact (mk.val l: on 1) : :)
+  (fact (mk.val l: lit 1) : :)
+|[]
+|[]); Success count: 5, Failure count: 0
+";
+
+    fn system() -> System {
+        let mut system = System::new();
+        parse_into_system(MODELS, &mut system).unwrap();
+        system
+    }
+
+    fn times(from: u64, to: u64) -> TimePatternRange {
+        TimePatternRange::new(TimePatternValue::Time(from), TimePatternValue::Time(to))
+    }
+
+    #[test]
+    fn delay_window_widens_to_the_observed_delays() {
+        let mut model = system().models["mdl_step"].clone();
+        assert_eq!(model.delay_window(), None);
+
+        model.observe_delay(100);
+        assert_eq!(model.delay_window(), Some((100, 100)));
+        model.observe_delay(80);
+        model.observe_delay(150);
+        model.observe_delay(120);
+        assert_eq!(model.delay_window(), Some((80, 150)));
+        // The time guards are replaced, not added to each time
+        assert_eq!(model.forward_computed.len(), 3);
+        assert_eq!(model.backward_computed.len(), 3);
+        assert_eq!(model.fwd_guard_params(), ["np"]);
+    }
+
+    #[test]
+    fn prediction_is_in_the_delay_window() {
+        let mut system = system();
+        system.models.get_mut("mdl_step").unwrap().set_delay_window(80, 150);
+        let mut state = system.current_state.clone();
+        state.time = SystemTime::Exact(1000);
+        let mut model = system.models["mdl_step"].as_bound_model();
+        model.bindings = HashMap::from([("r".to_string(), Value::EntityId("r".to_string())), ("p".to_string(), Value::Number(0.0))]);
+        model.compute_forward_bindings();
+
+        let predicted = model.predict_state_change(&state, &Vec::new(), &Vec::new(), &system).unwrap();
+        assert_eq!(predicted.variables[&EntityVariableKey::new("r", "pos")], Value::Number(1.0));
+        assert_eq!(predicted.time, SystemTime::Range(1080, 1150));
+
+        // Without timing the prediction keeps the time of the state
+        let mut model = system.models["mdl_step"].as_bound_model();
+        model.model.left.time_range = TimePatternRange::wildcard();
+        model.model.right.time_range = TimePatternRange::wildcard();
+        model.bindings = HashMap::from([("r".to_string(), Value::EntityId("r".to_string())), ("p".to_string(), Value::Number(0.0))]);
+        model.compute_forward_bindings();
+        assert_eq!(model.predict_state_change(&state, &Vec::new(), &Vec::new(), &system).unwrap().time, SystemTime::Exact(1000));
+    }
+
+    /// For the lamp to be lit between 2000 and 2100, it has to be on between 2100 - 150 and 2000 - 80
+    #[test]
+    fn abduction_moves_the_lhs_back_by_the_delay() {
+        let mut system = system();
+        system.models.get_mut("mdl_lit").unwrap().set_delay_window(80, 150);
+        let goal = Fact::new(MdlRightValue::MkVal(MkVal {
+            entity_id: EntityPatternValue::EntityId("l".to_string()),
+            var_name: "lit".to_string(),
+            value: PatternItem::Value(Value::Number(1.0)),
+            assumption: false,
+        }), times(2000, 2100));
+
+        let mut model = system.models["mdl_lit"].as_bound_model();
+        model.bindings.insert("l".to_string(), Value::EntityId("l".to_string()));
+        let Some(AbductionResult::SubGoal(subgoals, ..)) = model.abduce(&goal, &system) else {
+            panic!("Expected a sub goal");
+        };
+        assert_eq!(subgoals.len(), 1);
+        assert_eq!(subgoals[0].pattern.to_string(), "(mk.val l on 1)");
+        assert_eq!(subgoals[0].time_range, times(1850, 2020));
+    }
+}
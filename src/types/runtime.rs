@@ -2,10 +2,11 @@ use crate::types::cst::BoundCst;
 use crate::types::pattern::PatternItem;
 use crate::types::value::Value;
 use crate::types::{
-    cst::Cst, models::Mdl, Command, EntityPatternValue, EntityVariableKey, Fact, MkVal, Time,
-    TimePatternRange, TimePatternValue,
+    cst::Cst, models::Mdl, Command, EntityPatternValue, EntityVariableKey, Goal, Time,
+    TimePatternRange,
 };
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 
@@ -14,9 +15,14 @@ pub struct System {
     pub models: HashMap<String, Mdl>,
     pub csts: HashMap<String, Cst>,
     pub entities_in_classes: HashMap<String, Vec<String>>,
-    pub current_goal_index: usize,
-    pub goals: Vec<Vec<Fact<MkVal>>>,
+    pub goals: Vec<Goal>,
     pub babble_command: Vec<RuntimeCommand>,
+    /// Time each model was last used to make a prediction, used to decide which models to forget
+    pub model_last_used: HashMap<String, Time>,
+    /// Where each learned model and cst came from
+    pub provenance: HashMap<String, Provenance>,
+    /// Number used in the next generated model or cst id, it only increases so removed ids are never reused
+    pub next_id: usize,
 }
 
 impl System {
@@ -30,9 +36,33 @@ impl System {
             models: HashMap::new(),
             csts: HashMap::new(),
             entities_in_classes: HashMap::new(),
-            current_goal_index: 0,
             goals: Vec::new(),
             babble_command: Vec::new(),
+            model_last_used: HashMap::new(),
+            provenance: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Generate an id that is not used by any model or cst, and will not be generated again
+    pub fn generate_id(&mut self, prefix: &str) -> String {
+        loop {
+            let id = format!("{prefix}_{}", self.next_id);
+            self.next_id += 1;
+            if !self.models.contains_key(&id) && !self.csts.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Move the provenance of a learned model or cst into the provenance of the one it was merged into.
+    /// Seeded models and csts have no provenance, so nothing is recorded for merges into them
+    pub fn record_merge(&mut self, existing_id: &str, merged_id: &str) {
+        let Some(merged) = self.provenance.remove(merged_id) else {
+            return;
+        };
+        if let Some(provenance) = self.provenance.get_mut(existing_id) {
+            provenance.merged_from.push(MergedProvenance { id: merged_id.to_string(), provenance: merged });
         }
     }
 
@@ -94,30 +124,49 @@ pub enum SystemTime {
 }
 
 impl SystemTime {
-    // When comparing two ranges, it is considered a match even if only a part of the ranges overlap
-    pub fn matches_pattern(&self, pattern: &TimePatternRange) -> bool {
-        let (start, end) = match self {
-            SystemTime::Exact(t) => (*t, *t),
-            SystemTime::Range(t1, t2) => (*t1, *t2),
-        };
+    // When comparing two ranges, it is considered a match even if only a part of the ranges overlap.
+    // Time bindings in the pattern are looked up in `bindings`, unbound ones match any time
+    pub fn matches_pattern(&self, pattern: &TimePatternRange, bindings: &HashMap<String, Value>) -> bool {
+        TimePatternRange::from_system_time(self).overlaps(pattern, bindings)
+    }
 
-        let pattern_start = match &pattern.from {
-            TimePatternValue::Time(t) => *t,
-            TimePatternValue::Any => 0,
-            TimePatternValue::Binding(_) => panic!("Bindings not allowed when comparing time"),
-        };
-        let pattern_end = match &pattern.from {
-            TimePatternValue::Time(t) => *t,
-            TimePatternValue::Any => u64::MAX,
-            TimePatternValue::Binding(_) => panic!("Bindings not allowed when comparing time"),
-        };
-        let pattern_range = pattern_start..pattern_end;
+    /// Latest time of this time, used to check if deadlines have passed
+    pub fn end(&self) -> Time {
+        match self {
+            SystemTime::Exact(t) | SystemTime::Range(_, t) => *t,
+        }
+    }
+}
+
+/// Where a learned model or cst came from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Step of the agent in which it was learned
+    pub step: usize,
+    /// Command whose outcome it was learned from
+    pub command: RuntimeCommand,
+    /// Models or csts that were learned later and merged into it
+    pub merged_from: Vec<MergedProvenance>,
+}
 
-        pattern_range.contains(&start) || pattern_range.contains(&end)
+impl Provenance {
+    pub fn new(step: usize, command: &RuntimeCommand) -> Provenance {
+        Provenance {
+            step,
+            command: command.clone(),
+            merged_from: Vec::new(),
+        }
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// Model or cst that was merged into another one, and where it came from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MergedProvenance {
+    pub id: String,
+    pub provenance: Provenance,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct RuntimeCommand {
     pub name: String,
     pub entity_id: String,
@@ -49,6 +49,25 @@ impl Value {
         }
     }
 
+    /// Mean and standard deviation of a number, numbers without uncertainty have a standard deviation of 0
+    pub fn mean_and_std(&self) -> Option<(f64, f64)> {
+        match self {
+            Value::Number(n) | Value::ConstantNumber(n) => Some((*n, 0.0)),
+            Value::UncertainNumber(m, s) => Some((*m, *s)),
+            _ => None,
+        }
+    }
+
+    /// Number with the given uncertainty, or a plain number if there is none
+    pub fn from_mean_and_std(mean: f64, std: f64) -> Value {
+        if std > 0.0 {
+            Value::UncertainNumber(mean, std)
+        }
+        else {
+            Value::Number(mean)
+        }
+    }
+
     pub fn can_do_numeric_op(&self, other: &Value) -> bool {
         match (self, other) {
             (Value::Number(_) | Value::ConstantNumber(_) | Value::UncertainNumber(_, _), Value::Number(_) | Value::ConstantNumber(_) | Value::UncertainNumber(_, _)) => true,
@@ -157,8 +176,12 @@ impl Mul<Value> for Value {
                     .map(|(e1, e2)| e1 * e2)
                     .collect(),
             ),
-            (Value::UncertainNumber(m, s), (Value::Number(n) | Value::ConstantNumber(n))) => Value::UncertainNumber(m * n, s),
-            ((Value::Number(n) | Value::ConstantNumber(n)), Value::UncertainNumber(m, s)) => Value::UncertainNumber(n * m, s),
+            // Uncertainties are propagated assuming the values are independent
+            (Value::UncertainNumber(m, s), (Value::Number(n) | Value::ConstantNumber(n))) => Value::UncertainNumber(m * n, s * n.abs()),
+            ((Value::Number(n) | Value::ConstantNumber(n)), Value::UncertainNumber(m, s)) => Value::UncertainNumber(n * m, s * n.abs()),
+            (Value::UncertainNumber(m1, s1), Value::UncertainNumber(m2, s2)) => {
+                Value::UncertainNumber(m1 * m2, (m2 * s1).hypot(m1 * s2))
+            }
             _ => panic!("Value does not support multiplication"),
         }
     }
@@ -180,8 +203,13 @@ impl Div<Value> for Value {
                     .map(|(e1, e2)| e1 / e2)
                     .collect(),
             ),
-            (Value::UncertainNumber(m, s), (Value::Number(n) | Value::ConstantNumber(n))) => Value::UncertainNumber(m / n, s),
-            ((Value::Number(n) | Value::ConstantNumber(n)), Value::UncertainNumber(m, s)) => Value::UncertainNumber(n / m, s),
+            // Uncertainties are propagated assuming the values are independent, the relative errors add up in quadrature.
+            // They are written without dividing by the numerator so a numerator of zero works
+            (Value::UncertainNumber(m, s), (Value::Number(n) | Value::ConstantNumber(n))) => Value::UncertainNumber(m / n, s / n.abs()),
+            ((Value::Number(n) | Value::ConstantNumber(n)), Value::UncertainNumber(m, s)) => Value::UncertainNumber(n / m, (n * s / (m * m)).abs()),
+            (Value::UncertainNumber(m1, s1), Value::UncertainNumber(m2, s2)) => {
+                Value::UncertainNumber(m1 / m2, (s1 / m2).hypot(m1 * s2 / (m2 * m2)))
+            }
             _ => panic!("Value does not support division"),
         }
     }
@@ -203,9 +231,9 @@ impl Hash for Value {
 impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", match self {
-            Value::Number(n) => format!("{n:.2}"),
-            Value::ConstantNumber(n) => n.to_string(),
-            Value::UncertainNumber(m, s) => format!("(uncertain {m:.2} {s})"),
+            Value::Number(n) => format!("{n}"),
+            Value::ConstantNumber(n) => format!("(const {n})"),
+            Value::UncertainNumber(m, s) => format!("(uncertain {m} {s})"),
             Value::String(s) => format!("\"{}\"", s.to_owned()),
             Value::Vec(v) => format!("[{}]", v.iter().map(|e| e.to_string()).join(" ")),
             Value::EntityId(id) => id.to_owned()
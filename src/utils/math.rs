@@ -2,4 +2,29 @@ use std::f64::consts::*;
 
 pub fn probability_density(expected: f64, mean: f64, std: f64) -> f64 {
     (1.0 / (std * (2.0 * PI).sqrt())) * E.powf((-1.0 / 2.0) * ((expected - mean) / std).powf(2.0))
-}
\ No newline at end of file
+}
+
+/// Probability that a normally distributed value is between `low` and `high`
+pub fn probability_in_range(mean: f64, std: f64, low: f64, high: f64) -> f64 {
+    (normal_cdf(high, mean, std) - normal_cdf(low, mean, std)).clamp(0.0, 1.0)
+}
+
+/// Add a sample to the mean and standard deviation of `count` earlier samples (Welford's algorithm)
+pub fn running_mean_and_std(mean: f64, std: f64, count: usize, sample: f64) -> (f64, f64) {
+    let new_count = (count + 1) as f64;
+    let new_mean = mean + (sample - mean) / new_count;
+    let squared_distances = std.powi(2) * count as f64 + (sample - mean) * (sample - new_mean);
+    (new_mean, (squared_distances / new_count).sqrt())
+}
+
+pub fn normal_cdf(x: f64, mean: f64, std: f64) -> f64 {
+    0.5 * (1.0 + erf((x - mean) / (std * SQRT_2)))
+}
+
+// Approximation from Abramowitz and Stegun (7.1.26), the error is below 1.5e-7
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let polynomial = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let y = 1.0 - polynomial * (-x * x).exp();
+    if x < 0.0 { -y } else { y }
+}
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 
 use std::ops::Deref;
-use std::rc::Rc;
+use std::sync::Arc;
 use itertools::Itertools;
 use piston_window::*;
 use piston_window::types::Color;
@@ -33,7 +33,7 @@ fn draw_text(
         .unwrap();
 }
 
-fn visualize_node(node: &Rc<ForwardChainNode>, index: usize, x: f64, y: f64, scroll_y: f64, depth: usize, selected_node: &Vec<usize>, context: &Context, graphics: &mut G2d, glyphs: &mut Glyphs) {
+fn visualize_node(node: &Arc<ForwardChainNode>, index: usize, x: f64, y: f64, scroll_y: f64, depth: usize, selected_node: &Vec<usize>, context: &Context, graphics: &mut G2d, glyphs: &mut Glyphs) {
     if y > context.get_view_size()[1] {
         return;
     }
@@ -63,17 +63,17 @@ fn visualize_node(node: &Rc<ForwardChainNode>, index: usize, x: f64, y: f64, scr
     }*/
 }
 
-fn sort_tree(tree: &Vec<Rc<ForwardChainNode>>, depth: usize) -> Vec<Rc<ForwardChainNode>> {
+fn sort_tree(tree: &Vec<Arc<ForwardChainNode>>, depth: usize) -> Vec<Arc<ForwardChainNode>> {
     if depth >= 5 {
         return vec![];
     }
-    tree.iter().sorted_by_key(|node| node.min_goal_depth).map(|node| Rc::new(ForwardChainNode {
+    tree.iter().sorted_by_key(|node| node.min_goal_depth).map(|node| Arc::new(ForwardChainNode {
         children: sort_tree(&node.children, depth + 1),
         ..node.deref().clone()
     })).collect()
 }
 
-pub fn visualize_forward_chaining(tree: &Vec<Rc<ForwardChainNode>>) {
+pub fn visualize_forward_chaining(tree: &Vec<Arc<ForwardChainNode>>) {
     let tree = sort_tree(tree, 0);
     let mut window: PistonWindow = WindowSettings::new("Forward chaining visualization", [800, 800])
         .exit_on_esc(true)
@@ -0,0 +1,101 @@
+//! Runs AERA over TCP against the mock controller binary, the way it runs against the robot controller
+
+use std::fs;
+use std::net::TcpListener;
+use std::process::Command;
+use std::time::Duration;
+use serde_json::{json, Value as JsonValue};
+use aera_exp::interfaces::tcp_interface::{TcpConfig, TcpInterface};
+use aera_exp::replicode::parse_into_system;
+use aera_exp::runtime::agent::{Agent, Termination};
+use aera_exp::runtime::RunOptions;
+use aera_exp::types::value::Value;
+use aera_exp::types::EntityVariableKey;
+
+/// A robot that moves by the given distance, with a goal it reaches with one move
+const SEED: &str = "
+(mk.val r essence robot)
+
+S_at:(cst [] []
+  (fact (mk.val r: essence robot) : :)
+  (fact (mk.val r: pos p:) : :)
+|[]
+|[]); Success count: 5, Failure count: 0
+
+mdl_move:(mdl [] []
+  (fact (cmd move [r: dp:]) : :)
+  (fact (mk.val r: pos np:) : :)
+[]
+  np:(+ p: dp:)
+[]
+  dp:(- np: p:)
+); Success count: 37, Failure count: 0
+
+mdl_move_req:(mdl [] []
+  (fact (icst S_at [r: p:]) : :)
+  (fact (imdl mdl_move [r: dp: p:] | ) : :)
+|[]
+|[]); Success count: 5, Failure count: 0
+
+goal_0:(goal (fact (mk.val r pos 3) : :) 1 : retry)
+";
+
+/// Port that nothing listens on, so AERA can listen on it
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[test]
+fn commands_are_planned_from_what_the_controller_sends() {
+    let port = free_port();
+    let dir = std::env::temp_dir().join(format!("aera_mock_controller_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config_path = dir.join("config.json");
+    let record_path = dir.join("commands.jsonl");
+    let config = json!({
+        "address": format!("127.0.0.1:{port}"),
+        "steps": 3,
+        "command_timeout_secs": 30,
+        "record": record_path,
+        "entities": ["r", "sys"],
+        "commands": [
+            { "name": "move", "entity": "r" },
+            { "name": "no_action", "entity": "sys", "dimensions": [0] }
+        ],
+        "variables": [
+            { "entity": "r", "name": "pos", "value": 0.0 }
+        ],
+        "effects": [
+            { "command": "move", "op": "add", "variable": "pos" }
+        ]
+    });
+    fs::write(&config_path, config.to_string()).unwrap();
+
+    let mut controller = Command::new(env!("CARGO_BIN_EXE_mock_controller")).arg(&config_path).spawn().unwrap();
+    let tcp = TcpInterface::connect(&TcpConfig {
+        port,
+        connect_timeout: Some(Duration::from_secs(30)),
+        read_timeout: Some(Duration::from_secs(30)),
+        ..TcpConfig::default()
+    }).unwrap();
+    let options = RunOptions { fast: true, planning_threads: 1, save_knowledge: None, ..RunOptions::default() };
+    let mut agent = Agent::new(|system| parse_into_system(SEED, system).unwrap(), tcp, options).unwrap();
+    let termination = agent.run().unwrap();
+    assert!(controller.wait().unwrap().success());
+
+    assert_eq!(termination, Termination::EnvironmentStopped);
+    assert_eq!(agent.steps(), 4);
+    let position = agent.system().current_state.variables.get(&EntityVariableKey::new("r", "pos"));
+    assert_eq!(position, Some(&Value::Number(3.0)));
+    let records: Vec<JsonValue> = fs::read_to_string(&record_path).unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records, [
+        json!({ "step": 0, "command": "move", "entity": "r", "params": 3.0 }),
+        json!({ "step": 1, "goal": "goal_0", "status": "ACHIEVED" }),
+        json!({ "step": 1, "command": "no_action", "entity": "sys", "params": [] }),
+        json!({ "step": 2, "command": "no_action", "entity": "sys", "params": [] }),
+    ]);
+    fs::remove_dir_all(&dir).unwrap();
+}